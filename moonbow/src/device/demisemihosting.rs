@@ -2,6 +2,7 @@
 
 const SYS_OPEN: u32 = 0x01;
 const SYS_WRITE: u32 = 0x05;
+const SYS_GET_CMDLINE: u32 = 0x15;
 const ANGEL_REPORT_EXCEPTION: u32 = 0x18;
 
 const ADP_STOPPED_RUNTIME_ERROR_UNKNOWN: u32 = 0x20023;
@@ -13,13 +14,14 @@ const FILENO_STDIO_MAGIC: u32 = 0x1234;
 
 pub fn dispatch<T>(emu: &mut T) -> Result<(), String>
 where
-    T: EmulationControl + RegisterAccess + MemoryAccess + Debug,
+    T: EmulationControl + RegisterAccess + MemoryAccess + Debug + Host,
 {
     let r0 = emu.read_reg(RegisterARM::R0);
 
     match r0 {
         SYS_OPEN => sys_open(emu),
         SYS_WRITE => sys_write(emu),
+        SYS_GET_CMDLINE => sys_get_cmdline(emu),
         ANGEL_REPORT_EXCEPTION => angel_report_exception(emu),
         _ => Err(format!("Unsupported semihosting call {r0} (0x{r0:08x})")),
     }
@@ -72,6 +74,31 @@ where
     Ok(())
 }
 
+fn sys_get_cmdline<T>(emu: &mut T) -> Result<(), String>
+where
+    T: RegisterAccess + MemoryAccess + Host,
+{
+    let r1 = emu.read_reg(RegisterARM::R1);
+
+    let bptr = emu.read_u32(r1 + 0)?;
+    let blen = emu.read_u32(r1 + 4)?;
+
+    let mut cmdline = emu.cmdline().into_bytes();
+    cmdline.push(0);
+
+    let r0 = if cmdline.len() <= blen as usize {
+        emu.write_from(bptr, &cmdline)?;
+        emu.write_u32(r1 + 4, cmdline.len() as u32 - 1)?;
+        0
+    } else {
+        -1_i32 as u32
+    };
+
+    emu.write_reg(RegisterARM::R0, r0);
+
+    Ok(())
+}
+
 fn angel_report_exception<T>(emu: &mut T) -> Result<(), String>
 where
     T: RegisterAccess + EmulationControl,
@@ -60,6 +60,12 @@ trait MemoryAccess {
         })
     }
 
+    fn write_from(&mut self, address: u32, source: &[u8]) -> Result<(), String>;
+
+    fn write_u32(&mut self, address: u32, value: u32) -> Result<(), String> {
+        self.write_from(address, &value.to_le_bytes())
+    }
+
     #[allow(dead_code)] // TODO - remove me if not needed
     fn read_str_lossy(&mut self, address: u32, length: u32) -> Result<String, String> {
         self.read_buf(address, length)
@@ -82,6 +88,8 @@ pub trait Emulation {
     fn load_segment(&mut self, address: u32, data: &[u8]) -> Result<(), String>;
     fn load_elf(&mut self, elfdata: &[u8]) -> Result<(), String>;
     fn load_ihex(&mut self, ihexdata: &[u8]) -> Result<(), String>;
+
+    fn set_cmdline(&mut self, cmdline: &str);
 }
 
 /// Debug
@@ -89,6 +97,11 @@ trait Debug {
     fn log(&mut self, data: &[u8]);
 }
 
+/// Host environment
+trait Host {
+    fn cmdline(&mut self) -> String;
+}
+
 impl EmulationControl for Unicorn<'_, Context> {
     fn stop_emu(&mut self, result: Result<(), String>) {
         match result {
@@ -132,6 +145,15 @@ impl MemoryAccess for Unicorn<'_, Context> {
             ))
         })
     }
+
+    fn write_from(&mut self, address: u32, source: &[u8]) -> Result<(), String> {
+        self.mem_write(address as u64, source).or_else(|e| {
+            let n = source.len();
+            Err(format!(
+                "Could not write {n} bytes at 0x{address:08x} ({e:?})"
+            ))
+        })
+    }
 }
 
 fn handle_intr(emu: &mut Unicorn<'_, Context>, intno: u32) {
@@ -337,6 +359,10 @@ impl Emulation for Unicorn<'_, Context> {
         }
         Ok(())
     }
+
+    fn set_cmdline(&mut self, cmdline: &str) {
+        self.get_data_mut().cmdline = String::from(cmdline);
+    }
 }
 
 impl Debug for Unicorn<'_, Context> {
@@ -347,6 +373,12 @@ impl Debug for Unicorn<'_, Context> {
     }
 }
 
+impl Host for Unicorn<'_, Context> {
+    fn cmdline(&mut self) -> String {
+        self.get_data().cmdline.clone()
+    }
+}
+
 struct LogWriter {}
 
 impl LogWriter {
@@ -372,12 +404,14 @@ impl std::io::Write for LogWriter {
 
 pub struct Context {
     log: std::io::LineWriter<LogWriter>,
+    cmdline: String,
     dev: Device,
 }
 
 pub fn create_emulator<'a>(dev: Device) -> Result<Unicorn<'a, Context>, String> {
     let ctx = Context {
         log: std::io::LineWriter::new(LogWriter::new()),
+        cmdline: String::new(),
         dev,
     };
     let mut emu = Unicorn::new_with_data(Arch::ARM, Mode::LITTLE_ENDIAN, ctx).unwrap();
@@ -391,8 +425,10 @@ pub fn create_emulator<'a>(dev: Device) -> Result<Unicorn<'a, Context>, String>
 use std::collections::HashMap;
 use unicorn_engine::ArmCpuModel;
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
 pub enum CpuModel {
     M0Plus,
+    M33,
 }
 
 pub struct Device {
@@ -405,6 +441,7 @@ impl Device {
     pub fn new(model: CpuModel, mut peripherals: Vec<Box<dyn Peripheral>>) -> Self {
         let acm = match model {
             CpuModel::M0Plus => ArmCpuModel::UC_CPU_ARM_CORTEX_M0,
+            CpuModel::M33 => ArmCpuModel::UC_CPU_ARM_CORTEX_M33,
         };
 
         match model {
@@ -412,6 +449,9 @@ impl Device {
                 // TODO - SCS should be special as it contains the NVIC
                 peripherals.push(Box::new(cortex_m0::SCS::new()));
             }
+            CpuModel::M33 => {
+                peripherals.push(Box::new(cortex_m33::SCS::new()));
+            }
         };
 
         let mut dev = Self {
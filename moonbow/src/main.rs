@@ -1,7 +1,6 @@
 mod device;
 mod peripherals;
 
-use pow2::pow2_const;
 use std::io::Read;
 
 use device::Emulation;
@@ -18,6 +17,18 @@ mod args {
         /// Load Intel HEX file
         #[arg(short, long)]
         pub ihex: Vec<clio::Input>,
+
+        /// Device memory layout (defaults to the Test Loader layout)
+        #[arg(short, long)]
+        pub layout: Option<std::path::PathBuf>,
+
+        /// CPU model
+        #[arg(long, value_enum, default_value = "m0-plus")]
+        pub cpu: crate::device::CpuModel,
+
+        /// Command line returned to the target by semihosting
+        #[arg(short, long, default_value = "")]
+        pub cmdline: String,
     }
 }
 
@@ -29,19 +40,31 @@ fn main() {
 
     let args = <args::Args as clap::Parser>::parse();
 
+    let layout = match &args.layout {
+        Some(path) => nanolayout::Layout::load(path),
+        None => nanolayout::Layout::parse(include_str!("../../testloader/layout.toml")),
+    }
+    .unwrap();
+
+    let page_size = pow2::Pow2::try_from(layout.flash.page_size).unwrap();
     let peripherals: Vec<Box<dyn peripherals::Peripheral>> = vec![
-        Box::new(Sram::new(0x2000_0000, 4 * 1024, None)),
+        Box::new(Sram::new(
+            layout.ram.origin as u32,
+            layout.ram.size as u32,
+            None,
+        )),
         Box::new(FlashController::new(
-            0x0000_0000,
-            pow2_const!(1024),
-            64,
+            layout.flash.origin as u32,
+            page_size,
+            (layout.flash.size / layout.flash.page_size) as u32,
             0x4000_0000,
             None,
         )),
     ];
-    let dev = device::Device::new(device::CpuModel::M0Plus, peripherals);
+    let dev = device::Device::new(args.cpu, peripherals);
 
     let mut emu = device::create_emulator(dev).unwrap();
+    emu.set_cmdline(&args.cmdline);
 
     for mut f in args.elf {
         let mut data = Vec::new();
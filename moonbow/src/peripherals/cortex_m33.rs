@@ -0,0 +1,130 @@
+use super::*;
+use moonbow_macros::Peripheral;
+
+/// Number of SAU regions
+const SAU_REGIONS: usize = 8;
+
+/// Base of the non-secure alias of the SCS
+const SCS_NS: u32 = 0xe002e000;
+
+/// System Control Space of a Cortex-M33 with the Security Extension
+///
+/// Only the Secure VTOR and the SAU are modelled in the secure SCS, and only `VTOR_NS` in the
+/// non-secure alias.
+#[derive(Default, Peripheral)]
+pub struct SCS {
+    #[register(offset = 0xd08)]
+    vtor: u32,
+
+    #[register(offset = 0xdd0)]
+    sau_ctrl: (),
+
+    #[register(read_const = 8, write_nop)]
+    sau_type: (),
+
+    #[register]
+    sau_rnr: u32,
+
+    #[register]
+    sau_rbar: (),
+
+    #[register]
+    sau_rlar: (),
+
+    vtor_ns: u32,
+    ctrl: u32,
+    regions: [(u32, u32); SAU_REGIONS],
+}
+
+impl SCS {
+    pub fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    fn region(&mut self) -> Result<&mut (u32, u32), String> {
+        self.regions
+            .get_mut(self.sau_rnr as usize)
+            .ok_or_else(|| format!("Invalid SAU region {}", self.sau_rnr))
+    }
+
+    fn get_sau_ctrl(&self) -> Result<u32, String> {
+        Ok(self.ctrl)
+    }
+
+    fn set_sau_ctrl(&mut self, value: u32) -> Result<(), String> {
+        self.ctrl = value & 3;
+        if (self.ctrl & 1) != 0 {
+            // Non-secure callable regions are not told apart
+            for (i, (rbar, rlar)) in self.regions.iter().enumerate() {
+                if (rlar & 1) != 0 {
+                    log::debug!(
+                        "SAU region {i}: 0x{:08x}..0x{:08x} non-secure",
+                        rbar & !0x1f,
+                        rlar | 0x1f
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn get_sau_rbar(&self) -> Result<u32, String> {
+        let (rbar, _) = self.regions.get(self.sau_rnr as usize).unwrap_or(&(0, 0));
+        Ok(*rbar)
+    }
+
+    fn set_sau_rbar(&mut self, value: u32) -> Result<(), String> {
+        self.region()?.0 = value & !0x1f;
+        Ok(())
+    }
+
+    fn get_sau_rlar(&self) -> Result<u32, String> {
+        let (_, rlar) = self.regions.get(self.sau_rnr as usize).unwrap_or(&(0, 0));
+        Ok(*rlar)
+    }
+
+    fn set_sau_rlar(&mut self, value: u32) -> Result<(), String> {
+        self.region()?.1 = value & !0x1c;
+        Ok(())
+    }
+}
+
+impl Peripheral for SCS {
+    fn name(&self) -> &'static str {
+        "SCS"
+    }
+
+    fn mappings(&mut self) -> Vec<MemoryMapping> {
+        vec![
+            MemoryMapping::Mmio {
+                base: 0xe000e000,
+                size: 4096,
+            },
+            MemoryMapping::Mmio {
+                base: SCS_NS,
+                size: 4096,
+            },
+        ]
+    }
+
+    fn mmio_read(&self, base: u32, offset: u32, size: u32) -> Result<u32, String> {
+        match (base, offset, size) {
+            (SCS_NS, 0xd08, 4) => Ok(self.vtor_ns),
+            (SCS_NS, ..) => Err(format!("No register mapped at SCS_NS+0x{offset:x}")),
+            _ => self.read_registers(base, offset, size),
+        }
+    }
+
+    fn mmio_write(&mut self, base: u32, offset: u32, size: u32, value: u32) -> Result<(), String> {
+        match (base, offset, size) {
+            (SCS_NS, 0xd08, 4) => {
+                self.vtor_ns = value;
+                Ok(())
+            }
+            (SCS_NS, ..) => Err(format!("No register mapped at SCS_NS+0x{offset:x}")),
+            _ => self.write_registers(base, offset, size, value),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod cortex_m0;
+pub mod cortex_m33;
 pub mod generic;
 
 #[derive(Debug, Clone, Copy)]
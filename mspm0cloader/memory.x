@@ -1,10 +0,0 @@
-MEMORY {
-    BL_CODE : ORIGIN = 0x00000000, LENGTH = 3K
-    BL_DATA : ORIGIN = 0x00000C00, LENGTH = 1K
-
-    FW_CODE : ORIGIN = 0x00001000, LENGTH = 12K
-
-    RAM     : ORIGIN = 0x20000000, LENGTH = 1K
-}
-
-REGION_ALIAS("FLASH", BL_CODE);
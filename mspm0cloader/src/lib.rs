@@ -2,17 +2,33 @@
 
 use mspm0_metapac as device;
 
-use nanoloader::{Ignore, NanoHal, NanoReason, NanoResult};
+use nanoloader::options::{Banked, OptionsFlash, Pair, UpdatePointers};
+use nanoloader::progress::Phase;
+use nanoloader::{
+    Digest, FlashProgrammer, Ignore, Layout, Nano, NanoReason, NanoResult, Reporter, UpdateStore,
+};
+
+mod layout {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
+}
 
-const FLASH_PAGE_SZ: usize = 1024; // should this come from metapac?
+use layout::FLASH_PAGE_SZ;
 
 pub struct LedSettings {
     pub gpio: usize,
     pub tu_cycles: u32,
 }
 
+/// Strap pin that is pulled low to request recovery mode
+pub struct StrapSettings {
+    pub gpio: usize,
+    /// IOMUX PINCM index of the pin
+    pub pincm: usize,
+}
+
 pub trait NanoBoard {
     const LED: Option<LedSettings>;
+    const RECOVERY_STRAP: Option<StrapSettings> = None;
 }
 
 mod flash_util {
@@ -99,6 +115,41 @@ mod flash_util {
     }
 }
 
+/// Update pointer page (BL_OPTS0/1), holding pairs of 64-bit words
+struct DataPage(usize);
+
+impl OptionsFlash for DataPage {
+    type Error = NanoReason;
+
+    fn page(&self) -> &[u8] {
+        // SAFETY: It is assumed that the configured address is valid.
+        unsafe { core::slice::from_raw_parts(self.0 as *const u8, FLASH_PAGE_SZ) }
+    }
+
+    fn is_blank(&self, offset: usize) -> NanoResult<bool> {
+        // TODO -- I *think* that on devices which do not implement ECC for the Flash, an erased
+        // word will reliably read as all 1 bits (0xffff_ffff_ffff_ffff), and that could be
+        // exploited to make the coding of the data page more efficient. Also, TI forum posts
+        // appear to imply that BLANKVERIFY will pass for words that have been written to all 1s,
+        // at least for production devices. For now, let's believe the datasheet that says
+        // differently. This will still allow for 63 updates before the pages are swapped.
+        flash_util::blank_verify((self.0 + offset) as *const u64)
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> NanoResult {
+        for (i, word) in data.chunks_exact(size_of::<u64>()).enumerate() {
+            let addr = (self.0 + offset) as *const u64;
+            let value = u64::from_le_bytes(word.try_into().unwrap());
+            flash_util::write_word(addr.wrapping_add(i), value)?;
+        }
+        nanoloader::OK
+    }
+
+    fn erase(&mut self) -> NanoResult {
+        flash_util::erase_page(self.0 as *const u64)
+    }
+}
+
 struct Blinker {
     pin: usize,
     tu: u32,
@@ -153,135 +204,125 @@ impl Blinker {
     }
 }
 
+/// RTT logger, with a small buffer for the 1K of RAM
+// SAFETY: The bootloader does not log from interrupt handlers
+#[cfg(feature = "log")]
+#[unsafe(export_name = "_SEGGER_RTT")]
+static LOGGER: nanolog::rtt::Logger<128> = unsafe { nanolog::rtt::Logger::new("[NL] ") };
+
+/// Bootloader for a board
 pub struct MspM0CHal<B: NanoBoard> {
-    prog: FlashProgramming,
     _marker: core::marker::PhantomData<B>,
 }
 
-// Manual implementation for Default because #[derive] places a trait bound on the generic
-// parameter to PhantomData, which is undesired here.
-// https://github.com/rust-lang/rust/issues/26925
-impl<B: NanoBoard> Default for MspM0CHal<B> {
-    fn default() -> Self {
-        Self {
-            prog: FlashProgramming::default(),
-            _marker: core::marker::PhantomData,
-        }
-    }
-}
-
-#[derive(Default)]
-struct FlashProgramming {
-    address: usize,
-    buffer: u64,
-    count: u8,
-}
-
 impl<B: NanoBoard> MspM0CHal<B> {
     pub fn boot() -> ! {
-        let hal: MspM0CHal<B> = Default::default();
+        // SAFETY: Nothing is logged yet, and nothing logs from interrupt handlers
+        #[cfg(feature = "log")]
+        unsafe {
+            LOGGER.init(nanolog::LevelFilter::Info);
+        }
 
-        nanoloader::boot::<_>(hal)
-    }
-}
+        let nano: Nano<MspM0CLayout, _, _, _, _> = Nano::new(
+            Crc32,
+            DataStore,
+            FlashProgramming::default(),
+            BoardReporter::<B>::default(),
+        );
 
-impl<B: NanoBoard> MspM0CHal<B> {
-    const BL_DATA_START: usize = (3 * 1024);
+        nano.boot()
+    }
 
     pub fn panic(_panic: &core::panic::PanicInfo<'_>) -> ! {
-        Self::abort(HalErr::Panic.into())
+        BoardReporter::<B>::default().abort(HalErr::Panic.into())
     }
+}
 
-    fn get_bldata<T>() -> &'static [T] {
-        // SAFETY: It is assumed that the configured address is valid.
-        unsafe {
-            core::slice::from_raw_parts(
-                Self::BL_DATA_START as *const T,
-                FLASH_PAGE_SZ / size_of::<T>(),
-            )
-        }
-    }
+/// Firmware area from the layout file
+pub struct MspM0CLayout;
 
-    fn update_find() -> Option<&'static u64> {
-        // TODO -- I *think* that on devices which do not implement ECC for the Flash, an erased
-        // word will reliably read as all 1 bits (0xffff_ffff_ffff_ffff), and that could be
-        // exploited to make the coding of the data page more efficient. Also, TI forum posts
-        // appear to imply that BLANKVERIFY will pass for words that have been written to all 1s,
-        // at least for production devices. For now, let's believe the datasheet that says
-        // differently. This will still allow for 64 updates before the page needs to be erased.
+impl Layout for MspM0CLayout {
+    const FW_START: usize = layout::FW_START;
+    const FW_END: usize = layout::FW_END;
+    const FW_SIZE_OFF: usize = layout::FW_SIZE_OFF;
+    const FW_PAGE_SZ: usize = FLASH_PAGE_SZ;
+}
 
-        let mut it = Self::get_bldata::<u64>().iter();
-        loop {
-            let w1 = it.next()?;
-            let w2 = it.next()?;
-
-            return if flash_util::blank_verify(w1).ok()? {
-                None
-            } else if flash_util::blank_verify(w2).ok()? {
-                Some(w1)
-            } else {
-                continue;
-            };
+struct Crc32;
 
-            // TODO -- should errors in blank_verify be handled?
-        }
+impl Digest for Crc32 {
+    fn checksum(&self, data: &[u8]) -> u32 {
+        const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        CRC32.checksum(data)
     }
+}
 
-    fn update_clear() {
-        if let Some(up) = Self::update_find() {
-            let addr: *const u64 = up;
-            flash_util::write_word(addr.wrapping_add(1), 0).ignore_result();
+/// Update pointers in the data pages
+struct DataStore;
 
-            // TODO -- should errors in write_word be handled?
-        }
+impl DataStore {
+    fn options() -> NanoResult<Banked<DataPage, Pair>> {
+        Banked::new(layout::BL_OPTS.map(DataPage))
     }
+}
 
-    fn program_add_byte(&mut self, value: u8) {
-        self.prog.buffer |= (value as u64) << (self.prog.count * 8);
-        self.prog.count += 1;
+impl UpdateStore for DataStore {
+    fn update_address(&mut self) -> Option<usize> {
+        let (_, address) = Self::options().ok()?.pending().ok()??;
+        Some(address)
     }
 
-    fn program_commit_word<const FORCE: bool>(&mut self) -> NanoResult {
-        if self.prog.count == 8 || (FORCE && self.prog.count != 0) {
-            if pow2::pow2_const!(FLASH_PAGE_SZ).is_aligned(self.prog.address) {
-                flash_util::erase_page(self.prog.address as *const u64)?;
-            }
-
-            flash_util::write_word(self.prog.address as *const u64, self.prog.buffer)?;
+    fn update_queued(&mut self, position: usize) -> Option<usize> {
+        let (_, address) = Self::options().ok()?.queued(position).ok()??;
+        Some(address)
+    }
 
-            self.prog.address += 8;
-            self.prog.buffer = !0;
-            self.prog.count = 0;
+    fn update_clear(&mut self) {
+        if let Ok(mut options) = Self::options()
+            && let Ok(Some((index, _))) = options.pending()
+        {
+            options.clear(index).ignore_result();
         }
-        nanoloader::OK
+
+        // TODO -- should errors in blank_verify or write_word be handled?
     }
 }
 
-#[repr(u16)]
-pub enum HalErr {
-    Panic,
-    NotImplemented,
-    FlashError,
+/// Reports errors and progress with the board's LED, and checks its recovery strap
+struct BoardReporter<B: NanoBoard> {
+    _marker: core::marker::PhantomData<B>,
 }
 
-impl From<HalErr> for NanoReason {
-    fn from(item: HalErr) -> Self {
-        NanoReason::HalError(item as u16)
+// Manual implementation for Default because #[derive] places a trait bound on the generic
+// parameter to PhantomData, which is undesired here.
+// https://github.com/rust-lang/rust/issues/26925
+impl<B: NanoBoard> Default for BoardReporter<B> {
+    fn default() -> Self {
+        Self {
+            _marker: core::marker::PhantomData,
+        }
     }
 }
-impl<T> From<HalErr> for NanoResult<T> {
-    fn from(item: HalErr) -> Self {
-        Err(item.into())
+
+impl<B: NanoBoard> BoardReporter<B> {
+    const RECOVERY_WORD: *mut u32 = layout::RECOVERY_WORD as *mut u32;
+
+    fn strap_low(strap: &StrapSettings) -> bool {
+        device::IOMUX.pincm(strap.pincm).write(|w| {
+            w.set_pf(1); // GPIO
+            w.set_pc(true);
+            w.set_inena(true);
+            w.set_pipu(true);
+        });
+        // Let the pull-up settle
+        cortex_m::asm::delay(1000);
+
+        !device::GPIOA.din31_0().read().dio(strap.gpio)
     }
 }
 
-impl<B: NanoBoard> NanoHal for MspM0CHal<B> {
-    const FW_START: usize = (4 * 1024);
-    const FW_END: usize = (16 * 1024);
-    const FW_SIZE_OFF: usize = 0x30;
-    const FW_PAGE_SZ: usize = FLASH_PAGE_SZ;
-
-    fn abort(reason: NanoReason) -> ! {
+impl<B: NanoBoard> Reporter for BoardReporter<B> {
+    fn abort(&mut self, reason: NanoReason) -> ! {
         if let Some(led) = B::LED {
             let values = match reason {
                 NanoReason::HalError(e) => [0u32, e as u32],
@@ -296,37 +337,114 @@ impl<B: NanoBoard> NanoHal for MspM0CHal<B> {
         cortex_m::peripheral::SCB::sys_reset();
     }
 
-    fn checksum(data: &[u8]) -> u32 {
-        const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
-        CRC32.checksum(data)
+    fn recovery_requested(&mut self) -> bool {
+        // SAFETY: The word is reserved by the layout file
+        let requested = unsafe { nanoloader::recovery::take_request(Self::RECOVERY_WORD) };
+        requested || B::RECOVERY_STRAP.as_ref().is_some_and(Self::strap_low)
     }
 
-    fn update_address() -> Option<usize> {
-        MspM0CHal::<B>::update_find().map(|x| *x as usize)
+    fn recovery_wait(&mut self) -> bool {
+        // Stay here until an update is staged with the debugger
+        match B::LED {
+            Some(led) => Blinker::new(led.gpio, led.tu_cycles).pattern(&[2]),
+            None => cortex_m::asm::delay(6_000_000),
+        }
+        true
     }
 
-    fn update_clear() {
-        MspM0CHal::<B>::update_clear()
+    fn progress(&mut self, phase: Phase, done: usize, _total: usize) {
+        // A long blink when a check starts, and a short one for each page programmed
+        if let Some(led) = B::LED {
+            let blinker = Blinker::new(led.gpio, led.tu_cycles);
+            match phase {
+                Phase::Program => blinker.led(1),
+                Phase::Verify | Phase::VerifyAfter if done == 0 => blinker.led(4),
+                _ => {}
+            }
+        }
     }
+}
 
+/// Programs the firmware area in 64-bit words with FLASHCTL, which MSPM0 devices share
+#[derive(Default)]
+pub struct FlashProgramming {
+    address: usize,
+    buffer: u64,
+    count: u8,
+}
+
+impl FlashProgramming {
+    fn add_byte(&mut self, value: u8) {
+        // The buffer starts out erased (all 1s), so the byte has to replace what is there
+        let shift = self.count * 8;
+        self.buffer = (self.buffer & !(0xff << shift)) | ((value as u64) << shift);
+        self.count += 1;
+    }
+
+    fn commit_word<const FORCE: bool>(&mut self) -> NanoResult {
+        if self.count == 8 || (FORCE && self.count != 0) {
+            if pow2::pow2_const!(FLASH_PAGE_SZ).is_aligned(self.address) {
+                flash_util::erase_page(self.address as *const u64)?;
+            }
+
+            flash_util::write_word(self.address as *const u64, self.buffer)?;
+
+            self.address += 8;
+            self.buffer = !0;
+            self.count = 0;
+        }
+        nanoloader::OK
+    }
+}
+
+impl FlashProgrammer for FlashProgramming {
     fn program_start(&mut self) -> NanoResult {
-        self.prog.address = 0;
-        self.prog.buffer = !0;
-        self.prog.count = 0;
+        self.address = layout::FW_START;
+        self.buffer = !0;
+        self.count = 0;
 
         nanoloader::OK
     }
 
     fn program_write(&mut self, value: u8) -> NanoResult {
-        self.program_add_byte(value);
-        self.program_commit_word::<false>()
+        self.add_byte(value);
+        self.commit_word::<false>()
     }
 
-    fn program_read(&mut self, _offset: usize) -> NanoResult<u8> {
-        HalErr::NotImplemented.into()
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        let addr = layout::FW_START + offset;
+
+        if addr < self.address {
+            // SAFETY: Address lies within the already programmed part of the firmware area
+            Ok(unsafe { core::ptr::read_volatile(addr as *const u8) })
+        } else if addr - self.address < self.count as usize {
+            // Not yet programmed, still in the word buffer
+            Ok((self.buffer >> ((addr - self.address) * 8)) as u8)
+        } else {
+            HalErr::OutOfRange.into()
+        }
     }
 
     fn program_finish(&mut self) -> NanoResult {
-        self.program_commit_word::<true>()
+        self.commit_word::<true>()
+    }
+}
+
+#[repr(u16)]
+pub enum HalErr {
+    Panic,
+    NotImplemented,
+    FlashError,
+    OutOfRange,
+}
+
+impl From<HalErr> for NanoReason {
+    fn from(item: HalErr) -> Self {
+        NanoReason::HalError(item as u16)
+    }
+}
+impl<T> From<HalErr> for NanoResult<T> {
+    fn from(item: HalErr) -> Self {
+        Err(item.into())
     }
 }
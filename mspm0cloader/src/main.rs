@@ -1,8 +1,8 @@
 #![no_main]
 #![no_std]
 
-use mspm0cloader::MspM0CHal;
 use mspm0cloader::{LedSettings, NanoBoard};
+use mspm0cloader::{MspM0CHal, MspM0CLayout};
 
 struct TestBoard {}
 
@@ -13,6 +13,8 @@ impl NanoBoard for TestBoard {
     });
 }
 
+nanoloader::export_layout!(MspM0CLayout);
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
     MspM0CHal::<TestBoard>::boot();
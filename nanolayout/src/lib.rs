@@ -0,0 +1,430 @@
+//! Flash and RAM layout of a Nano Loader target
+//!
+//! A layout file describes where the bootloader, its options pages and the firmware are placed.
+//! Loader build scripts generate `memory.x` and the `nanoloader::Layout` constants from it with
+//! [`build`], and moonbow and nanotool read the same file.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use nanoloader::measure::Handover;
+use serde::Deserialize;
+
+/// Layout file, see `testloader/layout.toml` for an example
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    pub flash: Flash,
+    pub ram: Ram,
+    pub bootloader: Bootloader,
+    pub firmware: Firmware,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Flash {
+    pub origin: usize,
+    pub size: usize,
+    pub page_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ram {
+    pub origin: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bootloader {
+    /// Size of the bootloader code, starting at the Flash origin
+    pub size: usize,
+    /// Options pages holding the update pointers, the first one is preferred
+    pub options: Vec<usize>,
+    pub pointer_format: PointerFormat,
+    /// RAM word for recovery requests, reserved at the RAM origin
+    pub recovery_word: Option<usize>,
+    /// RAM for the measured boot handover, reserved after the recovery word
+    pub handover: Option<usize>,
+    /// RAM word for update policy decisions, reserved after the handover
+    pub policy_word: Option<usize>,
+    /// Flash pages for the event log, used as a ring
+    #[serde(default)]
+    pub event_log: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Firmware {
+    pub start: usize,
+    pub end: usize,
+    pub size_offset: usize,
+}
+
+/// Update pointer format of `nanoloader::options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PointerFormat {
+    Word,
+    Pair,
+}
+
+impl Layout {
+    pub fn parse(text: &str) -> Result<Layout, String> {
+        let layout: Layout = toml::from_str(text).map_err(|e| format!("{e}"))?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Layout, String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("{e}"))
+            .and_then(|text| Layout::parse(&text))
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let flash = self.flash.origin..self.flash.origin + self.flash.size;
+        let page = self.flash.page_size;
+        let fw = &self.firmware;
+
+        nanoloader::check::layout(fw.start, fw.end, fw.size_offset, page)
+            .map_err(|fault| format!("Invalid layout: {}", fault.message()))?;
+
+        let code = self.flash.origin..self.flash.origin + self.bootloader.size;
+        if code.end > flash.end || fw.start < flash.start || fw.end > flash.end {
+            return Err(String::from("Bootloader or firmware exceed the Flash"));
+        }
+        if code.end > fw.start {
+            return Err(String::from("Bootloader code overlaps the firmware area"));
+        }
+
+        if self.bootloader.options.is_empty() {
+            return Err(String::from("No options pages"));
+        }
+        for (i, &opts) in self.bootloader.options.iter().enumerate() {
+            let overlaps = |start: usize, end: usize| opts < end && start < opts + page;
+            if !opts.is_multiple_of(page) || opts < flash.start || opts + page > flash.end {
+                return Err(format!("Options page 0x{opts:08x} is not a Flash page"));
+            }
+            if overlaps(code.start, code.end)
+                || overlaps(fw.start, fw.end)
+                || self.bootloader.options[..i].contains(&opts)
+            {
+                return Err(format!("Options page 0x{opts:08x} overlaps other memory"));
+            }
+        }
+        for (i, &log) in self.bootloader.event_log.iter().enumerate() {
+            let overlaps = |start: usize, end: usize| log < end && start < log + page;
+            if !log.is_multiple_of(page) || log < flash.start || log + page > flash.end {
+                return Err(format!("Event log page 0x{log:08x} is not a Flash page"));
+            }
+            if overlaps(code.start, code.end)
+                || overlaps(fw.start, fw.end)
+                || self.bootloader.options.contains(&log)
+                || self.bootloader.event_log[..i].contains(&log)
+            {
+                return Err(format!("Event log page 0x{log:08x} overlaps other memory"));
+            }
+        }
+
+        if self
+            .bootloader
+            .recovery_word
+            .is_some_and(|word| word != self.ram.origin)
+        {
+            return Err(String::from("Recovery word is not at the RAM origin"));
+        }
+        if self
+            .bootloader
+            .handover
+            .is_some_and(|handover| handover != self.ram.origin + self.recovery_size())
+        {
+            return Err(String::from(
+                "Handover is not at the RAM origin, after the recovery word",
+            ));
+        }
+        if self.bootloader.policy_word.is_some_and(|word| {
+            word != self.ram.origin + self.recovery_size() + self.handover_size()
+        }) {
+            return Err(String::from(
+                "Policy word is not at the RAM origin, after the recovery word and handover",
+            ));
+        }
+
+        let reserved = self
+            .reserved_ram()
+            .iter()
+            .map(|(_, _, length)| length)
+            .sum();
+        if self.ram.size.checked_sub(reserved).is_none() {
+            return Err(String::from("Reserved RAM exceeds the RAM"));
+        }
+        Ok(())
+    }
+
+    fn recovery_size(&self) -> usize {
+        self.bootloader.recovery_word.map_or(0, |_| 4)
+    }
+
+    fn handover_size(&self) -> usize {
+        self.bootloader
+            .handover
+            .map_or(0, |_| size_of::<Handover>())
+    }
+
+    /// RAM reserved by the bootloader at the RAM origin
+    fn reserved_ram(&self) -> Vec<(&'static str, usize, usize)> {
+        let mut reserved = Vec::new();
+        if let Some(word) = self.bootloader.recovery_word {
+            reserved.push(("RECOVERY", word, self.recovery_size()));
+        }
+        if let Some(handover) = self.bootloader.handover {
+            reserved.push(("HANDOVER", handover, self.handover_size()));
+        }
+        if let Some(word) = self.bootloader.policy_word {
+            reserved.push(("POLICY", word, 4));
+        }
+        reserved
+    }
+
+    /// Linker script with the memory regions, for `cortex-m-rt`
+    ///
+    /// The options pages get regions `BL_OPTS0`, `BL_OPTS1`, ..., with sections of the same name
+    /// (in lower case) placed in them, and the event log pages regions `BL_LOG0`, ... The
+    /// firmware area is checked against the symbols exported by `nanoloader::export_layout!`.
+    pub fn memory_x(&self) -> String {
+        let mut regions = vec![(
+            String::from("BL_CODE"),
+            self.flash.origin,
+            self.bootloader.size,
+        )];
+        for (i, &opts) in self.bootloader.options.iter().enumerate() {
+            regions.push((format!("BL_OPTS{i}"), opts, self.flash.page_size));
+        }
+        for (i, &log) in self.bootloader.event_log.iter().enumerate() {
+            regions.push((format!("BL_LOG{i}"), log, self.flash.page_size));
+        }
+        regions.push((
+            String::from("FW_CODE"),
+            self.firmware.start,
+            self.firmware.end - self.firmware.start,
+        ));
+        let mut reserved = 0;
+        for (name, origin, length) in self.reserved_ram() {
+            regions.push((String::from(name), origin, length));
+            reserved += length;
+        }
+        regions.push((
+            String::from("RAM"),
+            self.ram.origin + reserved,
+            self.ram.size - reserved,
+        ));
+
+        let mut out = String::from("/* Generated from the layout file by nanolayout */\n");
+        out += "MEMORY {\n";
+        for (name, origin, length) in &regions {
+            writeln!(
+                out,
+                "    {name:<8} : ORIGIN = 0x{origin:08x}, LENGTH = 0x{length:x}"
+            )
+            .unwrap();
+        }
+        out += "}\n\nREGION_ALIAS(\"FLASH\", BL_CODE);\n\nSECTIONS {\n";
+        for i in 0..self.bootloader.options.len() {
+            writeln!(
+                out,
+                "    .bl_opts{i} : {{\n        KEEP(*(.bl_opts{i}*))\n    }} >BL_OPTS{i}"
+            )
+            .unwrap();
+        }
+        out += "}\n\n";
+        out += "/* Check the firmware area of the HAL against the memory regions */\n";
+        out += "ASSERT(ORIGIN(FW_CODE) == __nanoloader_fw_start, \
+                \"FW_CODE does not start at Layout::FW_START\");\n";
+        out += "ASSERT(ORIGIN(FW_CODE) + LENGTH(FW_CODE) == __nanoloader_fw_end, \
+                \"FW_CODE does not end at Layout::FW_END\");\n";
+        out
+    }
+
+    /// Rust constants for the HAL
+    pub fn constants(&self) -> String {
+        let opts: Vec<_> = self
+            .bootloader
+            .options
+            .iter()
+            .map(|a| format!("0x{a:x}"))
+            .collect();
+
+        let mut out = String::from("// Generated from the layout file by nanolayout\n");
+        let mut constant = |name: &str, ty: &str, value: String| {
+            writeln!(
+                out,
+                "#[allow(dead_code)]\npub const {name}: {ty} = {value};"
+            )
+            .unwrap()
+        };
+        constant(
+            "FLASH_PAGE_SZ",
+            "usize",
+            format!("0x{:x}", self.flash.page_size),
+        );
+        constant("FW_START", "usize", format!("0x{:x}", self.firmware.start));
+        constant("FW_END", "usize", format!("0x{:x}", self.firmware.end));
+        constant(
+            "FW_SIZE_OFF",
+            "usize",
+            format!("0x{:x}", self.firmware.size_offset),
+        );
+        constant(
+            "BL_OPTS",
+            &format!("[usize; {}]", opts.len()),
+            format!("[{}]", opts.join(", ")),
+        );
+        constant("RAM_START", "usize", format!("0x{:x}", self.ram.origin));
+        constant(
+            "RAM_END",
+            "usize",
+            format!("0x{:x}", self.ram.origin + self.ram.size),
+        );
+        if let Some(word) = self.bootloader.recovery_word {
+            constant("RECOVERY_WORD", "usize", format!("0x{word:x}"));
+        }
+        if let Some(handover) = self.bootloader.handover {
+            constant("HANDOVER", "usize", format!("0x{handover:x}"));
+        }
+        if let Some(word) = self.bootloader.policy_word {
+            constant("POLICY_WORD", "usize", format!("0x{word:x}"));
+        }
+        if !self.bootloader.event_log.is_empty() {
+            let log: Vec<_> = self
+                .bootloader
+                .event_log
+                .iter()
+                .map(|a| format!("0x{a:x}"))
+                .collect();
+            constant(
+                "EVENT_LOG",
+                &format!("[usize; {}]", log.len()),
+                format!("[{}]", log.join(", ")),
+            );
+        }
+        out
+    }
+}
+
+/// Generate `memory.x` and `layout.rs` from a layout file, for use in a build script
+///
+/// `memory.x` is put on the linker search path, and `layout.rs` can be included with
+/// `include!(concat!(env!("OUT_DIR"), "/layout.rs"))`.
+pub fn build(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    let layout = Layout::load(path).unwrap_or_else(|e| panic!("{e}"));
+
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    std::fs::write(out.join("memory.x"), layout.memory_x()).unwrap();
+    std::fs::write(out.join("layout.rs"), layout.constants()).unwrap();
+
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed={}", path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTLOADER: &str = include_str!("../../testloader/layout.toml");
+    const MSPM0C: &str = include_str!("../../mspm0cloader/layout.toml");
+
+    #[test]
+    fn testloader() {
+        let layout = Layout::parse(TESTLOADER).unwrap();
+        assert_eq!(layout.bootloader.pointer_format, PointerFormat::Word);
+
+        let memory = layout.memory_x();
+        assert!(memory.contains(
+            "    BL_CODE  : ORIGIN = 0x00000000, LENGTH = 0x3800\n\
+             \x20   BL_OPTS0 : ORIGIN = 0x00003c00, LENGTH = 0x400\n\
+             \x20   BL_OPTS1 : ORIGIN = 0x00003800, LENGTH = 0x400\n\
+             \x20   BL_LOG0  : ORIGIN = 0x0000f800, LENGTH = 0x400\n\
+             \x20   BL_LOG1  : ORIGIN = 0x0000fc00, LENGTH = 0x400\n\
+             \x20   FW_CODE  : ORIGIN = 0x00004000, LENGTH = 0xb800\n\
+             \x20   RECOVERY : ORIGIN = 0x20000000, LENGTH = 0x4\n\
+             \x20   HANDOVER : ORIGIN = 0x20000004, LENGTH = 0x48\n\
+             \x20   POLICY   : ORIGIN = 0x2000004c, LENGTH = 0x4\n\
+             \x20   RAM      : ORIGIN = 0x20000050, LENGTH = 0xfb0\n"
+        ));
+        assert!(
+            memory.contains("    .bl_opts1 : {\n        KEEP(*(.bl_opts1*))\n    } >BL_OPTS1\n")
+        );
+
+        let constants = layout.constants();
+        assert!(constants.contains("pub const FW_START: usize = 0x4000;\n"));
+        assert!(constants.contains("pub const BL_OPTS: [usize; 2] = [0x3c00, 0x3800];\n"));
+        assert!(constants.contains("pub const RAM_END: usize = 0x20001000;\n"));
+        assert!(constants.contains("pub const RECOVERY_WORD: usize = 0x20000000;\n"));
+        assert!(constants.contains("pub const HANDOVER: usize = 0x20000004;\n"));
+        assert!(constants.contains("pub const POLICY_WORD: usize = 0x2000004c;\n"));
+        assert!(constants.contains("pub const EVENT_LOG: [usize; 2] = [0xf800, 0xfc00];\n"));
+    }
+
+    #[test]
+    fn mspm0c() {
+        let layout = Layout::parse(MSPM0C).unwrap();
+        assert_eq!(layout.bootloader.pointer_format, PointerFormat::Pair);
+        assert!(
+            layout
+                .memory_x()
+                .contains("    FW_CODE  : ORIGIN = 0x00001000, LENGTH = 0x2c00\n")
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |from: &str, to: &str| Layout::parse(&TESTLOADER.replace(from, to)).err();
+
+        assert_eq!(
+            error("start = 0x4000", "start = 0x4100").as_deref(),
+            Some("Invalid layout: firmware area is not page-aligned")
+        );
+        assert_eq!(
+            error("size = 0x3800", "size = 0x4400").as_deref(),
+            Some("Bootloader code overlaps the firmware area")
+        );
+        assert_eq!(
+            error("[0x3c00, 0x3800]", "[0x3c00, 0x3c00]").as_deref(),
+            Some("Options page 0x00003c00 overlaps other memory")
+        );
+        assert_eq!(
+            error("[0x3c00, 0x3800]", "[0x3c00, 0x3000]").as_deref(),
+            Some("Options page 0x00003000 overlaps other memory")
+        );
+        assert_eq!(
+            error("[0xf800, 0xfc00]", "[0xf800, 0xf000]").as_deref(),
+            Some("Event log page 0x0000f000 overlaps other memory")
+        );
+        assert_eq!(
+            error("[0xf800, 0xfc00]", "[0xf800, 0x3800]").as_deref(),
+            Some("Event log page 0x00003800 overlaps other memory")
+        );
+        assert_eq!(
+            error("recovery_word = 0x2000_0000", "recovery_word = 0x2000_0100").as_deref(),
+            Some("Recovery word is not at the RAM origin")
+        );
+        assert_eq!(
+            error("handover = 0x2000_0004", "handover = 0x2000_0000").as_deref(),
+            Some("Handover is not at the RAM origin, after the recovery word")
+        );
+        assert_eq!(
+            error("policy_word = 0x2000_004c", "policy_word = 0x2000_0004").as_deref(),
+            Some("Policy word is not at the RAM origin, after the recovery word and handover")
+        );
+        assert_eq!(
+            error("size = 0x1000", "size = 0x40").as_deref(),
+            Some("Reserved RAM exceeds the RAM")
+        );
+        assert!(error("pointer_format = \"word\"", "pointer_format = \"byte\"").is_some());
+    }
+}
@@ -0,0 +1,431 @@
+//! Application-side interface to Nano Loader
+//!
+//! Firmware uses this to check an update it has staged in the firmware area, and to point the
+//! bootloader at it.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub use nanoloader::{events, measure, options, policy, recovery};
+
+use nanoloader::UpdateInfo;
+use nanoloader::check::{self, InstallFault, UpdateFault};
+
+use options::{Slot, UpdatePointers};
+
+/// Parameters shared with the bootloader's `Layout`
+pub trait NanoClient {
+    const FW_START: usize;
+    const FW_END: usize;
+    const FW_PAGE_SZ: usize;
+    /// Custom update types handled by the bootloader's `Installer`
+    const CUSTOM_TYPES: &'static [u32] = &[];
+
+    /// Contents of the firmware area, memory-mapped at `FW_START` by default
+    fn fwarea() -> &'static [u8] {
+        // SAFETY: It is assumed that the const parameters are valid.
+        unsafe {
+            core::slice::from_raw_parts(Self::FW_START as *const u8, Self::FW_END - Self::FW_START)
+        }
+    }
+
+    /// Same checksum as the bootloader's `Digest`
+    fn checksum(data: &[u8]) -> u32;
+}
+
+/// Reason why the bootloader would not install an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// The bootloader would not accept the update
+    Update(UpdateFault),
+    /// The bootloader would not be able to install the update
+    Install(InstallFault),
+    /// The bootloader does not support the update type
+    UnsupportedType(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError<E> {
+    /// The update is not valid
+    Rejected(Rejected),
+    /// Another update is already pending at the given address
+    Pending(usize),
+    /// Installing the update queued at the given address would overwrite this one
+    Overwritten(usize),
+    /// No free update pointer slots are left
+    Full,
+    /// The update pointer did not read back as programmed
+    Verify,
+    /// Error accessing the update pointers
+    Pointers(E),
+}
+
+/// Check an update staged at `address` with the same rules the bootloader applies
+pub fn validate<C: NanoClient>(address: usize) -> Result<UpdateInfo, Rejected> {
+    const { assert!(C::FW_PAGE_SZ.is_power_of_two()) }
+
+    let update =
+        check::update(C::fwarea(), C::FW_START, address, C::checksum).map_err(Rejected::Update)?;
+
+    match update.info.uptype {
+        UpdateInfo::TYPE_PLAIN => {
+            check::plain(&update, C::FW_START, C::FW_PAGE_SZ).map_err(Rejected::Install)?;
+        }
+        UpdateInfo::TYPE_LZ4 => {
+            check::lz4(&update, C::FW_START, C::FW_PAGE_SZ).map_err(Rejected::Install)?;
+        }
+        uptype if C::CUSTOM_TYPES.contains(&uptype) => {}
+        uptype => return Err(Rejected::UnsupportedType(uptype)),
+    }
+
+    Ok(update.info)
+}
+
+/// Validate the update staged at `address` and schedule it for installation on the next boot
+///
+/// If all update pointer slots have been used, the storage is compacted first.
+pub fn schedule<C: NanoClient, P: UpdatePointers>(
+    pointers: &mut P,
+    address: usize,
+) -> Result<UpdateInfo, ClientError<P::Error>> {
+    let info = validate::<C>(address).map_err(ClientError::Rejected)?;
+
+    if let Some((_, pending)) = pointers.pending().map_err(ClientError::Pointers)? {
+        return Err(ClientError::Pending(pending));
+    }
+
+    append(pointers, address)?;
+    Ok(info)
+}
+
+/// Validate the update staged at `address` and queue it after any pending updates
+///
+/// The bootloader installs queued updates in order, clearing each one when it is done. Since
+/// installing an update erases the firmware area up to the end of its new firmware, the update
+/// must be staged beyond that for every update queued before it.
+pub fn enqueue<C: NanoClient, P: UpdatePointers>(
+    pointers: &mut P,
+    address: usize,
+) -> Result<UpdateInfo, ClientError<P::Error>> {
+    let info = validate::<C>(address).map_err(ClientError::Rejected)?;
+
+    let mut position = 0;
+    while let Some((_, queued)) = pointers.queued(position).map_err(ClientError::Pointers)? {
+        let fwsize = check::update(C::fwarea(), C::FW_START, queued, C::checksum)
+            .map_or(0, |update| update.info.fwsize as usize);
+        if address < C::FW_START + fwsize.next_multiple_of(C::FW_PAGE_SZ) {
+            return Err(ClientError::Overwritten(queued));
+        }
+        position += 1;
+    }
+
+    append(pointers, address)?;
+    Ok(info)
+}
+
+/// Program `address` into the first free slot, compacting the storage if there is none
+fn append<P: UpdatePointers>(
+    pointers: &mut P,
+    address: usize,
+) -> Result<(), ClientError<P::Error>> {
+    let free = |pointers: &P| {
+        for index in 0..pointers.slots() {
+            if pointers.slot(index)? == Slot::Free {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    };
+
+    let index = match free(pointers).map_err(ClientError::Pointers)? {
+        Some(index) => index,
+        None => {
+            pointers.compact().map_err(ClientError::Pointers)?;
+            free(pointers)
+                .map_err(ClientError::Pointers)?
+                .ok_or(ClientError::Full)?
+        }
+    };
+
+    pointers
+        .set(index, address)
+        .map_err(ClientError::Pointers)?;
+
+    match pointers.slot(index).map_err(ClientError::Pointers)? {
+        Slot::Pending(a) if a == address => Ok(()),
+        _ => Err(ClientError::Verify),
+    }
+}
+
+/// Cancel the pending update, returning its address
+///
+/// Of queued updates, only the first one is cancelled.
+pub fn cancel<P: UpdatePointers>(pointers: &mut P) -> Result<Option<usize>, P::Error> {
+    match pointers.pending()? {
+        Some((index, address)) => {
+            pointers.clear(index)?;
+            Ok(Some(address))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reset into the bootloader's recovery mode
+///
+/// # Safety
+///
+/// `word` must be the recovery word the bootloader checks, reserved in RAM by both.
+pub unsafe fn request_recovery(word: *mut u32) -> ! {
+    // SAFETY: Guaranteed by caller
+    unsafe { core::ptr::write_volatile(word, recovery::MAGIC) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::options::{Banked, OptionsFlash, Pair, Single, Word};
+    use super::*;
+
+    use std::sync::OnceLock;
+    use std::vec::Vec;
+
+    const FW_START: usize = 0x4000;
+    const FW_END: usize = 0x8000;
+    const STAGE: usize = 0x5000;
+    const UNKNOWN: usize = 0x5400;
+    const CORRUPT: usize = 0x5800;
+    /// Installs up to 0x5800, over the updates above
+    const BASE: usize = 0x6000;
+
+    struct TestClient;
+
+    impl NanoClient for TestClient {
+        const FW_START: usize = FW_START;
+        const FW_END: usize = FW_END;
+        const FW_PAGE_SZ: usize = 1024;
+
+        fn fwarea() -> &'static [u8] {
+            static FWAREA: OnceLock<Vec<u8>> = OnceLock::new();
+            FWAREA.get_or_init(|| {
+                let mut fwarea = std::vec![0xff; FW_END - FW_START];
+                let mut place = |address: usize, update: &[u8]| {
+                    fwarea[address - FW_START..][..update.len()].copy_from_slice(update)
+                };
+                place(STAGE, &update(&[0x5a; 600], UpdateInfo::TYPE_PLAIN));
+                place(UNKNOWN, &update(&[0x5a; 600], 0x42));
+                let mut corrupt = update(&[0x5a; 600], UpdateInfo::TYPE_PLAIN);
+                corrupt[100] ^= 1;
+                place(CORRUPT, &corrupt);
+                place(BASE, &update(&[0xa5; 0x1800], UpdateInfo::TYPE_PLAIN));
+                fwarea
+            })
+        }
+
+        fn checksum(data: &[u8]) -> u32 {
+            const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+            CRC32.checksum(data)
+        }
+    }
+
+    /// Client of a bootloader with an installer for type 0x42
+    struct CustomClient;
+
+    impl NanoClient for CustomClient {
+        const FW_START: usize = FW_START;
+        const FW_END: usize = FW_END;
+        const FW_PAGE_SZ: usize = 1024;
+        const CUSTOM_TYPES: &'static [u32] = &[0x42];
+
+        fn fwarea() -> &'static [u8] {
+            TestClient::fwarea()
+        }
+
+        fn checksum(data: &[u8]) -> u32 {
+            TestClient::checksum(data)
+        }
+    }
+
+    fn update(firmware: &[u8], uptype: u32) -> Vec<u8> {
+        let mut info = UpdateInfo {
+            checksum: 0,
+            upsize: (size_of::<UpdateInfo>() + firmware.len()) as u32,
+            uptype,
+            fwsize: firmware.len() as u32,
+        };
+        let mut update = info.to_bytes().to_vec();
+        update.extend_from_slice(firmware);
+        info.checksum = TestClient::checksum(&update[4..]);
+        update[..size_of::<UpdateInfo>()].copy_from_slice(&info.to_bytes());
+        update
+    }
+
+    /// Options page in RAM, enforcing that programming only clears bits
+    struct RamFlash(Vec<u8>);
+
+    impl OptionsFlash for RamFlash {
+        type Error = ();
+
+        fn page(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), ()> {
+            let target = &mut self.0[offset..offset + data.len()];
+            for (t, d) in target.iter_mut().zip(data) {
+                if *t & d != *d {
+                    return Err(());
+                }
+                *t = *d;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self) -> Result<(), ()> {
+            self.0.fill(0xff);
+            Ok(())
+        }
+    }
+
+    /// Schedule and cancel updates until all slots are used
+    fn lifecycle<P: UpdatePointers<Error = ()>>(pointers: &mut P) {
+        let slots = pointers.slots();
+        assert_eq!(pointers.pending(), Ok(None));
+
+        for n in 0..slots {
+            let info = schedule::<TestClient, _>(pointers, STAGE).unwrap();
+            assert_eq!(info.fwsize, 600);
+            assert_eq!(pointers.pending(), Ok(Some((n, STAGE))));
+            assert_eq!(pointers.remaining(), Ok(slots - n));
+            assert_eq!(
+                schedule::<TestClient, _>(pointers, STAGE).err(),
+                Some(ClientError::Pending(STAGE))
+            );
+            assert_eq!(cancel(pointers), Ok(Some(STAGE)));
+        }
+
+        assert_eq!(pointers.remaining(), Ok(0));
+    }
+
+    fn single<P: UpdatePointers<Error = ()>>(mut pointers: P) {
+        lifecycle(&mut pointers);
+        assert_eq!(
+            schedule::<TestClient, _>(&mut pointers, STAGE).err(),
+            Some(ClientError::Full)
+        );
+    }
+
+    fn banked<P: UpdatePointers<Error = ()>>(mut pointers: P) {
+        // Compacted whenever all slots are used
+        for _ in 0..3 {
+            lifecycle(&mut pointers);
+        }
+    }
+
+    fn pages() -> [RamFlash; 2] {
+        [RamFlash(std::vec![0xff; 64]), RamFlash(std::vec![0xff; 64])]
+    }
+
+    #[test]
+    fn words() {
+        single(Single::<_, Word>::new(RamFlash(std::vec![0xff; 64])));
+        banked(Banked::<_, Word>::new(pages()).unwrap());
+    }
+
+    #[test]
+    fn pairs() {
+        single(Single::<_, Pair>::new(RamFlash(std::vec![0xff; 64])));
+        banked(Banked::<_, Pair>::new(pages()).unwrap());
+    }
+
+    #[test]
+    fn queue() {
+        let mut words = Single::<_, Word>::new(RamFlash(std::vec![0xff; 16]));
+        enqueue::<CustomClient, _>(&mut words, STAGE).unwrap();
+        enqueue::<CustomClient, _>(&mut words, UNKNOWN).unwrap();
+        assert_eq!(
+            enqueue::<CustomClient, _>(&mut words, CORRUPT).err(),
+            Some(ClientError::Rejected(Rejected::Update(
+                UpdateFault::Checksum
+            )))
+        );
+        enqueue::<CustomClient, _>(&mut words, BASE).unwrap();
+        assert_eq!(words.pending(), Ok(Some((0, STAGE))));
+        assert_eq!(words.queued(1), Ok(Some((1, UNKNOWN))));
+        assert_eq!(words.queued(2), Ok(Some((2, BASE))));
+        assert_eq!(
+            schedule::<CustomClient, _>(&mut words, STAGE).err(),
+            Some(ClientError::Pending(STAGE))
+        );
+
+        // Installing the base update would erase the update staged below it
+        assert_eq!(
+            enqueue::<CustomClient, _>(&mut words, UNKNOWN).err(),
+            Some(ClientError::Overwritten(BASE))
+        );
+
+        // Cancelling takes the first update off the queue, without freeing its slot
+        assert_eq!(cancel(&mut words), Ok(Some(STAGE)));
+        assert_eq!(words.pending(), Ok(Some((1, UNKNOWN))));
+        enqueue::<CustomClient, _>(&mut words, BASE).unwrap();
+        assert_eq!(
+            enqueue::<CustomClient, _>(&mut words, BASE).err(),
+            Some(ClientError::Full)
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        let mut words = Single::<_, Word>::new(RamFlash(std::vec![0xff; 16]));
+        schedule::<TestClient, _>(&mut words, STAGE).unwrap();
+        cancel(&mut words).unwrap();
+        schedule::<TestClient, _>(&mut words, STAGE).unwrap();
+        assert_eq!(
+            words.into_flash().0,
+            [
+                0, 0, 0, 0, 0x00, 0x50, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+            ]
+        );
+
+        let mut pairs = Single::<_, Pair>::new(RamFlash(std::vec![0xff; 32]));
+        schedule::<TestClient, _>(&mut pairs, STAGE).unwrap();
+        cancel(&mut pairs).unwrap();
+        schedule::<TestClient, _>(&mut pairs, STAGE).unwrap();
+        let mut expected = std::vec![0xff; 32];
+        expected[..16].copy_from_slice(&[0, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        expected[16..24].copy_from_slice(&[0, 0x50, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(pairs.into_flash().0, expected);
+    }
+
+    #[test]
+    fn invalid() {
+        let rejected = |address| validate::<TestClient>(address).err();
+        assert_eq!(
+            rejected(CORRUPT),
+            Some(Rejected::Update(UpdateFault::Checksum))
+        );
+        assert_eq!(
+            rejected(STAGE + 0x200),
+            Some(Rejected::Update(UpdateFault::Size))
+        );
+        assert_eq!(
+            rejected(FW_START - 16),
+            Some(Rejected::Update(UpdateFault::Address))
+        );
+        assert_eq!(
+            rejected(FW_END),
+            Some(Rejected::Update(UpdateFault::Address))
+        );
+        assert_eq!(rejected(UNKNOWN), Some(Rejected::UnsupportedType(0x42)));
+        assert_eq!(
+            validate::<CustomClient>(UNKNOWN).map(|i| i.uptype),
+            Ok(0x42)
+        );
+
+        // Nothing gets programmed for an invalid update
+        let mut words = Single::<_, Word>::new(RamFlash(std::vec![0xff; 16]));
+        assert!(schedule::<TestClient, _>(&mut words, CORRUPT).is_err());
+        assert_eq!(words.remaining(), Ok(4));
+    }
+}
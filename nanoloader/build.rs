@@ -0,0 +1,8 @@
+fn main() {
+    // Vector table entry 12 (0x30) is only reserved on ARMv6-M, see `check::layout`
+    println!("cargo::rustc-check-cfg=cfg(armv6m)");
+    if std::env::var("TARGET").unwrap().starts_with("thumbv6m-") {
+        println!("cargo::rustc-cfg=armv6m");
+    }
+    println!("cargo::rerun-if-changed=build.rs");
+}
@@ -0,0 +1,65 @@
+//! Drive the LZ4 decoders with arbitrary input and dictionaries
+//!
+//! Input layout: flags (1 byte), dictionary length (1 byte), dictionary, compressed data. Bit 0
+//! of the flags selects frame instead of block format, the remaining bits select the chunk size
+//! for the streaming decoder.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use nanoloader::lz4::sink::{FlashSink, RingSink};
+use nanoloader::lz4::stream::Decoder;
+use nanoloader::lz4::{self, frame};
+
+/// Large enough for any LZ4 back-reference, so the ring sink must agree with the flash sink
+const WINDOW: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let [flags, dict_len, rest @ ..] = data else {
+        return;
+    };
+    let Some((dict, compressed)) = rest.split_at_checked(*dict_len as usize) else {
+        return;
+    };
+
+    if flags & 1 == 0 {
+        block(compressed, dict, (*flags >> 1) as usize + 1);
+    } else {
+        frame(compressed, dict);
+    }
+});
+
+fn block(compressed: &[u8], dict: &[u8], chunk: usize) {
+    let mut flash = FlashSink::new(Vec::new(), dict);
+    let expected = lz4::decompress(compressed, &mut flash);
+
+    let mut ring = Box::new(RingSink::<_, WINDOW>::new(Vec::new(), dict));
+    assert_eq!(lz4::decompress(compressed, &mut *ring), expected);
+
+    let mut streamed = FlashSink::new(Vec::new(), dict);
+    let mut decoder = Decoder::new();
+    let result = compressed
+        .chunks(chunk)
+        .try_for_each(|c| decoder.feed(c, &mut streamed))
+        .and_then(|_| decoder.finish());
+    assert_eq!(result, expected);
+
+    if expected.is_ok() {
+        let output = flash.into_output();
+        assert_eq!(ring.into_output(), output);
+        assert_eq!(streamed.into_output(), output);
+    }
+}
+
+fn frame(compressed: &[u8], dict: &[u8]) {
+    let mut flash = FlashSink::new(Vec::new(), dict);
+    let expected = frame::decompress(compressed, &mut flash);
+
+    let mut ring = Box::new(RingSink::<_, WINDOW>::new(Vec::new(), dict));
+    assert_eq!(frame::decompress(compressed, &mut *ring), expected);
+
+    if expected.is_ok() {
+        assert_eq!(ring.into_output(), flash.into_output());
+    }
+}
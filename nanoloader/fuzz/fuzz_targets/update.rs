@@ -0,0 +1,160 @@
+//! Drive update checking and installation against a simulated Flash
+//!
+//! Input layout: update pointer (u32, little-endian, 0 for none), followed by the initial
+//! contents of the firmware area. The rest of the firmware area is erased.
+
+#![no_main]
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use libfuzzer_sys::fuzz_target;
+
+use nanoloader::{
+    Digest, FlashProgrammer, Layout, Nano, NanoReason, NanoResult, Reporter, UpdateStore,
+};
+
+const FW_START: usize = 0x4000;
+const FW_END: usize = 0x6000;
+const FW_AREA: usize = FW_END - FW_START;
+const PAGE_SZ: usize = 1024;
+
+#[repr(C, align(8))]
+struct Flash(UnsafeCell<[u8; FW_AREA]>);
+
+// SAFETY: Fuzz targets are run from a single thread
+unsafe impl Sync for Flash {}
+
+static FLASH: Flash = Flash(UnsafeCell::new([0xff; FW_AREA]));
+static UPDATE_PTR: AtomicU32 = AtomicU32::new(0);
+static UPDATE_CLEARED: AtomicBool = AtomicBool::new(false);
+
+struct SimLayout;
+
+impl Layout for SimLayout {
+    const FW_START: usize = FW_START;
+    const FW_END: usize = FW_END;
+    const FW_SIZE_OFF: usize = 0x30;
+    const FW_PAGE_SZ: usize = PAGE_SZ;
+
+    fn fwarea() -> &'static [u8] {
+        // SAFETY: The Flash is only modified in program_finish, when no references are held
+        unsafe { &*FLASH.0.get() }
+    }
+}
+
+struct Crc;
+
+impl Digest for Crc {
+    fn checksum(&self, data: &[u8]) -> u32 {
+        const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        CRC32.checksum(data)
+    }
+}
+
+struct SimStore;
+
+impl UpdateStore for SimStore {
+    fn update_address(&mut self) -> Option<usize> {
+        let ptr = UPDATE_PTR.load(Ordering::Relaxed);
+        (ptr != 0 && !UPDATE_CLEARED.load(Ordering::Relaxed)).then_some(ptr as usize)
+    }
+
+    fn update_queued(&mut self, position: usize) -> Option<usize> {
+        self.update_address().filter(|_| position == 0)
+    }
+
+    fn update_clear(&mut self) {
+        UPDATE_CLEARED.store(true, Ordering::Relaxed);
+    }
+}
+
+struct SimReporter;
+
+impl Reporter for SimReporter {
+    fn abort(&mut self, reason: NanoReason) -> ! {
+        panic!("abort: {reason:?}");
+    }
+}
+
+/// Simulated Flash programming
+///
+/// Programmed data is buffered and only committed to the Flash once programming is finished, so
+/// that no references into the firmware area are invalidated while an update is installed.
+#[derive(Default)]
+struct SimProgrammer {
+    programmed: Vec<u8>,
+}
+
+impl FlashProgrammer for SimProgrammer {
+    fn program_start(&mut self) -> NanoResult {
+        self.programmed.clear();
+        nanoloader::OK
+    }
+
+    fn program_write(&mut self, value: u8) -> NanoResult {
+        // Invariant: never write outside the firmware area, or over the update being installed
+        let limit = (UPDATE_PTR.load(Ordering::Relaxed) as usize).min(FW_END);
+        assert!(
+            FW_START + self.programmed.len() < limit,
+            "write at 0x{:08x} beyond limit 0x{:08x}",
+            FW_START + self.programmed.len(),
+            limit
+        );
+        self.programmed.push(value);
+        nanoloader::OK
+    }
+
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        self.programmed
+            .get(offset)
+            .copied()
+            .ok_or(NanoReason::HalError(0))
+    }
+
+    fn program_finish(&mut self) -> NanoResult {
+        // Pages are erased as they are programmed, so the rest of the last page is left erased
+        let len = self.programmed.len().next_multiple_of(PAGE_SZ).min(FW_AREA);
+        self.programmed.resize(len, 0xff);
+
+        // SAFETY: No references into the firmware area are held while programming
+        unsafe { (&mut *FLASH.0.get())[..len].copy_from_slice(&self.programmed) };
+        nanoloader::OK
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((ptr, contents)) = data.split_first_chunk::<4>() else {
+        return;
+    };
+    let contents = &contents[..contents.len().min(FW_AREA)];
+
+    // SAFETY: No references into the firmware area are held between runs
+    unsafe {
+        let flash = &mut *FLASH.0.get();
+        flash.fill(0xff);
+        flash[..contents.len()].copy_from_slice(contents);
+    }
+    UPDATE_PTR.store(u32::from_le_bytes(*ptr), Ordering::Relaxed);
+    UPDATE_CLEARED.store(false, Ordering::Relaxed);
+
+    let mut nano: Nano<SimLayout, _, _, _, _> =
+        Nano::new(Crc, SimStore, SimProgrammer::default(), SimReporter);
+
+    let update = nano.fuzz_check_update();
+    if let Some((address, size)) = update {
+        // Invariant: a valid update lies entirely within the firmware area
+        assert!(address >= FW_START && address + size <= FW_END);
+    }
+
+    nano.fuzz_process_update();
+
+    // Invariant: an invalid update is cleared, a valid one only if there is valid firmware
+    // afterwards
+    let cleared = UPDATE_CLEARED.load(Ordering::Relaxed);
+    match update {
+        Some(_) if cleared => assert!(nano.fuzz_check_firmware().is_ok()),
+        Some(_) => {}
+        None => assert_eq!(cleared, *ptr != [0; 4]),
+    }
+});
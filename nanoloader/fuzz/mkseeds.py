@@ -0,0 +1,63 @@
+import os
+import struct
+
+here = os.path.dirname(os.path.abspath(__file__))
+testdata = os.path.join(here, '..', 'src', 'lz4', 'testdata')
+firmware = os.path.join(here, '..', '..', 'moonbow', 'test')
+
+FW_START = 0x4000
+UPDATE_OFF = 0x1000
+
+def read(path:str) -> bytes:
+    with open(path, 'rb') as fh:
+        return fh.read()
+
+def ihex(path:str) -> dict[int, bytes]:
+    '''Load an Intel HEX file into a dictionary of contiguous segments'''
+    mem = {}
+    base = 0
+    for line in open(path):
+        rec = bytes.fromhex(line.strip()[1:])
+        n, addr, rtype, data = rec[0], (rec[1] << 8) | rec[2], rec[3], rec[4:4 + rec[0]]
+        if rtype == 0:
+            for i, b in enumerate(data):
+                mem[base + addr + i] = b
+        elif rtype == 2:
+            base = int.from_bytes(data, 'big') << 4
+        elif rtype == 4:
+            base = int.from_bytes(data, 'big') << 16
+    segments = {}
+    for addr in sorted(mem):
+        for start, seg in segments.items():
+            if start + len(seg) == addr:
+                seg.append(mem[addr])
+                break
+        else:
+            segments[addr] = bytearray([mem[addr]])
+    return {k: bytes(v) for k, v in segments.items()}
+
+def seed(target:str, name:str, data:bytes) -> None:
+    path = os.path.join(here, 'seeds', target)
+    os.makedirs(path, exist_ok=True)
+    with open(os.path.join(path, name), 'wb') as fh:
+        fh.write(data)
+
+
+# decompress: flags, dictionary length, dictionary, compressed data
+dct = read(os.path.join(testdata, 'lorem2.dct'))
+for name in ['lorem1', 'lorem2']:
+    d = dct if name == 'lorem2' else b''
+    seed('decompress', f'{name}-block', bytes([0x00, len(d)]) + d + read(os.path.join(testdata, f'{name}.lz4')))
+    seed('decompress', f'{name}-chunked', bytes([0x0e, len(d)]) + d + read(os.path.join(testdata, f'{name}.lz4')))
+    seed('decompress', f'{name}-frame', bytes([0x01, len(d)]) + d + read(os.path.join(testdata, f'{name}.lz4f')))
+for name in ['empty', 'lorem3', 'random']:
+    seed('decompress', f'{name}-frame', bytes([0x01, 0]) + read(os.path.join(testdata, f'{name}.lz4f')))
+
+# update: update pointer, firmware area contents
+patched = ihex(os.path.join(firmware, 'hello.patched.hex'))[FW_START]
+update = [seg for addr, seg in ihex(os.path.join(firmware, 'hello2.up')).items() if addr != 0x3c00][0]
+
+area = bytearray(patched.ljust(UPDATE_OFF, b'\xff'))
+seed('update', 'no-update', struct.pack('<I', 0) + patched)
+seed('update', 'hello2', struct.pack('<I', FW_START + UPDATE_OFF) + area + update)
+seed('update', 'hello2-no-firmware', struct.pack('<I', FW_START + UPDATE_OFF) + b'\xff' * UPDATE_OFF + update)
@@ -0,0 +1,48 @@
+// Adapted from the cortex-m-quickstart boilerplate
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Bootloader code budget: `BL_CODE` of the MSPM0C Loader
+const BUDGET: &str = "3K";
+/// Logging, events and the factory image do not fit in `BL_CODE` of the MSPM0C Loader; budget
+/// for development builds and parts with a little more Flash
+const BUDGET_EXTRA: &str = "5K";
+/// LZ4 and measured boot need a bootloader region like the Test Loader's
+const BUDGET_FULL: &str = "14K";
+
+fn main() {
+    let feature = |name: &str| env::var_os(format!("CARGO_FEATURE_{name}")).is_some();
+    let budget = if feature("LZ4") || feature("MEASURED") {
+        BUDGET_FULL
+    } else if feature("LOG") || feature("EVENTS") || feature("FACTORY") {
+        BUDGET_EXTRA
+    } else {
+        BUDGET
+    };
+    // Recovery word and measured boot handover
+    let reserved = match env::var_os("CARGO_FEATURE_MEASURED") {
+        Some(_) => 4 + 72,
+        None => 4,
+    };
+
+    // Put `memory.x` with the budget filled in into our output directory and ensure it's
+    // on the linker search path.
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    File::create(out.join("memory.x"))
+        .unwrap()
+        .write_all(
+            include_str!("memory.x.in")
+                .replace("BL_CODE_SIZE", budget)
+                .replace("RAM_RESERVED", &reserved.to_string())
+                .as_bytes(),
+        )
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x.in");
+
+    println!("cargo:rustc-link-arg=--nmagic");
+    println!("cargo:rustc-link-arg=-Tlink.x");
+}
@@ -0,0 +1,13 @@
+#!/bin/sh
+# Build the size probe in representative configurations and report code sizes. Linking fails
+# if a configuration does not fit its budget (see build.rs).
+set -e
+cd "$(dirname "$0")"
+
+for features in "" "plain" "plain,recovery" "plain,recovery,progress" \
+    "plain,recovery,progress,policy" "plain,factory,events" "log,plain,recovery" \
+    "plain,lz4" "plain,measured" "log,plain,recovery,progress,policy,events,factory,lz4,measured"; do
+    cargo build --quiet --release --no-default-features --features "$features"
+    printf '%-66s' "[$features]"
+    llvm-size target/thumbv6m-none-eabi/release/nanoloader-size | awk 'NR == 2 { print $4 " bytes" }'
+done
@@ -0,0 +1,327 @@
+//! Size probe: a minimal bootloader with the same shape as the MSPM0C Loader
+//!
+//! The HAL does just enough to keep every part of nanoloader that is enabled by the selected
+//! features from being optimized away.
+
+#![no_main]
+#![no_std]
+
+use core::convert::Infallible;
+
+use nanoloader::options::{Banked, OptionsFlash, Pair, UpdatePointers};
+use nanoloader::{
+    Digest, FlashProgrammer, Layout, Nano, NanoReason, NanoResult, Reporter, UpdateStore,
+};
+
+const FLASH_PAGE_SZ: usize = 1024;
+const BL_DATA_START: usize = 3 * 1024;
+const BL_DATA2_START: usize = 15 * 1024;
+
+/// Flash controller registers
+const FLASH_ADDR: *mut u32 = 0x4000_0004 as *mut u32;
+const FLASH_DATA: *mut u32 = 0x4000_0008 as *mut u32;
+const FLASH_COMMAND: *mut u32 = 0x4000_000c as *mut u32;
+
+/// Output register for installation progress
+#[cfg(feature = "progress")]
+const PROGRESS: *mut u32 = 0x4000_0010 as *mut u32;
+
+/// Input register for the update policy, and output register for the decision taken
+#[cfg(feature = "policy")]
+const POLICY: *mut u32 = 0x4000_0014 as *mut u32;
+
+/// Output register for events, taking a record as two words
+#[cfg(feature = "events")]
+const EVENT: *mut u32 = 0x4000_0018 as *mut u32;
+
+/// Unique device secret, and the register locking it away
+#[cfg(feature = "measured")]
+const UDS: *const [u8; 32] = 0x4000_0020 as *const [u8; 32];
+#[cfg(feature = "measured")]
+const UDS_LOCK: *mut u32 = 0x4000_0040 as *mut u32;
+
+/// Measured boot handover, reserved in memory.x after the recovery word
+#[cfg(feature = "measured")]
+const HANDOVER: *mut nanoloader::measure::Handover = 0x2000_0004 as *mut _;
+
+fn flash_command(address: usize, data: &[u8], command: u32) {
+    // SAFETY: Registers of the Flash controller
+    unsafe {
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            core::ptr::write_volatile(FLASH_ADDR, (address + i * 4) as u32);
+            core::ptr::write_volatile(FLASH_DATA, u32::from_le_bytes(word.try_into().unwrap()));
+            core::ptr::write_volatile(FLASH_COMMAND, command);
+        }
+    }
+}
+
+fn program(address: usize, data: &[u8]) {
+    flash_command(address, data, 0x860cd758);
+}
+
+fn erase(address: usize) {
+    flash_command(address, &[0; 4], 0x4c6f315f);
+}
+
+#[cfg(feature = "log")]
+mod logger {
+    use log::{Log, Metadata, Record};
+
+    /// Output register for log messages
+    const LOG_DATA: *mut u32 = 0x4000_0100 as *mut u32;
+
+    struct Logger;
+
+    impl Log for Logger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            use core::fmt::Write;
+            write!(Logger, "{}", record.args()).ok();
+        }
+
+        fn flush(&self) {}
+    }
+
+    impl core::fmt::Write for Logger {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for b in s.bytes() {
+                // SAFETY: Output register
+                unsafe { core::ptr::write_volatile(LOG_DATA, b as u32) };
+            }
+            Ok(())
+        }
+    }
+
+    pub fn init() {
+        // SAFETY: Called once, before anything else runs
+        unsafe {
+            log::set_logger_racy(&Logger).ok();
+            log::set_max_level_racy(log::LevelFilter::Info);
+        }
+    }
+}
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    #[cfg(feature = "log")]
+    logger::init();
+
+    let nano: Nano<SizeLayout, _, _, _, _> = Nano::new(
+        Crc32,
+        DataStore(SizeLayout::options()),
+        SizeProgrammer::default(),
+        SizeReporter,
+    );
+    #[cfg(feature = "measured")]
+    let nano = nano.with_measure(nanoloader::measure::Dice {
+        secret: SizeSecret,
+        handover: HANDOVER,
+    });
+    nano.boot()
+}
+
+#[panic_handler]
+fn panic(_panic: &core::panic::PanicInfo<'_>) -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Update pointer page, holding pairs of 64-bit words
+struct DataPage(usize);
+
+impl OptionsFlash for DataPage {
+    type Error = Infallible;
+
+    fn page(&self) -> &[u8] {
+        // SAFETY: Options page in Flash
+        unsafe { core::slice::from_raw_parts(self.0 as *const u8, FLASH_PAGE_SZ) }
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Infallible> {
+        program(self.0 + offset, data);
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), Infallible> {
+        erase(self.0);
+        Ok(())
+    }
+}
+
+struct SizeLayout;
+
+impl SizeLayout {
+    fn options() -> Banked<DataPage, Pair> {
+        let Ok(options) = Banked::new([DataPage(BL_DATA_START), DataPage(BL_DATA2_START)]);
+        options
+    }
+}
+
+impl Layout for SizeLayout {
+    const FW_START: usize = 4 * 1024;
+    const FW_END: usize = 15 * 1024;
+    const FW_SIZE_OFF: usize = 0x30;
+    const FW_PAGE_SZ: usize = FLASH_PAGE_SZ;
+    // The MSPM0C has no room for a factory image, so this lies beyond its Flash
+    #[cfg(feature = "factory")]
+    const FACTORY: Option<core::ops::Range<usize>> = Some(16 * 1024..24 * 1024);
+}
+
+struct Crc32;
+
+impl Digest for Crc32 {
+    fn checksum(&self, data: &[u8]) -> u32 {
+        const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        CRC32.checksum(data)
+    }
+}
+
+struct DataStore(Banked<DataPage, Pair>);
+
+impl UpdateStore for DataStore {
+    fn update_address(&mut self) -> Option<usize> {
+        let Ok(pending) = self.0.pending();
+        pending.map(|(_, address)| address)
+    }
+
+    fn update_queued(&mut self, position: usize) -> Option<usize> {
+        let Ok(queued) = self.0.queued(position);
+        queued.map(|(_, address)| address)
+    }
+
+    fn update_clear(&mut self) {
+        if let Ok(Some((index, _))) = self.0.pending() {
+            let Ok(()) = self.0.clear(index);
+        }
+    }
+}
+
+struct SizeReporter;
+
+impl Reporter for SizeReporter {
+    fn abort(&mut self, _reason: NanoReason) -> ! {
+        cortex_m::peripheral::SCB::sys_reset()
+    }
+
+    #[cfg(feature = "recovery")]
+    fn recovery_requested(&mut self) -> bool {
+        // SAFETY: The word is reserved in memory.x
+        unsafe { nanoloader::recovery::take_request(0x2000_0000 as *mut u32) }
+    }
+
+    #[cfg(feature = "recovery")]
+    fn recovery_wait(&mut self) -> bool {
+        cortex_m::asm::wfi();
+        true
+    }
+
+    #[cfg(feature = "progress")]
+    fn progress(&mut self, phase: nanoloader::progress::Phase, done: usize, _total: usize) {
+        // SAFETY: Output register
+        unsafe { core::ptr::write_volatile(PROGRESS, ((phase as u32) << 24) | done as u32) };
+    }
+
+    #[cfg(feature = "policy")]
+    fn update_policy(
+        &mut self,
+        _address: usize,
+        _info: &nanoloader::UpdateInfo,
+    ) -> nanoloader::policy::Decision {
+        // SAFETY: Input register
+        let word = unsafe { core::ptr::read_volatile(POLICY) };
+        nanoloader::policy::Decision::from_word(word)
+            .unwrap_or(nanoloader::policy::Decision::Install)
+    }
+
+    #[cfg(feature = "policy")]
+    fn record_decision(&mut self, decision: nanoloader::policy::Decision) {
+        // SAFETY: Output register
+        unsafe { nanoloader::policy::record(POLICY, decision) };
+    }
+
+    #[cfg(feature = "events")]
+    fn event(&mut self, event: nanoloader::events::Event) {
+        for word in event.encode().chunks_exact(4) {
+            // SAFETY: Output register
+            unsafe {
+                core::ptr::write_volatile(EVENT, u32::from_le_bytes(word.try_into().unwrap()))
+            };
+        }
+    }
+}
+
+#[cfg(feature = "measured")]
+struct SizeSecret;
+
+#[cfg(feature = "measured")]
+impl nanoloader::measure::DeviceSecret for SizeSecret {
+    fn read(&mut self, uds: &mut [u8; 32]) -> NanoResult {
+        // SAFETY: Secret register
+        *uds = unsafe { core::ptr::read_volatile(UDS) };
+        nanoloader::OK
+    }
+
+    fn lock(&mut self) {
+        // SAFETY: Lock register
+        unsafe { core::ptr::write_volatile(UDS_LOCK, 1) };
+    }
+}
+
+#[derive(Default)]
+struct SizeProgrammer {
+    address: usize,
+    buffer: u64,
+    count: u8,
+}
+
+impl SizeProgrammer {
+    fn commit(&mut self) {
+        if self.address.is_multiple_of(FLASH_PAGE_SZ) {
+            erase(self.address);
+        }
+        program(self.address, &self.buffer.to_le_bytes());
+        self.address += 8;
+        self.buffer = !0;
+        self.count = 0;
+    }
+}
+
+impl FlashProgrammer for SizeProgrammer {
+    fn program_start(&mut self) -> NanoResult {
+        self.address = SizeLayout::FW_START;
+        self.buffer = !0;
+        self.count = 0;
+        nanoloader::OK
+    }
+
+    fn program_write(&mut self, value: u8) -> NanoResult {
+        let shift = self.count * 8;
+        self.buffer = (self.buffer & !(0xff << shift)) | ((value as u64) << shift);
+        self.count += 1;
+        if self.count == 8 {
+            self.commit();
+        }
+        nanoloader::OK
+    }
+
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        let addr = SizeLayout::FW_START + offset;
+        if addr < self.address {
+            // SAFETY: Already programmed part of the firmware area
+            Ok(unsafe { core::ptr::read_volatile(addr as *const u8) })
+        } else if addr - self.address < self.count as usize {
+            Ok((self.buffer >> ((addr - self.address) * 8)) as u8)
+        } else {
+            Err(NanoReason::HalError(0))
+        }
+    }
+
+    fn program_finish(&mut self) -> NanoResult {
+        if self.count != 0 {
+            self.commit();
+        }
+        nanoloader::OK
+    }
+}
@@ -0,0 +1,264 @@
+//! Firmware and update checks that do not depend on a HAL
+//!
+//! The bootloader runs these on its own firmware area; host tools can run them on Flash dumps.
+
+use crate::{NanoReason, NanoResult, UpdateInfo, ensure, read_checked};
+
+/// Reason why a firmware area layout is invalid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutFault {
+    /// The page size is not a power of two
+    PageSize,
+    /// The firmware area is not page-aligned
+    Alignment,
+    /// The firmware area is empty
+    Empty,
+    /// The size offset is not word-aligned, or not in a reserved vector table entry
+    SizeOffset,
+}
+
+impl LayoutFault {
+    pub const fn message(self) -> &'static str {
+        match self {
+            LayoutFault::PageSize => "page size is not a power of two",
+            LayoutFault::Alignment => "firmware area is not page-aligned",
+            LayoutFault::Empty => "firmware area is empty",
+            LayoutFault::SizeOffset => "size offset is not a reserved vector table entry",
+        }
+    }
+}
+
+/// Check the layout of the firmware area
+///
+/// The firmware size has to be stored in one of the reserved entries of the vector table. Entry
+/// 12 (0x30) is only reserved on ARMv6-M, ARMv7-M and later use it for the DebugMonitor handler.
+/// This check accepts it for any target, the bootloader itself refuses to build with it for
+/// anything but ARMv6-M.
+pub const fn layout(
+    fw_start: usize,
+    fw_end: usize,
+    size_off: usize,
+    page_sz: usize,
+) -> Result<(), LayoutFault> {
+    if !page_sz.is_power_of_two() {
+        return Err(LayoutFault::PageSize);
+    }
+    if !fw_start.is_multiple_of(page_sz) || !fw_end.is_multiple_of(page_sz) {
+        return Err(LayoutFault::Alignment);
+    }
+    if fw_start >= fw_end {
+        return Err(LayoutFault::Empty);
+    }
+    if !matches!(size_off, 0x1c | 0x20 | 0x24 | 0x28 | 0x30 | 0x34) {
+        return Err(LayoutFault::SizeOffset);
+    }
+    Ok(())
+}
+
+/// Firmware found in the firmware area
+#[derive(Debug, Clone, Copy)]
+pub struct Firmware<'a> {
+    /// Firmware image, excluding the CRC
+    pub data: &'a [u8],
+    /// CRC stored after the firmware image
+    pub crc_expected: u32,
+    /// CRC calculated over the firmware image
+    pub crc_actual: u32,
+}
+
+impl Firmware<'_> {
+    pub fn is_valid(&self) -> bool {
+        self.crc_expected == self.crc_actual
+    }
+}
+
+/// Locate the firmware in the firmware area and calculate its CRC
+pub fn firmware(
+    fwarea: &[u8],
+    size_off: usize,
+    checksum: impl FnOnce(&[u8]) -> u32,
+) -> NanoResult<Firmware<'_>> {
+    // Read firmware size (always 32 bits, also when running on a host)
+    let fwsize = read_checked::<u32>(fwarea, size_off).ok_or(NanoReason::FwSizeInvalid)?;
+
+    // Split firmware area from rest
+    let (data, rest) = fwarea
+        .split_at_checked(fwsize as usize)
+        .ok_or(NanoReason::FwSizeInvalid)?;
+
+    // Read expected firmware CRC
+    let crc_expected = read_checked::<u32>(rest, 0).ok_or(NanoReason::FwSizeInvalid)?;
+
+    Ok(Firmware {
+        data,
+        crc_expected,
+        crc_actual: checksum(data),
+    })
+}
+
+/// Reason why an update was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateFault {
+    /// Update address is outside of the firmware area or misaligned
+    Address,
+    /// Update size exceeds the firmware area
+    Size,
+    /// Update checksum mismatch
+    Checksum,
+}
+
+/// Update found in the firmware area
+#[derive(Debug, Clone, Copy)]
+pub struct Update<'a> {
+    pub info: UpdateInfo,
+    pub address: usize,
+    /// Update payload, following the header
+    pub data: &'a [u8],
+}
+
+/// Check the update at `address`
+pub fn update(
+    fwarea: &[u8],
+    fw_start: usize,
+    address: usize,
+    checksum: impl FnOnce(&[u8]) -> u32,
+) -> Result<Update<'_>, UpdateFault> {
+    // Calculate offset of update into firmware area
+    let upinfo_off = address.checked_sub(fw_start).ok_or(UpdateFault::Address)?;
+
+    // Read the update info header
+    let info = read_checked::<UpdateInfo>(fwarea, upinfo_off).ok_or(UpdateFault::Address)?;
+
+    // Create slice for entire update
+    let upslice = upinfo_off
+        .checked_add(info.upsize as usize)
+        .and_then(|end| fwarea.get(upinfo_off..end))
+        .ok_or(UpdateFault::Size)?;
+
+    let payload = upslice.get(size_of::<u32>()..).ok_or(UpdateFault::Size)?;
+    ensure(info.checksum == checksum(payload)).ok_or(UpdateFault::Checksum)?;
+
+    Ok(Update {
+        info,
+        address,
+        data: upslice
+            .get(size_of::<UpdateInfo>()..)
+            .ok_or(UpdateFault::Size)?,
+    })
+}
+
+/// Reason why an update cannot be installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallFault {
+    /// Firmware size does not match the update payload
+    Size,
+    /// New firmware would overwrite the update while it is installed
+    Overlap,
+    /// Compressed payload is not a single LZ4 frame without dictionary
+    Format,
+}
+
+/// Check that a plain update can be installed, returning the Flash size it occupies
+///
+/// `page_sz` must be a power of two.
+#[inline]
+pub fn plain(update: &Update, fw_start: usize, page_sz: usize) -> Result<usize, InstallFault> {
+    ensure(update.info.fwsize as usize == update.data.len()).ok_or(InstallFault::Size)?;
+    footprint(update, fw_start, page_sz)
+}
+
+/// Check that an LZ4 update can be installed, returning the Flash size it occupies
+///
+/// Only the frame header is checked here; the frame's content size, if present, has to match the
+/// firmware size. `page_sz` must be a power of two.
+pub fn lz4(update: &Update, fw_start: usize, page_sz: usize) -> Result<usize, InstallFault> {
+    let (descriptor, _) =
+        crate::lz4::frame::descriptor(update.data).or(Err(InstallFault::Format))?;
+    ensure(descriptor.dict_id.is_none()).ok_or(InstallFault::Format)?;
+    ensure(
+        descriptor
+            .content_size
+            .is_none_or(|size| size == update.info.fwsize as u64),
+    )
+    .ok_or(InstallFault::Size)?;
+    footprint(update, fw_start, page_sz)
+}
+
+/// Flash size occupied by the new firmware, which must end before the update
+fn footprint(update: &Update, fw_start: usize, page_sz: usize) -> Result<usize, InstallFault> {
+    let size = (update.info.fwsize as usize)
+        .checked_add(page_sz - 1)
+        .ok_or(InstallFault::Size)?
+        & !(page_sz - 1);
+    ensure(fw_start.checked_add(size).ok_or(InstallFault::Overlap)? <= update.address)
+        .ok_or(InstallFault::Overlap)?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        assert_eq!(layout(0x4000, 0x10000, 0x30, 1024), Ok(()));
+        assert_eq!(layout(0x1000, 0x3c00, 0x1c, 1024), Ok(()));
+        assert_eq!(
+            layout(0x4000, 0x10000, 0x30, 1000),
+            Err(LayoutFault::PageSize)
+        );
+        assert_eq!(
+            layout(0x4200, 0x10000, 0x30, 1024),
+            Err(LayoutFault::Alignment)
+        );
+        assert_eq!(
+            layout(0x4000, 0x10100, 0x30, 1024),
+            Err(LayoutFault::Alignment)
+        );
+        assert_eq!(layout(0x4000, 0x4000, 0x30, 1024), Err(LayoutFault::Empty));
+        assert_eq!(
+            layout(0x4000, 0x10000, 0x2c, 1024),
+            Err(LayoutFault::SizeOffset)
+        );
+        assert_eq!(
+            layout(0x4000, 0x10000, 0x32, 1024),
+            Err(LayoutFault::SizeOffset)
+        );
+        assert_eq!(
+            layout(0x4000, 0x10000, 0x100, 1024),
+            Err(LayoutFault::SizeOffset)
+        );
+    }
+
+    #[test]
+    fn lz4_updates() {
+        let firmware = [0x5a; 3000];
+        let data = crate::lz4::frame::compress(&firmware, crate::lz4::Mode::Fast);
+        let update = |fwsize: u32, data| Update {
+            info: UpdateInfo {
+                checksum: 0,
+                upsize: 0,
+                uptype: UpdateInfo::TYPE_LZ4,
+                fwsize,
+            },
+            address: 0x8000,
+            data,
+        };
+
+        assert_eq!(lz4(&update(3000, &data), 0x4000, 1024), Ok(3072));
+        assert_eq!(
+            lz4(&update(2000, &data), 0x4000, 1024),
+            Err(InstallFault::Size)
+        );
+        assert_eq!(
+            lz4(&update(3000, &firmware), 0x4000, 1024),
+            Err(InstallFault::Format)
+        );
+
+        let update = Update {
+            address: 0x4800,
+            ..update(3000, &data)
+        };
+        assert_eq!(lz4(&update, 0x4000, 1024), Err(InstallFault::Overlap));
+    }
+}
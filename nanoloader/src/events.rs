@@ -0,0 +1,328 @@
+//! Persistent event log
+//!
+//! The bootloader reports what it did on each boot as an [`Event`] to `Reporter::event`, and the
+//! HAL can append it to an [`EventLog`] in reserved Flash pages, for the application and host
+//! tools to decode after a failure in the field.
+//!
+//! The pages are used as a ring. Each starts with a header holding a 32-bit sequence number and
+//! its complement, followed by 8-byte records that are programmed in order. Once the newest page
+//! is full, the next one in the ring, which holds the oldest records, is erased and takes the next
+//! sequence number. This way, all pages are erased equally often.
+
+use crate::NanoReason;
+use crate::check::UpdateFault;
+use crate::options::{OptionsFlash, read};
+
+/// Something the bootloader did, or failed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Booted valid firmware of `fwsize` bytes
+    Boot { fwsize: u32 },
+    /// The pending update at `address` is invalid
+    UpdateInvalid { address: usize, fault: UpdateFault },
+    /// Installed the update at `address`
+    Installed { address: usize },
+    /// Installing the update at `address` left no valid firmware, the update stays pending
+    InstallFailed { address: usize },
+    /// The update policy deferred the update at `address`, with the HAL's reason code
+    Deferred { address: usize, reason: u16 },
+    /// The update policy rejected the update at `address`, with the HAL's reason code
+    Rejected { address: usize, reason: u16 },
+    /// Reinstalled the factory image, `fwsize` bytes
+    Factory { fwsize: u32 },
+    /// The bootloader gave up
+    Abort(NanoReason),
+}
+
+/// Size of an encoded event
+pub const RECORD_SZ: usize = 8;
+
+/// Size of the page header
+const HEADER_SZ: usize = 8;
+
+impl Event {
+    /// Record: kind, check byte, 16-bit code and 32-bit value, little-endian
+    pub fn encode(self) -> [u8; RECORD_SZ] {
+        let (kind, code, value): (u8, u16, u32) = match self {
+            Event::Boot { fwsize } => (1, 0, fwsize),
+            Event::UpdateInvalid { address, fault } => (2, fault as u16, address as u32),
+            Event::Installed { address } => (3, 0, address as u32),
+            Event::InstallFailed { address } => (4, 0, address as u32),
+            Event::Deferred { address, reason } => (5, reason, address as u32),
+            Event::Rejected { address, reason } => (6, reason, address as u32),
+            Event::Factory { fwsize } => (7, 0, fwsize),
+            Event::Abort(NanoReason::HalError(e)) => (8, e, 0),
+            Event::Abort(NanoReason::FwSizeInvalid) => (8, 0, 1),
+            Event::Abort(NanoReason::FwCrcMismatch) => (8, 0, 2),
+        };
+
+        let mut record = [0; RECORD_SZ];
+        record[0] = kind;
+        record[2..4].copy_from_slice(&code.to_le_bytes());
+        record[4..].copy_from_slice(&value.to_le_bytes());
+        record[1] = !check(&record);
+        record
+    }
+
+    /// Decode a record, `None` if it is corrupt (e.g. torn by a reset) or unknown
+    pub fn decode(record: [u8; RECORD_SZ]) -> Option<Event> {
+        if check(&record) != 0xff {
+            return None;
+        }
+        let code = u16::from_le_bytes([record[2], record[3]]);
+        let value = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+        let address = value as usize;
+
+        Some(match (record[0], code, value) {
+            (1, 0, fwsize) => Event::Boot { fwsize },
+            (2, 0, _) => Event::UpdateInvalid {
+                address,
+                fault: UpdateFault::Address,
+            },
+            (2, 1, _) => Event::UpdateInvalid {
+                address,
+                fault: UpdateFault::Size,
+            },
+            (2, 2, _) => Event::UpdateInvalid {
+                address,
+                fault: UpdateFault::Checksum,
+            },
+            (3, 0, _) => Event::Installed { address },
+            (4, 0, _) => Event::InstallFailed { address },
+            (5, reason, _) => Event::Deferred { address, reason },
+            (6, reason, _) => Event::Rejected { address, reason },
+            (7, 0, fwsize) => Event::Factory { fwsize },
+            (8, e, 0) => Event::Abort(NanoReason::HalError(e)),
+            (8, 0, 1) => Event::Abort(NanoReason::FwSizeInvalid),
+            (8, 0, 2) => Event::Abort(NanoReason::FwCrcMismatch),
+            _ => return None,
+        })
+    }
+}
+
+/// Check byte over the record, which includes it
+fn check(record: &[u8; RECORD_SZ]) -> u8 {
+    record.iter().fold(0x5a, |acc, b| acc ^ b)
+}
+
+/// Sequence number of a page, `None` if it is erased or its header is invalid
+fn sequence<F: OptionsFlash>(page: &F) -> Result<Option<u32>, F::Error> {
+    if page.is_blank(0)? {
+        return Ok(None);
+    }
+    let header = read::<HEADER_SZ>(page.page(), 0);
+    let seq = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let check = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((seq == !check).then_some(seq))
+}
+
+/// Index and sequence number of the newest page
+fn newest<F: OptionsFlash>(pages: &[F]) -> Result<Option<(usize, u32)>, F::Error> {
+    let mut newest: Option<(usize, u32)> = None;
+    for (i, page) in pages.iter().enumerate() {
+        if let Some(seq) = sequence(page)?
+            && newest.is_none_or(|(_, newest)| seq > newest)
+        {
+            newest = Some((i, seq));
+        }
+    }
+    Ok(newest)
+}
+
+/// Call `f` with each record in `pages`, oldest first, as decoded or as raw bytes if corrupt
+///
+/// This works on any slice of pages, e.g. read from a Flash dump.
+pub fn for_each<F: OptionsFlash>(
+    pages: &[F],
+    mut f: impl FnMut(Result<Event, [u8; RECORD_SZ]>),
+) -> Result<(), F::Error> {
+    // The oldest page follows the newest one in the ring
+    let Some((newest, _)) = newest(pages)? else {
+        return Ok(());
+    };
+
+    for i in 1..=pages.len() {
+        let page = &pages[(newest + i) % pages.len()];
+        if sequence(page)?.is_none() {
+            continue;
+        }
+        let mut offset = HEADER_SZ;
+        while offset + RECORD_SZ <= page.page().len() && !page.is_blank(offset)? {
+            let record = read::<RECORD_SZ>(page.page(), offset);
+            f(Event::decode(record).ok_or(record));
+            offset += RECORD_SZ;
+        }
+    }
+    Ok(())
+}
+
+/// Event log in a ring of `N` Flash pages
+pub struct EventLog<F: OptionsFlash, const N: usize> {
+    pages: [F; N],
+    /// Newest page, and the offset of the next free record in it
+    active: usize,
+    next: usize,
+    seq: u32,
+}
+
+impl<F: OptionsFlash, const N: usize> EventLog<F, N> {
+    pub fn new(pages: [F; N]) -> Result<Self, F::Error> {
+        const { assert!(N > 0) }
+
+        // Without any valid page, start over with the first one
+        let Some((active, seq)) = newest(&pages)? else {
+            return Ok(Self {
+                active: N - 1,
+                next: usize::MAX,
+                seq: 0,
+                pages,
+            });
+        };
+
+        let page = &pages[active];
+        let mut next = HEADER_SZ;
+        while next + RECORD_SZ <= page.page().len() && !page.is_blank(next)? {
+            next += RECORD_SZ;
+        }
+        Ok(Self {
+            pages,
+            active,
+            next,
+            seq,
+        })
+    }
+
+    pub fn into_pages(self) -> [F; N] {
+        self.pages
+    }
+
+    /// Append `event`, erasing the oldest page if the newest one is full
+    pub fn append(&mut self, event: Event) -> Result<(), F::Error> {
+        if self.next.saturating_add(RECORD_SZ) > self.pages[self.active].page().len() {
+            self.active = (self.active + 1) % N;
+            self.seq = self.seq.wrapping_add(1);
+            self.next = HEADER_SZ;
+
+            let page = &mut self.pages[self.active];
+            page.erase()?;
+            let mut header = [0; HEADER_SZ];
+            header[..4].copy_from_slice(&self.seq.to_le_bytes());
+            header[4..].copy_from_slice(&(!self.seq).to_le_bytes());
+            page.program(0, &header)?;
+        }
+
+        self.pages[self.active].program(self.next, &event.encode())?;
+        self.next += RECORD_SZ;
+        Ok(())
+    }
+
+    /// Call `f` with each record, oldest first, see [`for_each`]
+    pub fn for_each(&self, f: impl FnMut(Result<Event, [u8; RECORD_SZ]>)) -> Result<(), F::Error> {
+        for_each(&self.pages, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    struct RamFlash(Vec<u8>);
+
+    impl OptionsFlash for RamFlash {
+        type Error = ();
+
+        fn page(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), ()> {
+            for (t, d) in self.0[offset..offset + data.len()].iter_mut().zip(data) {
+                assert_eq!(*t & d, *d, "programming must only clear bits");
+                *t = *d;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self) -> Result<(), ()> {
+            self.0.fill(0xff);
+            Ok(())
+        }
+    }
+
+    fn events<const N: usize>(log: &EventLog<RamFlash, N>) -> Vec<Result<Event, [u8; 8]>> {
+        let mut events = Vec::new();
+        log.for_each(|e| events.push(e)).unwrap();
+        events
+    }
+
+    #[test]
+    fn records() {
+        for event in [
+            Event::Boot { fwsize: 0x1234 },
+            Event::UpdateInvalid {
+                address: 0xc000,
+                fault: UpdateFault::Checksum,
+            },
+            Event::Installed { address: 0xc000 },
+            Event::InstallFailed { address: 0xc000 },
+            Event::Deferred {
+                address: 0xc000,
+                reason: 7,
+            },
+            Event::Rejected {
+                address: 0xc000,
+                reason: 0xffff,
+            },
+            Event::Factory { fwsize: 0 },
+            Event::Abort(NanoReason::HalError(0xffff)),
+            Event::Abort(NanoReason::FwSizeInvalid),
+            Event::Abort(NanoReason::FwCrcMismatch),
+        ] {
+            let record = event.encode();
+            assert_ne!(record, [0xff; 8]);
+            assert_eq!(Event::decode(record), Some(event));
+        }
+
+        let mut torn = Event::Installed { address: 0xc000 }.encode();
+        torn[5] |= 0x0f;
+        assert_eq!(Event::decode(torn), None);
+        assert_eq!(Event::decode([0; 8]), None);
+    }
+
+    #[test]
+    fn ring() {
+        // Three pages of three records each
+        let pages = || [(); 3].map(|_| RamFlash(std::vec![0xff; 32]));
+        let mut log = EventLog::new(pages()).unwrap();
+        assert!(events(&log).is_empty());
+
+        for fwsize in 0..5 {
+            log.append(Event::Boot { fwsize }).unwrap();
+        }
+        let boots = |range: core::ops::Range<u32>| {
+            range
+                .map(|fwsize| Ok(Event::Boot { fwsize }))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(events(&log), boots(0..5));
+
+        // Picks up where it left off, and overwrites the oldest page once all are used
+        let mut log = EventLog::new(log.into_pages()).unwrap();
+        for fwsize in 5..10 {
+            log.append(Event::Boot { fwsize }).unwrap();
+        }
+        assert_eq!(events(&log), boots(3..10));
+
+        // A torn record is reported raw, and skipped when appending
+        let mut pages = log.into_pages();
+        pages[0].0[16] = 0x01;
+        let mut log = EventLog::new(pages).unwrap();
+        log.append(Event::Boot { fwsize: 10 }).unwrap();
+        let recorded = events(&log);
+        assert_eq!(recorded.len(), 9);
+        assert!(recorded[7].is_err());
+        assert_eq!(recorded[8], Ok(Event::Boot { fwsize: 10 }));
+    }
+}
@@ -0,0 +1,128 @@
+//! Installers for custom update types
+//!
+//! Updates of a type that nanoloader does not know are handed to the [`Installer`] added with
+//! [`Nano::with_installer`]. Several installers are registered together as a tuple, which hands
+//! each update to the first one that handles its type.
+//!
+//! [`Nano::with_installer`]: crate::Nano::with_installer
+
+use crate::check::Update;
+use crate::{FlashProgrammer, NanoResult};
+
+/// Installation of updates of one or more custom types
+///
+/// The built-in types (`UpdateInfo::TYPE_*`) always take precedence, so custom types should be
+/// picked from the upper half of the range, e.g. `0x8000_0000` and up.
+pub trait Installer {
+    /// Whether this installer handles updates of type `uptype`
+    fn handles(&self, uptype: u32) -> bool;
+
+    /// Install `update`, whose checksum and size have been verified already
+    ///
+    /// The payload lies within the firmware area. The firmware area is programmed through
+    /// `programmer`, which reports progress per page like for built-in types; anything else (a
+    /// configuration page, a coprocessor) is up to the installer. The update is cleared
+    /// afterwards only if this succeeds and the firmware is valid; otherwise it stays pending
+    /// and is installed again on the next boot.
+    fn install(&mut self, update: &Update, programmer: &mut impl FlashProgrammer) -> NanoResult;
+}
+
+/// No custom update types
+impl Installer for () {
+    fn handles(&self, _uptype: u32) -> bool {
+        false
+    }
+
+    fn install(&mut self, _update: &Update, _programmer: &mut impl FlashProgrammer) -> NanoResult {
+        crate::OK
+    }
+}
+
+/// Both `A` and `B`, with `A` taking precedence
+impl<A: Installer, B: Installer> Installer for (A, B) {
+    fn handles(&self, uptype: u32) -> bool {
+        self.0.handles(uptype) || self.1.handles(uptype)
+    }
+
+    fn install(&mut self, update: &Update, programmer: &mut impl FlashProgrammer) -> NanoResult {
+        if self.0.handles(update.info.uptype) {
+            self.0.install(update, programmer)
+        } else {
+            self.1.install(update, programmer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NanoReason, UpdateInfo};
+
+    struct NullProgrammer;
+
+    impl FlashProgrammer for NullProgrammer {
+        fn program_start(&mut self) -> NanoResult {
+            crate::OK
+        }
+
+        fn program_write(&mut self, _value: u8) -> NanoResult {
+            crate::OK
+        }
+
+        fn program_read(&mut self, _offset: usize) -> NanoResult<u8> {
+            Ok(0)
+        }
+
+        fn program_finish(&mut self) -> NanoResult {
+            crate::OK
+        }
+    }
+
+    /// Fails with its own type as error code
+    struct Failing(u32);
+
+    impl Installer for Failing {
+        fn handles(&self, uptype: u32) -> bool {
+            uptype == self.0
+        }
+
+        fn install(
+            &mut self,
+            _update: &Update,
+            _programmer: &mut impl FlashProgrammer,
+        ) -> NanoResult {
+            Err(NanoReason::HalError(self.0 as u16))
+        }
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = (Failing(0x8001), (Failing(0x8002), ()));
+        assert!(registry.handles(0x8001));
+        assert!(registry.handles(0x8002));
+        assert!(!registry.handles(0x8003));
+
+        let update = |uptype| Update {
+            info: UpdateInfo {
+                checksum: 0,
+                upsize: 0,
+                uptype,
+                fwsize: 0,
+            },
+            address: 0,
+            data: &[],
+        };
+        let code = |result: NanoResult| match result {
+            Err(NanoReason::HalError(code)) => code,
+            _ => 0,
+        };
+        assert_eq!(
+            code(registry.install(&update(0x8002), &mut NullProgrammer)),
+            0x8002
+        );
+        assert_eq!(
+            code(registry.install(&update(0x8001), &mut NullProgrammer)),
+            0x8001
+        );
+    }
+}
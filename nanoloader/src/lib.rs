@@ -1,8 +1,36 @@
 #![no_std]
 
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+#[macro_use]
+mod macros;
+
+pub mod check;
+#[cfg(feature = "events")]
+pub mod events;
+pub mod install;
 pub mod lz4;
+pub mod measure;
+pub mod options;
+#[cfg(feature = "policy")]
+pub mod policy;
+#[cfg(feature = "progress")]
+pub mod progress;
+#[cfg(feature = "recovery")]
+pub mod recovery;
+#[cfg(feature = "secure")]
+pub mod secure;
+pub mod vectors;
 
-#[derive(Debug)]
+use check::{Update, UpdateFault};
+#[cfg(feature = "events")]
+use events::Event;
+use install::Installer;
+use measure::Measure;
+pub use vectors::Vectors;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NanoReason {
     HalError(u16),
     FwSizeInvalid,
@@ -16,188 +44,1109 @@ pub trait Ignore {
 }
 impl<T> Ignore for NanoResult<T> {}
 
-pub trait NanoHal {
+/// Layout of the firmware area
+pub trait Layout {
     const FW_START: usize;
     const FW_END: usize;
     const FW_SIZE_OFF: usize;
     const FW_PAGE_SZ: usize;
+    /// How the firmware's vector table is made active, `SCB.VTOR` by default
+    const VECTORS: Vectors = Vectors::Vtor;
+    /// Read-only region after the firmware area holding a factory image, laid out like an update
+    #[cfg(feature = "factory")]
+    const FACTORY: Option<core::ops::Range<usize>> = None;
+
+    /// Contents of the firmware area, memory-mapped at `FW_START` by default
+    fn fwarea() -> &'static [u8] {
+        // SAFETY: It is assumed that the const parameters are valid.
+        unsafe {
+            core::slice::from_raw_parts(Self::FW_START as *const u8, Self::FW_END - Self::FW_START)
+        }
+    }
 
-    fn abort(reason: NanoReason) -> !;
+    /// Contents of the factory image region, memory-mapped at `FACTORY` by default
+    #[cfg(feature = "factory")]
+    fn factory() -> Option<&'static [u8]> {
+        // SAFETY: It is assumed that the const parameters are valid.
+        Self::FACTORY.map(|region| unsafe {
+            core::slice::from_raw_parts(region.start as *const u8, region.len())
+        })
+    }
+}
 
-    fn checksum(data: &[u8]) -> u32;
+/// Checksum over firmware images and updates
+pub trait Digest {
+    fn checksum(&self, data: &[u8]) -> u32;
+}
 
-    fn update_address() -> Option<usize>;
-    fn update_clear();
+/// Storage of the pointers to pending updates, queued in order
+pub trait UpdateStore {
+    /// Address of the first pending update
+    fn update_address(&mut self) -> Option<usize>;
+    /// Address of the pending update at `position` in the queue, the first one being at 0
+    fn update_queued(&mut self, position: usize) -> Option<usize>;
+    /// Mark the first pending update as done
+    fn update_clear(&mut self);
+}
 
+/// Programming of the firmware area, sequentially from `FW_START`
+pub trait FlashProgrammer {
     fn program_start(&mut self) -> NanoResult;
     fn program_write(&mut self, value: u8) -> NanoResult;
     fn program_read(&mut self, offset: usize) -> NanoResult<u8>;
     fn program_finish(&mut self) -> NanoResult;
 }
 
-pub fn boot<HAL: NanoHal>(mut hal: HAL) -> ! {
-    // Process any pending update
-    process_update::<HAL>(&mut hal);
+impl<P: FlashProgrammer + ?Sized> FlashProgrammer for &mut P {
+    fn program_start(&mut self) -> NanoResult {
+        (**self).program_start()
+    }
 
-    // Verify firmware is valid
-    check_firmware::<HAL>().unwrap_or_else(|e| HAL::abort(e));
+    fn program_write(&mut self, value: u8) -> NanoResult {
+        (**self).program_write(value)
+    }
 
-    // SAFETY: Since firmware is valid, we can assume that it is safe to boot into it
-    unsafe {
-        // Set VTOR to start of firmware (always safe on Cortex-M)
-        (*cortex_m::peripheral::SCB::PTR)
-            .vtor
-            .write(HAL::FW_START as u32);
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        (**self).program_read(offset)
+    }
 
-        // 3 .. 2 .. 1 .. lift-off!
-        cortex_m::asm::bootload(HAL::FW_START as *const u32);
+    fn program_finish(&mut self) -> NanoResult {
+        (**self).program_finish()
     }
 }
 
-#[inline]
-#[must_use]
-fn ensure(b: bool) -> Option<()> {
-    b.then_some(())
+/// Interaction with the outside world: reporting fatal errors, progress and events, recovery
+/// mode and update policy
+pub trait Reporter {
+    fn abort(&mut self, reason: NanoReason) -> !;
+
+    #[cfg(feature = "recovery")]
+    /// Whether to stay in the bootloader, even if the firmware is valid
+    fn recovery_requested(&mut self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "recovery")]
+    /// Wait in recovery mode, returning `false` to leave it
+    ///
+    /// This is called repeatedly until it returns `false` or a valid update is found.
+    fn recovery_wait(&mut self) -> bool {
+        false
+    }
+
+    /// Whether to install the update at `address` now, later or never
+    ///
+    /// This is called with the verified update header, before anything is erased.
+    #[cfg(feature = "policy")]
+    fn update_policy(&mut self, _address: usize, _info: &UpdateInfo) -> policy::Decision {
+        policy::Decision::Install
+    }
+
+    /// Record the update policy decision for the application, see [`policy::record`]
+    #[cfg(feature = "policy")]
+    fn record_decision(&mut self, _decision: policy::Decision) {}
+
+    /// Report installation progress, `done` out of `total` bytes of the current phase
+    #[cfg(feature = "progress")]
+    fn progress(&mut self, _phase: progress::Phase, _done: usize, _total: usize) {}
+
+    /// Record what the bootloader did, e.g. in an [`events::EventLog`]
+    #[cfg(feature = "events")]
+    fn event(&mut self, _event: events::Event) {}
+
+    /// Whether to reinstall the factory image, even if the firmware is valid
+    #[cfg(feature = "factory")]
+    fn factory_reset_requested(&mut self) -> bool {
+        false
+    }
 }
 
-fn read_checked<T: Copy>(slice: &[u8], offset: usize) -> Option<T> {
-    slice
-        .get(offset..offset.checked_add(size_of::<T>())?)
-        .map(|s| s.as_ptr() as *const T)
-        .and_then(|ptr| ptr.is_aligned().then_some(ptr))
-        // SAFETY: Pointer range and alignment just verified
-        .map(|ptr| unsafe { core::ptr::read(ptr) })
+/// Bootloader composed of its parts, for the firmware area described by `L`
+///
+/// The firmware is not measured unless a [`Measure`] hook is added with [`Nano::with_measure`],
+/// and custom update types are only installed once an [`Installer`] is added with
+/// [`Nano::with_installer`].
+pub struct Nano<L, D, S, P, R, M = (), I = ()> {
+    pub digest: D,
+    pub store: S,
+    pub programmer: P,
+    pub reporter: R,
+    pub measure: M,
+    pub installer: I,
+    _layout: core::marker::PhantomData<L>,
+}
+
+impl<L, D, S, P, R> Nano<L, D, S, P, R>
+where
+    L: Layout,
+    D: Digest,
+    S: UpdateStore,
+    P: FlashProgrammer,
+    R: Reporter,
+{
+    pub fn new(digest: D, store: S, programmer: P, reporter: R) -> Self {
+        const { assert_layout::<L>() }
+
+        Nano {
+            digest,
+            store,
+            programmer,
+            reporter,
+            measure: (),
+            installer: (),
+            _layout: core::marker::PhantomData,
+        }
+    }
 }
 
-fn get_fwarea<HAL: NanoHal>() -> &'static [u8] {
-    // SAFETY: It is assumed that the HAL's const parameters are valid.
-    unsafe { core::slice::from_raw_parts(HAL::FW_START as *const u8, HAL::FW_END - HAL::FW_START) }
+impl<L, D, S, P, R, M, I> Nano<L, D, S, P, R, M, I> {
+    /// Measure the firmware with `measure` before booting it
+    pub fn with_measure<N: Measure>(self, measure: N) -> Nano<L, D, S, P, R, N, I> {
+        Nano {
+            digest: self.digest,
+            store: self.store,
+            programmer: self.programmer,
+            reporter: self.reporter,
+            measure,
+            installer: self.installer,
+            _layout: core::marker::PhantomData,
+        }
+    }
+
+    /// Install updates of custom types with `installer`
+    pub fn with_installer<J>(self, installer: J) -> Nano<L, D, S, P, R, M, J> {
+        Nano {
+            digest: self.digest,
+            store: self.store,
+            programmer: self.programmer,
+            reporter: self.reporter,
+            measure: self.measure,
+            installer,
+            _layout: core::marker::PhantomData,
+        }
+    }
 }
 
-fn check_firmware<HAL: NanoHal>() -> NanoResult {
-    let fwarea = get_fwarea::<HAL>();
+impl<L, D, S, P, R, M, I> Nano<L, D, S, P, R, M, I>
+where
+    L: Layout,
+    D: Digest,
+    S: UpdateStore,
+    P: FlashProgrammer,
+    R: Reporter,
+    M: Measure,
+    I: Installer,
+{
+    pub fn boot(mut self) -> ! {
+        self.prepare();
 
-    // Read firmware size
-    let fwsize =
-        read_checked::<usize>(fwarea, HAL::FW_SIZE_OFF).ok_or(NanoReason::FwSizeInvalid)?;
+        // SAFETY: Since firmware is valid, we can assume that it is safe to boot into it
+        unsafe {
+            vectors::install(L::VECTORS, L::FW_START);
 
-    // Split firmware area from rest
-    let (firmware, rest) = fwarea
-        .split_at_checked(fwsize)
-        .ok_or(NanoReason::FwSizeInvalid)?;
+            // 3 .. 2 .. 1 .. lift-off!
+            cortex_m::asm::bootload(L::FW_START as *const u32);
+        }
+    }
 
-    // Read expected firmware CRC
-    let fwcrc_exp = read_checked::<u32>(rest, 0).ok_or(NanoReason::FwSizeInvalid)?;
+    /// Boot the firmware as the non-secure application, after configuring `attribution`
+    #[cfg(feature = "secure")]
+    pub fn boot_non_secure(mut self, attribution: &mut impl secure::Attribution) -> ! {
+        self.prepare();
 
-    // Calculate firmware CRC
-    let fwcrc_act = HAL::checksum(firmware);
+        attribution.configure().unwrap_or_else(|e| self.abort(e));
 
-    // Log information
-    if fwcrc_act == fwcrc_exp {
-        log::info!("Firmware CRC verified: 0x{:08x}", fwcrc_act);
-    } else {
-        log::warn!(
-            "Firmware CRC verification failed: exp=0x{:08x}, act=0x{:08x}",
-            fwcrc_exp,
-            fwcrc_act
+        // SAFETY: The firmware is valid, and its memory was made non-secure
+        unsafe { secure::bootload_ns(L::FW_START) }
+    }
+
+    /// Install any pending update, then make sure the firmware is valid
+    fn prepare(&mut self) {
+        // Process any pending updates
+        self.process_updates();
+
+        // Stay in the bootloader if requested
+        #[cfg(feature = "recovery")]
+        if self.reporter.recovery_requested() {
+            self.recover();
+        }
+
+        // Verify firmware is valid, falling back to the factory image
+        #[cfg_attr(not(feature = "factory"), allow(unused_mut))]
+        let mut firmware = self.check_firmware();
+        #[cfg(feature = "factory")]
+        if self.reporter.factory_reset_requested() || firmware.is_err() {
+            self.install_factory();
+            firmware = self.check_firmware();
+        }
+
+        // Measure the firmware
+        let image = firmware.unwrap_or_else(|e| self.abort(e));
+        self.measure
+            .measure(L::FW_START, image)
+            .unwrap_or_else(|e| self.abort(e));
+
+        #[cfg(feature = "events")]
+        self.reporter.event(Event::Boot {
+            fwsize: image.len() as u32,
+        });
+    }
+
+    /// Record the reason, and give up
+    fn abort(&mut self, reason: NanoReason) -> ! {
+        #[cfg(feature = "events")]
+        self.reporter.event(Event::Abort(reason));
+        self.reporter.abort(reason)
+    }
+
+    /// Wait for an update to be staged, or for the reporter to end recovery mode
+    #[cfg(feature = "recovery")]
+    fn recover(&mut self) {
+        info!("Recovery mode requested");
+
+        while self.reporter.recovery_wait() {
+            if self.check_update().is_some() {
+                self.process_updates();
+                break;
+            }
+        }
+    }
+
+    /// Returns the firmware image if it is valid
+    fn check_firmware(&self) -> NanoResult<&'static [u8]> {
+        let firmware = check::firmware(L::fwarea(), L::FW_SIZE_OFF, |d| self.digest.checksum(d))?;
+
+        // Log information
+        if firmware.is_valid() {
+            info!("Firmware CRC verified: 0x{:08x}", firmware.crc_actual);
+        } else {
+            warn!(
+                "Firmware CRC verification failed: exp=0x{:08x}, act=0x{:08x}",
+                firmware.crc_expected, firmware.crc_actual
+            );
+        }
+
+        // Check firmware CRC
+        ensure(firmware.is_valid()).ok_or(NanoReason::FwCrcMismatch)?;
+
+        Ok(firmware.data)
+    }
+
+    /// Process queued updates in order, until one is left pending or none are left
+    ///
+    /// Each update is cleared on its own, so after a reset, processing resumes with the first
+    /// update that was not done. Invalid and rejected updates are cleared without installing
+    /// them; deferred updates and failed installs stay pending, and stop the queue.
+    fn process_updates(&mut self) {
+        while let Some(cleared) = self.process_update() {
+            // A store that fails to clear would have the same update installed over and over
+            if self.store.update_address() == Some(cleared) {
+                break;
+            }
+        }
+    }
+
+    /// Process the first pending update, returning its address if it was cleared
+    fn process_update(&mut self) -> Option<usize> {
+        let Some(address) = self.store.update_address() else {
+            info!("No pending update found");
+            return None;
+        };
+        let update = match self.verify_update(address) {
+            Ok(update) => update,
+            #[cfg_attr(not(feature = "events"), allow(unused_variables))]
+            Err(fault) => {
+                // It would never become valid, and would hold up the updates queued after it
+                warn!("Pending update is invalid");
+                #[cfg(feature = "events")]
+                self.reporter.event(Event::UpdateInvalid { address, fault });
+                self.store.update_clear();
+                return Some(address);
+            }
+        };
+
+        #[cfg(feature = "policy")]
+        {
+            let decision = self.reporter.update_policy(address, &update.info);
+            self.reporter.record_decision(decision);
+            match decision {
+                policy::Decision::Install => {}
+                policy::Decision::Defer(reason) => {
+                    info!("Update deferred by policy ({})", reason);
+                    #[cfg(feature = "events")]
+                    self.reporter.event(Event::Deferred { address, reason });
+                    return None;
+                }
+                policy::Decision::Reject(reason) => {
+                    warn!("Update rejected by policy ({})", reason);
+                    #[cfg(feature = "events")]
+                    self.reporter.event(Event::Rejected { address, reason });
+                    self.store.update_clear();
+                    return Some(address);
+                }
+            }
+        }
+
+        #[cfg(feature = "progress")]
+        let fwsize = update.info.fwsize as usize;
+        let installed = self.install(update).is_some();
+
+        // If a transient error occured during programming, the update might be recoverable
+        // even if the firmware is now in an inconsistent state. Unconditionally clearing the
+        // update pointer here would risk bricking a device that can still be saved. It is
+        // safer to only clear the update once it is installed and there is a valid firmware in
+        // Flash; a failed install is retried on the next boot.
+
+        #[cfg(feature = "progress")]
+        self.reporter
+            .progress(progress::Phase::VerifyAfter, 0, fwsize);
+        let valid = self.check_firmware().is_ok();
+        #[cfg(feature = "progress")]
+        self.reporter
+            .progress(progress::Phase::VerifyAfter, fwsize, fwsize);
+
+        #[cfg(feature = "events")]
+        self.reporter.event(match installed && valid {
+            true => Event::Installed { address },
+            false => Event::InstallFailed { address },
+        });
+
+        if !(installed && valid) {
+            return None;
+        }
+        self.store.update_clear();
+        Some(address)
+    }
+
+    /// Check if there is a valid update available
+    #[cfg(any(feature = "recovery", feature = "fuzzing"))]
+    fn check_update(&mut self) -> Option<Update<'static>> {
+        // Ask the store if a potential update exists
+        let upinfo_addr = self.store.update_address()?;
+        self.verify_update(upinfo_addr).ok()
+    }
+
+    /// Check the update at `address`
+    fn verify_update(&mut self, address: usize) -> Result<Update<'static>, UpdateFault> {
+        check::update(L::fwarea(), L::FW_START, address, |d| {
+            #[cfg(feature = "progress")]
+            self.reporter.progress(progress::Phase::Verify, 0, d.len());
+            let checksum = self.digest.checksum(d);
+            #[cfg(feature = "progress")]
+            self.reporter
+                .progress(progress::Phase::Verify, d.len(), d.len());
+            checksum
+        })
+    }
+
+    /// Reinstall the factory image, if there is a valid one
+    #[cfg(feature = "factory")]
+    fn install_factory(&mut self) -> Option<()> {
+        let region = L::factory()?;
+        let start = region.as_ptr() as usize;
+        let image = check::update(region, start, start, |d| self.digest.checksum(d)).ok()?;
+        ensure(image.info.fwsize as usize <= L::FW_END - L::FW_START)?;
+
+        // Updates left pending after a failed install are retried on the next boot, so none of
+        // them may be overwritten
+        let end = L::FW_START + (image.info.fwsize as usize).next_multiple_of(L::FW_PAGE_SZ);
+        if (0..)
+            .map_while(|position| self.store.update_queued(position))
+            .any(|address| address < end)
+        {
+            warn!("Factory image would overwrite a pending update");
+            return None;
+        }
+
+        info!("Installing factory image");
+        self.install(image)?;
+
+        #[cfg(feature = "events")]
+        self.reporter.event(Event::Factory {
+            fwsize: image.info.fwsize,
+        });
+        Some(())
+    }
+
+    /// Install an update or the factory image
+    fn install(&mut self, update: Update) -> Option<()> {
+        // The new firmware is programmed through this, reporting progress per page
+        #[cfg(feature = "progress")]
+        let mut programmer = progress::Tracked::new(
+            &mut self.programmer,
+            &mut self.reporter,
+            L::FW_PAGE_SZ,
+            update.info.fwsize as usize,
         );
+        #[cfg(not(feature = "progress"))]
+        let mut programmer = &mut self.programmer;
+
+        match update.info.uptype {
+            #[cfg(feature = "plain")]
+            UpdateInfo::TYPE_PLAIN => Self::install_plain(programmer, update),
+            #[cfg(feature = "lz4")]
+            UpdateInfo::TYPE_LZ4 => Self::install_lz4(programmer, update),
+            uptype if self.installer.handles(uptype) => {
+                info!("Installing update of type 0x{:08x}", uptype);
+                self.installer.install(&update, &mut programmer).ok()
+            }
+            _ => {
+                // unknown or unsupported update type
+                None
+            }
+        }
+    }
+
+    /// Install a plain update
+    #[cfg(feature = "plain")]
+    fn install_plain(mut programmer: impl FlashProgrammer, update: Update) -> Option<()> {
+        // Check update size
+        check::plain(&update, L::FW_START, L::FW_PAGE_SZ).ok()?;
+
+        // Copy new firmware into place
+        programmer.program_start().ok()?;
+        for b in update.data {
+            programmer.program_write(*b).ok()?;
+        }
+        programmer.program_finish().ok()?;
+
+        Some(())
     }
 
-    // Check firmware CRC
-    ensure(fwcrc_act == fwcrc_exp).ok_or(NanoReason::FwCrcMismatch)?;
+    /// Install an LZ4-compressed update
+    #[cfg(feature = "lz4")]
+    fn install_lz4(mut programmer: impl FlashProgrammer, update: Update) -> Option<()> {
+        // Check frame and update size
+        check::lz4(&update, L::FW_START, L::FW_PAGE_SZ).ok()?;
+
+        // Decompress new firmware into place, reading back from Flash for back-references. Without
+        // a content size in the frame, only the limit keeps the output from running into the
+        // update.
+        programmer.program_start().ok()?;
+        let mut sink =
+            lz4::sink::FlashSink::new(&mut programmer, &[]).with_limit(update.info.fwsize as usize);
+        lz4::frame::decompress(update.data, &mut sink).ok()?;
+        let complete = sink.len() == update.info.fwsize as usize;
+        programmer.program_finish().ok()?;
+
+        ensure(complete)
+    }
+}
+
+/// Fail the build if the layout constants are invalid
+const fn assert_layout<L: Layout>() {
+    if let Err(fault) = check::layout(L::FW_START, L::FW_END, L::FW_SIZE_OFF, L::FW_PAGE_SZ) {
+        panic!("{}", fault.message());
+    }
+    #[cfg(all(target_arch = "arm", not(armv6m)))]
+    if L::FW_SIZE_OFF == 0x30 {
+        panic!("size offset 0x30 is the DebugMonitor vector on ARMv7-M and later");
+    }
+    #[cfg(feature = "factory")]
+    if let Some(region) = L::FACTORY
+        && (region.start < L::FW_END || region.start >= region.end)
+    {
+        panic!("factory image region is not after the firmware area");
+    }
+}
+
+/// Export the firmware area of a [`Layout`] as the linker symbols `__nanoloader_fw_start` and
+/// `__nanoloader_fw_end`
+///
+/// `memory.x` can then check its memory regions against them, e.g.
+/// `ASSERT(ORIGIN(FW_CODE) == __nanoloader_fw_start, "FW_CODE does not match FW_START")`.
+#[macro_export]
+macro_rules! export_layout {
+    ($layout:ty) => {
+        core::arch::global_asm!(
+            ".global __nanoloader_fw_start",
+            ".set __nanoloader_fw_start, {fw_start}",
+            ".global __nanoloader_fw_end",
+            ".set __nanoloader_fw_end, {fw_end}",
+            fw_start = const <$layout as $crate::Layout>::FW_START,
+            fw_end = const <$layout as $crate::Layout>::FW_END,
+        );
+    };
+}
+
+#[inline]
+#[must_use]
+fn ensure(b: bool) -> Option<()> {
+    b.then_some(())
+}
 
-    OK
+fn read_checked<T: Copy>(slice: &[u8], offset: usize) -> Option<T> {
+    slice
+        .get(offset..offset.checked_add(size_of::<T>())?)
+        .map(|s| s.as_ptr() as *const T)
+        .and_then(|ptr| ptr.is_aligned().then_some(ptr))
+        // SAFETY: Pointer range and alignment just verified
+        .map(|ptr| unsafe { core::ptr::read(ptr) })
 }
 
+/// Header at the start of each update
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
-struct UpdateInfo {
+pub struct UpdateInfo {
     /// Update checksum (includes everything except this field)
-    checksum: u32,
+    pub checksum: u32,
     /// Update size (in bytes, including this header)
-    upsize: u32,
+    pub upsize: u32,
     /// Update type
-    uptype: u32,
+    pub uptype: u32,
     /// Firmware size (once unpacked)
-    fwsize: u32,
+    pub fwsize: u32,
 }
 
 impl UpdateInfo {
-    const TYPE_PLAIN: u32 = 0;
+    pub const TYPE_PLAIN: u32 = 0;
+    /// Payload is an LZ4 frame without dictionary, holding the firmware image
+    pub const TYPE_LZ4: u32 = 1;
+
+    /// Serialized header, as stored in Flash (little-endian)
+    pub fn to_bytes(&self) -> [u8; size_of::<UpdateInfo>()] {
+        let mut bytes = [0; size_of::<UpdateInfo>()];
+        for (chunk, v) in
+            bytes
+                .chunks_exact_mut(4)
+                .zip([self.checksum, self.upsize, self.uptype, self.fwsize])
+        {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
 }
 
-struct Update {
-    info: UpdateInfo,
-    address: usize,
-    data: &'static [u8],
+/// Entry points for fuzzing, not part of the public API
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    use super::*;
+
+    impl<L, D, S, P, R, M, I> Nano<L, D, S, P, R, M, I>
+    where
+        L: Layout,
+        D: Digest,
+        S: UpdateStore,
+        P: FlashProgrammer,
+        R: Reporter,
+        M: Measure,
+        I: Installer,
+    {
+        pub fn fuzz_check_firmware(&self) -> NanoResult {
+            self.check_firmware().map(|_| ())
+        }
+
+        /// Returns the address and size of the pending update, if it is valid
+        pub fn fuzz_check_update(&mut self) -> Option<(usize, usize)> {
+            self.check_update()
+                .map(|u| (u.address, u.info.upsize as usize))
+        }
+
+        pub fn fuzz_process_update(&mut self) {
+            self.process_updates()
+        }
+    }
 }
 
-fn process_update<HAL: NanoHal>(hal: &mut HAL) {
-    if let Some(update) = check_update::<HAL>() {
-        match update.info.uptype {
-            UpdateInfo::TYPE_PLAIN => {
-                install_plain::<HAL>(hal, update);
+#[cfg(all(test, feature = "plain", feature = "events"))]
+mod tests {
+    use super::*;
+
+    use core::cell::UnsafeCell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const FW_START: usize = 0x4000;
+    const FW_END: usize = 0x6000;
+    const PAGE_SZ: usize = 256;
+    /// End of the factory image region, which follows the firmware area
+    const FACTORY_END: usize = 0x7000;
+
+    /// Simulated Flash from `FW_START` to `FACTORY_END`
+    #[repr(C, align(8))]
+    struct Flash(UnsafeCell<[u8; FACTORY_END - FW_START]>);
+
+    std::thread_local! {
+        // Each test runs in a thread of its own, with a Flash of its own
+        static FLASH: &'static Flash =
+            Box::leak(Box::new(Flash(UnsafeCell::new([0xff; FACTORY_END - FW_START]))));
+    }
+
+    fn flash() -> *mut u8 {
+        FLASH.with(|flash| flash.0.get().cast())
+    }
+
+    /// Program `data` at `address`, bypassing the bootloader
+    fn write(address: usize, data: &[u8]) {
+        assert!(address >= FW_START && address + data.len() <= FACTORY_END);
+        // SAFETY: In bounds, and no references into the Flash are used across writes
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                flash().add(address - FW_START),
+                data.len(),
+            )
+        }
+    }
+
+    struct SimLayout;
+
+    impl Layout for SimLayout {
+        const FW_START: usize = FW_START;
+        const FW_END: usize = FW_END;
+        const FW_SIZE_OFF: usize = 0x30;
+        const FW_PAGE_SZ: usize = PAGE_SZ;
+        #[cfg(feature = "factory")]
+        const FACTORY: Option<core::ops::Range<usize>> = Some(FW_END..FACTORY_END);
+
+        fn fwarea() -> &'static [u8] {
+            // SAFETY: The Flash is leaked, and only written through `write`
+            unsafe { core::slice::from_raw_parts(flash(), FW_END - FW_START) }
+        }
+
+        #[cfg(feature = "factory")]
+        fn factory() -> Option<&'static [u8]> {
+            // SAFETY: As above
+            Some(unsafe {
+                core::slice::from_raw_parts(flash().add(FW_END - FW_START), FACTORY_END - FW_END)
+            })
+        }
+    }
+
+    /// FNV-1a, which tells the test images apart just as well as a CRC
+    struct Fnv;
+
+    impl Digest for Fnv {
+        fn checksum(&self, data: &[u8]) -> u32 {
+            data.iter().fold(0x811c_9dc5, |h, b| {
+                (h ^ *b as u32).wrapping_mul(0x0100_0193)
+            })
+        }
+    }
+
+    /// Queue of update pointers, which can be made to fail to clear
+    #[derive(Default)]
+    struct SimStore {
+        queue: Vec<usize>,
+        stuck: bool,
+    }
+
+    impl UpdateStore for SimStore {
+        fn update_address(&mut self) -> Option<usize> {
+            self.queue.first().copied()
+        }
+
+        fn update_queued(&mut self, position: usize) -> Option<usize> {
+            self.queue.get(position).copied()
+        }
+
+        fn update_clear(&mut self) {
+            if !self.stuck {
+                self.queue.remove(0);
             }
-            _ => {
-                // unknown or unsupported update type
+        }
+    }
+
+    /// Programs whole pages, failing once if set to `fail`, a number of installs to complete
+    /// first and the number of bytes to write
+    #[derive(Default)]
+    struct SimProgrammer {
+        programmed: Vec<u8>,
+        fail: Option<(usize, usize)>,
+    }
+
+    impl SimProgrammer {
+        /// Program the buffered data, leaving the rest of the last page erased
+        fn commit(&mut self) {
+            let len = self.programmed.len().next_multiple_of(PAGE_SZ);
+            self.programmed.resize(len, 0xff);
+            write(FW_START, &self.programmed);
+        }
+    }
+
+    impl FlashProgrammer for SimProgrammer {
+        fn program_start(&mut self) -> NanoResult {
+            self.programmed.clear();
+            OK
+        }
+
+        fn program_write(&mut self, value: u8) -> NanoResult {
+            if self.fail == Some((0, self.programmed.len())) {
+                // Pages programmed before the failure stay programmed
+                self.fail = None;
+                self.commit();
+                return Err(NanoReason::HalError(1));
             }
+            assert!(FW_START + self.programmed.len() < FW_END);
+            self.programmed.push(value);
+            OK
         }
 
-        // If a transient error occured during programming, the update might be recoverable even if
-        // the firmware is now in an inconsistent state. Unconditionally clearing the update
-        // pointer here would risk bricking a device that can still be saved. It is safer to only
-        // clear the update if there is a valid firmware in Flash.
+        fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+            self.programmed
+                .get(offset)
+                .copied()
+                .ok_or(NanoReason::HalError(0))
+        }
 
-        if check_firmware::<HAL>().is_ok() {
-            HAL::update_clear();
+        fn program_finish(&mut self) -> NanoResult {
+            self.commit();
+            if let Some((installs, _)) = &mut self.fail {
+                *installs -= 1;
+            }
+            OK
         }
-    } else {
-        log::info!("No pending update found");
     }
-}
 
-/// Check if there is a valid update available
-fn check_update<HAL: NanoHal>() -> Option<Update> {
-    // Ask HAL if a potential update exists
-    let upinfo_addr = HAL::update_address()?;
+    #[derive(Default)]
+    struct SimReporter {
+        events: Vec<Event>,
+        factory_reset: bool,
+    }
 
-    // Calculate offset of update into firmware area
-    let upinfo_off = upinfo_addr.checked_sub(HAL::FW_START)?;
+    impl Reporter for SimReporter {
+        fn abort(&mut self, reason: NanoReason) -> ! {
+            panic!("abort: {reason:?}");
+        }
 
-    let fwarea = get_fwarea::<HAL>();
+        fn event(&mut self, event: Event) {
+            self.events.push(event);
+        }
 
-    // Read the update info header
-    let upinfo = read_checked::<UpdateInfo>(fwarea, upinfo_off)?;
+        #[cfg(feature = "factory")]
+        fn factory_reset_requested(&mut self) -> bool {
+            self.factory_reset
+        }
+    }
 
-    // Create slice for entire update
-    let update_end = upinfo_off.checked_add(upinfo.upsize as usize)?;
-    let upslice = fwarea.get(upinfo_off..update_end)?;
+    type SimNano = Nano<SimLayout, Fnv, SimStore, SimProgrammer, SimReporter>;
 
-    let checksum = HAL::checksum(upslice.get(size_of::<u32>()..)?);
+    /// Bootloader with `queue` as the pending updates, after a reset
+    fn nano(queue: &[usize]) -> SimNano {
+        let store = SimStore {
+            queue: queue.to_vec(),
+            stuck: false,
+        };
+        Nano::new(Fnv, store, SimProgrammer::default(), SimReporter::default())
+    }
 
-    ensure(upinfo.checksum == checksum)?;
+    /// Firmware image of `len` bytes of `fill`, with its size and checksum in place
+    fn firmware(len: usize, fill: u8) -> Vec<u8> {
+        let mut image = std::vec![fill; len];
+        image[0x30..0x34].copy_from_slice(&(len as u32).to_le_bytes());
+        let checksum = Fnv.checksum(&image);
+        image.extend(checksum.to_le_bytes());
+        image
+    }
 
-    Some(Update {
-        info: upinfo,
-        address: upinfo_addr,
-        data: upslice.get(size_of::<UpdateInfo>()..)?,
-    })
-}
+    /// Update (or factory image) installing `firmware`, LZ4-compressed if `lz4` is set
+    fn update(firmware: &[u8], lz4: bool) -> Vec<u8> {
+        match lz4 {
+            false => package(UpdateInfo::TYPE_PLAIN, firmware.to_vec(), firmware.len()),
+            true => package(
+                UpdateInfo::TYPE_LZ4,
+                lz4::frame::compress(firmware, lz4::Mode::Fast),
+                firmware.len(),
+            ),
+        }
+    }
+
+    /// Update of type `uptype` with `payload`, claiming to install `fwsize` bytes
+    fn package(uptype: u32, payload: Vec<u8>, fwsize: usize) -> Vec<u8> {
+        let info = UpdateInfo {
+            checksum: 0,
+            upsize: (size_of::<UpdateInfo>() + payload.len()) as u32,
+            uptype,
+            fwsize: fwsize as u32,
+        };
+        let mut update = info.to_bytes().to_vec();
+        update.extend(payload);
+        let checksum = Fnv.checksum(&update[size_of::<u32>()..]);
+        update[..size_of::<u32>()].copy_from_slice(&checksum.to_le_bytes());
+        update
+    }
+
+    /// Contents of the firmware area, as far as `image` goes
+    fn installed(image: &[u8]) -> bool {
+        &SimLayout::fwarea()[..image.len()] == image
+    }
+
+    /// Run the bootloader up to the point of booting, returning whether it aborted
+    fn prepare(nano: &mut SimNano) -> bool {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| nano.prepare())).is_err()
+    }
+
+    #[cfg(feature = "factory")]
+    #[test]
+    fn factory_fallback() {
+        let factory = firmware(0x400, 0xfa);
+        write(FW_END, &update(&factory, false));
+
+        // No valid firmware
+        let mut nano = nano(&[]);
+        assert!(!prepare(&mut nano));
+        assert!(installed(&factory));
+        assert_eq!(
+            nano.reporter.events,
+            [
+                Event::Factory { fwsize: 0x404 },
+                Event::Boot { fwsize: 0x400 }
+            ]
+        );
+
+        // Valid firmware is left alone
+        let current = firmware(0x800, 0x11);
+        write(FW_START, &current);
+        let mut nano = self::nano(&[]);
+        assert!(!prepare(&mut nano));
+        assert!(installed(&current));
+        assert_eq!(nano.reporter.events, [Event::Boot { fwsize: 0x800 }]);
+
+        // Failed install with a second update queued where the factory image would go
+        write(FW_START, &[0xff; FW_END - FW_START]);
+        let queue = stage(&[
+            (0x5400, &firmware(0x800, 0x22)),
+            (0x4200, &firmware(0x100, 0x33)),
+        ]);
+        let mut nano = self::nano(&queue);
+        nano.programmer.fail = Some((0, PAGE_SZ + 1));
+        assert!(prepare(&mut nano));
+        assert_eq!(nano.store.queue, queue);
+        assert!(nano.verify_update(0x4200).is_ok());
+    }
+
+    #[cfg(feature = "factory")]
+    #[test]
+    fn factory_reset() {
+        let factory = firmware(0x400, 0xfa);
+        for lz4 in [false, cfg!(feature = "lz4")] {
+            write(FW_END, &update(&factory, lz4));
+            write(FW_START, &firmware(0x800, 0x11));
+
+            let mut nano = nano(&[]);
+            nano.reporter.factory_reset = true;
+            assert!(!prepare(&mut nano));
+            assert!(installed(&factory));
+            assert_eq!(nano.reporter.events[0], Event::Factory { fwsize: 0x404 });
+        }
+    }
+
+    #[cfg(feature = "factory")]
+    #[test]
+    fn failed_install() {
+        let new = firmware(0x600, 0x22);
+        let staged = update(&new, false);
+
+        for (factory, fallback) in [
+            (firmware(0x400, 0xfa), true),
+            (firmware(0xc00, 0xfb), false),
+        ] {
+            write(FW_START, &[0xff; FACTORY_END - FW_START]);
+            write(FW_END, &update(&factory, false));
+            write(FW_START, &firmware(0x800, 0x11));
+            write(0x4c00, &staged);
+
+            // Installing fails after the first page, leaving no valid firmware
+            let mut nano = nano(&[0x4c00]);
+            nano.programmer.fail = Some((0, PAGE_SZ + 1));
+            let aborted = prepare(&mut nano);
+            assert_eq!(nano.store.queue, [0x4c00]);
+            assert_eq!(
+                nano.reporter.events[0],
+                Event::InstallFailed { address: 0x4c00 }
+            );
+
+            // The factory image is only installed if the update is left intact
+            assert_eq!(aborted, !fallback);
+            assert_eq!(installed(&factory), fallback);
+            assert!(nano.verify_update(0x4c00).is_ok());
+
+            // After a reset, the update is installed
+            let mut nano = self::nano(&[0x4c00]);
+            assert!(!prepare(&mut nano));
+            assert!(installed(&new));
+            assert!(nano.store.queue.is_empty());
+        }
 
-/// Install a plain update
-fn install_plain<HAL: NanoHal>(hal: &mut HAL, update: Update) -> Option<()> {
-    // Check update size
-    const { assert!(HAL::FW_PAGE_SZ.next_power_of_two() == HAL::FW_PAGE_SZ) }
-    ensure(update.info.fwsize as usize == update.data.len())?;
-    let size = pow2::pow2_const!(HAL::FW_PAGE_SZ).align_up(update.info.fwsize)?;
-    ensure(HAL::FW_START.checked_add(size as usize)? <= update.address)?;
+        // A custom installer failing leaves the current firmware valid, but the install failed
+        let current = firmware(0x800, 0x11);
+        write(FW_START, &current);
+        write(0x4c00, &package(0x8000_0000, std::vec![0x5a; 0x40], 0x40));
+        let mut nano = nano(&[0x4c00]).with_installer(Failing);
+        nano.process_updates();
+        assert!(installed(&current));
+        assert_eq!(nano.store.queue, [0x4c00]);
+        assert_eq!(
+            nano.reporter.events,
+            [Event::InstallFailed { address: 0x4c00 }]
+        );
+    }
+
+    /// Custom installer that fails without programming anything
+    #[cfg(feature = "factory")]
+    struct Failing;
+
+    #[cfg(feature = "factory")]
+    impl install::Installer for Failing {
+        fn handles(&self, uptype: u32) -> bool {
+            uptype == 0x8000_0000
+        }
+
+        fn install(
+            &mut self,
+            _update: &Update,
+            _programmer: &mut impl FlashProgrammer,
+        ) -> NanoResult {
+            Err(NanoReason::HalError(2))
+        }
+    }
 
-    // Copy new firmware into place
-    hal.program_start().ok()?;
-    for b in update.data {
-        hal.program_write(*b).ok()?;
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_overflow() {
+        // Frame without content size, holding a single block that expands to 0x1004 bytes
+        let long = firmware(0x1000, 0x22);
+        let block = lz4::compress(&long, &[], lz4::Mode::Fast);
+        let mut frame = std::vec![0x04, 0x22, 0x4d, 0x18, 0x40, 0x40, 0xc0];
+        frame.extend((block.len() as u32).to_le_bytes());
+        frame.extend(block);
+        frame.extend(0u32.to_le_bytes());
+        let staged = package(UpdateInfo::TYPE_LZ4, frame, 0x600);
+
+        // Decompression stops at the firmware size, short of the update right behind it
+        write(FW_START, &firmware(0x400, 0x11));
+        write(0x4600, &staged);
+        let mut nano = nano(&[0x4600]);
+        nano.process_updates();
+        assert_eq!(
+            nano.reporter.events,
+            [Event::InstallFailed { address: 0x4600 }]
+        );
+        assert_eq!(nano.store.queue, [0x4600]);
+        assert!(nano.verify_update(0x4600).is_ok());
+        assert_eq!(&SimLayout::fwarea()[0x600..0x600 + staged.len()], staged);
+    }
+
+    /// Stage the updates to `firmware` at the given addresses, returning the queue
+    fn stage(updates: &[(usize, &[u8])]) -> Vec<usize> {
+        for &(address, firmware) in updates {
+            write(address, &update(firmware, false));
+        }
+        updates.iter().map(|&(address, _)| address).collect()
     }
-    hal.program_finish().ok()?;
 
-    Some(())
+    #[test]
+    fn queue() {
+        let base = firmware(0x600, 0x22);
+        let delta = firmware(0x800, 0x33);
+        write(FW_START, &firmware(0x400, 0x11));
+        let queue = stage(&[(0x4c00, &base), (0x5400, &delta)]);
+
+        let mut nano = nano(&queue);
+        nano.process_updates();
+        assert!(installed(&delta));
+        assert!(nano.store.queue.is_empty());
+        assert_eq!(
+            nano.reporter.events,
+            [
+                Event::Installed { address: 0x4c00 },
+                Event::Installed { address: 0x5400 }
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_failed() {
+        let base = firmware(0x600, 0x22);
+        let delta = firmware(0x800, 0x33);
+        for (installs, events) in [
+            (0, &[Event::InstallFailed { address: 0x4c00 }][..]),
+            (
+                1,
+                &[
+                    Event::Installed { address: 0x4c00 },
+                    Event::InstallFailed { address: 0x5400 },
+                ],
+            ),
+        ] {
+            write(FW_START, &firmware(0x400, 0x11));
+            let queue = stage(&[(0x4c00, &base), (0x5400, &delta)]);
+
+            // The queue stops at the failed install
+            let mut nano = nano(&queue);
+            nano.programmer.fail = Some((installs, PAGE_SZ + 1));
+            nano.process_updates();
+            assert_eq!(nano.store.queue, queue[installs..]);
+            assert_eq!(nano.reporter.events, events);
+
+            // After a reset, it resumes with the failed update
+            let queue = nano.store.queue.clone();
+            let mut nano = self::nano(&queue);
+            nano.process_updates();
+            assert!(installed(&delta));
+            assert!(nano.store.queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn queue_invalid() {
+        let base = firmware(0x600, 0x22);
+        let delta = firmware(0x800, 0x33);
+
+        // Torn write of the first update
+        write(FW_START, &firmware(0x400, 0x11));
+        let queue = stage(&[(0x4c00, &base), (0x5400, &delta)]);
+        write(0x4c00 + 0x100, &[0]);
+        let mut nano = nano(&queue);
+        nano.process_updates();
+        assert!(installed(&delta));
+        assert!(nano.store.queue.is_empty());
+        assert_eq!(
+            nano.reporter.events,
+            [
+                Event::UpdateInvalid {
+                    address: 0x4c00,
+                    fault: UpdateFault::Checksum
+                },
+                Event::Installed { address: 0x5400 }
+            ]
+        );
+
+        // Second update staged where the first one installs
+        write(FW_START, &firmware(0x400, 0x11));
+        let queue = stage(&[(0x5400, &delta), (0x4400, &base)]);
+        let mut nano = self::nano(&queue);
+        nano.process_updates();
+        assert!(installed(&delta));
+        assert!(nano.store.queue.is_empty());
+        assert!(matches!(
+            nano.reporter.events[1],
+            Event::UpdateInvalid {
+                address: 0x4400,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn queue_stuck() {
+        // A store that fails to clear stops the queue instead of installing over and over
+        let base = firmware(0x600, 0x22);
+        let delta = firmware(0x800, 0x33);
+        write(FW_START, &firmware(0x400, 0x11));
+        let queue = stage(&[(0x4c00, &base), (0x5400, &delta)]);
+        write(0x5400 + 0x100, &[0]);
+
+        let mut nano = nano(&queue);
+        nano.store.stuck = true;
+        nano.process_updates();
+        assert!(installed(&base));
+        assert_eq!(nano.reporter.events, [Event::Installed { address: 0x4c00 }]);
+
+        let mut nano = self::nano(&queue[1..]);
+        nano.store.stuck = true;
+        nano.process_updates();
+        assert_eq!(nano.reporter.events.len(), 1);
+    }
 }
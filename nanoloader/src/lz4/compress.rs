@@ -0,0 +1,272 @@
+//! LZ4 block compression (host only)
+
+use std::{vec, vec::Vec};
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+
+/// The last match must start at least this many bytes before the end of the block
+const MF_LIMIT: usize = 12;
+/// The last bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+
+const HASH_BITS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Greedy matching, only considering the most recent candidate
+    Fast,
+    /// Search hash chains for the longest match and use lazy matching
+    HighCompression,
+}
+
+impl Mode {
+    fn depth(self) -> usize {
+        match self {
+            Mode::Fast => 1,
+            Mode::HighCompression => 4096,
+        }
+    }
+}
+
+struct MatchFinder<'a> {
+    buf: &'a [u8],
+    head: Vec<usize>,
+    chain: Vec<usize>,
+    inserted: usize,
+    depth: usize,
+}
+
+impl<'a> MatchFinder<'a> {
+    const NONE: usize = usize::MAX;
+
+    fn new(buf: &'a [u8], depth: usize) -> Self {
+        Self {
+            buf,
+            head: vec![Self::NONE; 1 << HASH_BITS],
+            chain: vec![Self::NONE; buf.len()],
+            inserted: 0,
+            depth,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let v = u32::from_le_bytes(self.buf[pos..pos + 4].try_into().unwrap());
+        (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    /// Add all positions before `pos` to the hash chains
+    fn insert_upto(&mut self, pos: usize) {
+        let pos = pos.min(self.buf.len().saturating_sub(MIN_MATCH - 1));
+        while self.inserted < pos {
+            let h = self.hash(self.inserted);
+            self.chain[self.inserted] = self.head[h];
+            self.head[h] = self.inserted;
+            self.inserted += 1;
+        }
+    }
+
+    /// Find the longest match for `pos` that does not extend past `limit`
+    fn find(&mut self, pos: usize, limit: usize) -> Option<(usize, usize)> {
+        self.insert_upto(pos);
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+
+        for _ in 0..self.depth {
+            if candidate == Self::NONE || pos - candidate > MAX_OFFSET {
+                break;
+            }
+
+            let length = self.buf[candidate..limit]
+                .iter()
+                .zip(&self.buf[pos..limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if length >= MIN_MATCH && best.is_none_or(|(_, l)| length > l) {
+                best = Some((pos - candidate, length));
+            }
+
+            candidate = self.chain[candidate];
+        }
+
+        best
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], backref: Option<(usize, usize)>) {
+    let match_len = backref.map_or(0, |(_, length)| length - MIN_MATCH);
+
+    let token = (literals.len().min(15) << 4) | match_len.min(15);
+    out.push(token as u8);
+
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = backref {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+/// Compress `data` into a single LZ4 block
+///
+/// Back-references may reach into the last 64K of `dict`, which the decompressor then has to
+/// supply as well.
+pub fn compress(data: &[u8], dict: &[u8], mode: Mode) -> Vec<u8> {
+    let dict = &dict[dict.len().saturating_sub(MAX_OFFSET)..];
+
+    let mut buf = Vec::with_capacity(dict.len() + data.len());
+    buf.extend_from_slice(dict);
+    buf.extend_from_slice(data);
+
+    let start = dict.len();
+    let end = buf.len();
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / 255 + 16);
+    let mut anchor = start;
+
+    if data.len() > MF_LIMIT {
+        let mf_limit = end - MF_LIMIT;
+        let match_limit = end - LAST_LITERALS;
+
+        let mut finder = MatchFinder::new(&buf, mode.depth());
+        let mut pos = start;
+
+        while pos < mf_limit {
+            let Some(mut backref) = finder.find(pos, match_limit) else {
+                pos += 1;
+                continue;
+            };
+
+            // Lazy matching: emit a literal instead if the next position has a longer match
+            if mode == Mode::HighCompression {
+                while pos + 1 < mf_limit {
+                    match finder.find(pos + 1, match_limit) {
+                        Some(next) if next.1 > backref.1 => {
+                            backref = next;
+                            pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+
+            write_sequence(&mut out, &buf[anchor..pos], Some(backref));
+
+            pos += backref.1;
+            anchor = pos;
+        }
+    }
+
+    write_sequence(&mut out, &buf[anchor..end], None);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::BufferSink;
+    use super::*;
+
+    use proptest::prelude::*;
+
+    fn roundtrip(data: &[u8], dict: &[u8], mode: Mode) {
+        let compressed = compress(data, dict, mode);
+
+        let mut sink = BufferSink::<4096>::new(dict);
+        assert!(super::super::decompress(&compressed, &mut sink).is_ok());
+        assert_eq!(sink.as_slice(), data);
+
+        // Cross-check with an independent implementation
+        let decompressed =
+            lz4_flex::block::decompress_with_dict(&compressed, data.len(), dict).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    fn reference(data: &[u8], dict: &[u8]) {
+        let compressed = lz4_flex::block::compress_with_dict(data, dict);
+
+        let mut sink = BufferSink::<4096>::new(dict);
+        assert!(super::super::decompress(&compressed, &mut sink).is_ok());
+        assert_eq!(sink.as_slice(), data);
+    }
+
+    /// Data with plenty of repetition, so that there is something to find for the compressor
+    fn repetitive() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(
+            prop_oneof![
+                prop::collection::vec(any::<u8>(), 1..16),
+                prop::collection::vec(b'a'..=b'd', 1..64),
+                (any::<u8>(), 1..300usize).prop_map(|(b, n)| vec![b; n]),
+            ],
+            0..32,
+        )
+        .prop_map(|v| v.concat())
+        .prop_filter("too large", |v| v.len() <= 4096)
+    }
+
+    #[test]
+    fn lorem() {
+        let data = include_bytes!("testdata/lorem2.dat");
+        let dict = include_bytes!("testdata/lorem2.dct");
+
+        for mode in [Mode::Fast, Mode::HighCompression] {
+            roundtrip(data, &[], mode);
+            roundtrip(data, dict, mode);
+        }
+
+        // High compression should do no worse than the reference encoder used for the test data
+        assert!(
+            compress(data, &[], Mode::HighCompression).len()
+                <= include_bytes!("testdata/lorem1.lz4").len()
+        );
+        assert!(
+            compress(data, dict, Mode::HighCompression).len()
+                <= include_bytes!("testdata/lorem2.lz4").len()
+        );
+    }
+
+    #[test]
+    fn short() {
+        for len in 0..=MF_LIMIT + 1 {
+            roundtrip(&[0u8; 16][..len], &[], Mode::HighCompression);
+        }
+        assert_eq!(compress(b"", &[], Mode::Fast), b"\0");
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip_fast(data in repetitive(), dict in repetitive()) {
+            roundtrip(&data, &dict, Mode::Fast);
+        }
+
+        #[test]
+        fn roundtrip_hc(data in repetitive(), dict in repetitive()) {
+            roundtrip(&data, &dict, Mode::HighCompression);
+        }
+
+        #[test]
+        fn roundtrip_random(data in prop::collection::vec(any::<u8>(), 0..2048)) {
+            roundtrip(&data, &[], Mode::HighCompression);
+        }
+
+        #[test]
+        fn reference_decompress(data in repetitive(), dict in repetitive()) {
+            reference(&data, &dict);
+        }
+    }
+}
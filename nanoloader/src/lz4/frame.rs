@@ -0,0 +1,403 @@
+//! LZ4 frame format
+//!
+//! See https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md
+
+use core::convert::Infallible;
+
+use super::xxhash::XxHash32;
+use super::{Error, ErrorKind, Readback, Sink};
+use crate::ensure;
+
+const MAGIC: u32 = 0x184d_2204;
+const MAGIC_SKIPPABLE: u32 = 0x184d_2a50;
+const MAGIC_SKIPPABLE_MASK: u32 = 0xffff_fff0;
+
+const FLG_VERSION_MASK: u8 = 0xc0;
+const FLG_VERSION: u8 = 0x40;
+const FLG_BLOCK_CHECKSUM: u8 = 0x10;
+const FLG_CONTENT_SIZE: u8 = 0x08;
+const FLG_CONTENT_CHECKSUM: u8 = 0x04;
+const FLG_RESERVED: u8 = 0x02;
+const FLG_DICT_ID: u8 = 0x01;
+
+const BD_RESERVED: u8 = 0x8f;
+
+const BLOCK_UNCOMPRESSED: u32 = 0x8000_0000;
+
+/// Frame descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor {
+    pub block_checksum: bool,
+    pub content_checksum: bool,
+    pub content_size: Option<u64>,
+    pub dict_id: Option<u32>,
+    pub block_max_size: usize,
+}
+
+/// Sink wrapper that keeps track of the output length and content checksum
+struct Content<'a, S> {
+    sink: &'a mut S,
+    hasher: Option<XxHash32>,
+    length: u64,
+}
+
+impl<S: Readback> Sink for Content<'_, S> {
+    type Error = S::Error;
+
+    fn literal(&mut self, data: &[u8]) -> Result<(), S::Error> {
+        self.sink.literal(data)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    fn backref(&mut self, offset: usize, length: usize) -> Result<(), S::Error> {
+        let Some(hasher) = &mut self.hasher else {
+            self.sink.backref(offset, length)?;
+            self.length += length as u64;
+            return Ok(());
+        };
+
+        // A match can overlap its own output, so it is forwarded in pieces no longer than the
+        // offset. That way, each piece can be read back for hashing once it has been produced.
+        // The decoder never passes a zero offset, but leave it to the sink to reject it anyway.
+        let mut remaining = length;
+        while remaining > 0 {
+            let n = remaining.min(offset.max(1));
+            self.sink.backref(offset, n)?;
+            for distance in (1..=n).rev() {
+                hasher.update(&[self.sink.readback(distance)?]);
+            }
+            remaining -= n;
+        }
+        self.length += length as u64;
+        Ok(())
+    }
+}
+
+/// Input cursor that keeps track of the position for error reporting
+struct Reader<'a> {
+    source: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<E>(&mut self, n: usize) -> Result<&'a [u8], Error<E>> {
+        let (head, tail) = self.source.split_at_checked(n).ok_or(Error::new(
+            self.position + self.source.len(),
+            ErrorKind::Truncated,
+        ))?;
+        self.source = tail;
+        self.position += n;
+        Ok(head)
+    }
+
+    fn take_array<E, const N: usize>(&mut self) -> Result<[u8; N], Error<E>> {
+        // The length is guaranteed to match, so the conversion cannot fail
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn u8<E>(&mut self) -> Result<u8, Error<E>> {
+        self.take_array().map(u8::from_le_bytes)
+    }
+
+    fn u32<E>(&mut self) -> Result<u32, Error<E>> {
+        self.take_array().map(u32::from_le_bytes)
+    }
+
+    fn u64<E>(&mut self) -> Result<u64, Error<E>> {
+        self.take_array().map(u64::from_le_bytes)
+    }
+}
+
+fn check<E>(b: bool, position: usize, kind: ErrorKind<E>) -> Result<(), Error<E>> {
+    ensure(b).ok_or(Error::new(position, kind))
+}
+
+fn parse_descriptor<E>(reader: &mut Reader) -> Result<Descriptor, Error<E>> {
+    // Skip any skippable frames preceding the LZ4 frame
+    let magic = loop {
+        let magic = reader.u32()?;
+        if magic & MAGIC_SKIPPABLE_MASK != MAGIC_SKIPPABLE {
+            break magic;
+        }
+        let size = reader.u32()?;
+        reader.take(size as usize)?;
+    };
+    check(magic == MAGIC, reader.position - 4, ErrorKind::BadMagic)?;
+
+    let header = reader.source;
+    let start = reader.position;
+
+    let flg = reader.u8()?;
+    let bd = reader.u8()?;
+
+    check(
+        flg & FLG_VERSION_MASK == FLG_VERSION && flg & FLG_RESERVED == 0 && bd & BD_RESERVED == 0,
+        start,
+        ErrorKind::BadDescriptor,
+    )?;
+
+    let block_max_size = match bd >> 4 {
+        4 => 64 << 10,
+        5 => 256 << 10,
+        6 => 1 << 20,
+        7 => 4 << 20,
+        _ => Err(Error::new(start + 1, ErrorKind::BadDescriptor))?,
+    };
+
+    let content_size = if flg & FLG_CONTENT_SIZE != 0 {
+        Some(reader.u64()?)
+    } else {
+        None
+    };
+
+    let dict_id = if flg & FLG_DICT_ID != 0 {
+        Some(reader.u32()?)
+    } else {
+        None
+    };
+
+    // Header checksum covers everything from FLG up to here
+    let header = &header[..reader.position - start];
+    let hc = reader.u8()?;
+    check(
+        hc == (XxHash32::checksum(header) >> 8) as u8,
+        reader.position - 1,
+        ErrorKind::HeaderChecksum,
+    )?;
+
+    Ok(Descriptor {
+        block_checksum: flg & FLG_BLOCK_CHECKSUM != 0,
+        content_checksum: flg & FLG_CONTENT_CHECKSUM != 0,
+        content_size,
+        dict_id,
+        block_max_size,
+    })
+}
+
+/// Parse the frame header, returning the descriptor and the remaining input
+pub fn descriptor(source: &[u8]) -> Result<(Descriptor, &[u8]), Error<Infallible>> {
+    let mut reader = Reader {
+        source,
+        position: 0,
+    };
+    let descriptor = parse_descriptor(&mut reader)?;
+    Ok((descriptor, reader.source))
+}
+
+/// Decompress a single LZ4 frame
+///
+/// The frame must make up the entire input, optionally preceded by skippable frames. Since the
+/// content checksum is calculated over the decompressed data, the sink must be able to read back
+/// what it has produced. A dictionary, if any, has to be supplied by the sink.
+pub fn decompress<S: Readback>(source: &[u8], sink: &mut S) -> Result<(), Error<S::Error>> {
+    let mut reader = Reader {
+        source,
+        position: 0,
+    };
+    let desc = parse_descriptor(&mut reader)?;
+
+    let mut content = Content {
+        sink,
+        hasher: desc.content_checksum.then(|| XxHash32::new(0)),
+        length: 0,
+    };
+
+    loop {
+        let start = reader.position;
+        let size = reader.u32()?;
+        if size == 0 {
+            // EndMark
+            break;
+        }
+
+        let length = (size & !BLOCK_UNCOMPRESSED) as usize;
+        check(length <= desc.block_max_size, start, ErrorKind::BlockSize)?;
+
+        let block = reader.take(length)?;
+
+        if desc.block_checksum {
+            let checksum = reader.u32()?;
+            check(
+                checksum == XxHash32::checksum(block),
+                reader.position - 4,
+                ErrorKind::BlockChecksum,
+            )?;
+        }
+
+        if size & BLOCK_UNCOMPRESSED != 0 {
+            content
+                .literal(block)
+                .map_err(|e| Error::new(start + 4, ErrorKind::Sink(e)))?;
+        } else {
+            super::decompress(block, &mut content).map_err(|e| e.offset(start + 4))?;
+        }
+    }
+
+    if let Some(hasher) = &content.hasher {
+        let checksum = reader.u32()?;
+        check(
+            checksum == hasher.finish(),
+            reader.position - 4,
+            ErrorKind::ContentChecksum,
+        )?;
+    }
+
+    if let Some(size) = desc.content_size {
+        check(
+            size == content.length,
+            reader.position,
+            ErrorKind::ContentSize,
+        )?;
+    }
+
+    check(
+        reader.source.is_empty(),
+        reader.position,
+        ErrorKind::TrailingData,
+    )
+}
+
+/// Compress `data` into a single LZ4 frame of linked 64K blocks, with content size and checksum
+#[cfg(any(feature = "std", test))]
+pub fn compress(data: &[u8], mode: super::Mode) -> std::vec::Vec<u8> {
+    const BLOCK_MAX_SIZE: usize = 64 << 10;
+
+    let mut out = MAGIC.to_le_bytes().to_vec();
+    let header = out.len();
+    out.extend_from_slice(&[
+        FLG_VERSION | FLG_CONTENT_SIZE | FLG_CONTENT_CHECKSUM,
+        4 << 4,
+    ]);
+    out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    out.push((XxHash32::checksum(&out[header..]) >> 8) as u8);
+
+    for (i, block) in data.chunks(BLOCK_MAX_SIZE).enumerate() {
+        let start = i * BLOCK_MAX_SIZE;
+        let compressed = super::compress(block, &data[..start], mode);
+        if compressed.len() < block.len() {
+            out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            out.extend_from_slice(&compressed);
+        } else {
+            out.extend_from_slice(&(block.len() as u32 | BLOCK_UNCOMPRESSED).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+    }
+
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&XxHash32::checksum(data).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::BufferSink;
+    use super::*;
+
+    fn do_test(data: &[u8], compressed: &[u8], dict: &[u8]) {
+        let mut sink = BufferSink::<2048>::new(dict);
+
+        let result = decompress(compressed, &mut sink);
+
+        assert!(result.is_ok());
+        assert_eq!(sink.as_slice(), data)
+    }
+
+    fn do_test_corrupt(compressed: &[u8], position: usize, kind: ErrorKind<()>) {
+        let mut sink = BufferSink::<2048>::new(&[]);
+
+        assert_eq!(
+            decompress(compressed, &mut sink),
+            Err(Error::new(position, kind))
+        );
+    }
+
+    #[test]
+    fn empty() {
+        do_test(b"", include_bytes!("testdata/empty.lz4f"), &[]);
+    }
+
+    #[test]
+    fn lorem1() {
+        do_test(
+            include_bytes!("testdata/lorem1.dat"),
+            include_bytes!("testdata/lorem1.lz4f"),
+            &[],
+        );
+    }
+
+    #[test]
+    fn lorem2() {
+        do_test(
+            include_bytes!("testdata/lorem2.dat"),
+            include_bytes!("testdata/lorem2.lz4f"),
+            include_bytes!("testdata/lorem2.dct"),
+        );
+    }
+
+    #[test]
+    fn lorem3() {
+        // Small linked blocks with block checksums and content size
+        let data = include_bytes!("testdata/lorem1.dat");
+        let compressed = include_bytes!("testdata/lorem3.lz4f");
+
+        let (desc, _) = descriptor(compressed).unwrap();
+        assert!(desc.block_checksum);
+        assert!(desc.content_checksum);
+        assert_eq!(desc.content_size, Some(data.len() as u64));
+
+        do_test(data, compressed, &[]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = include_bytes!("testdata/lorem1.dat");
+        let compressed = compress(data, super::super::Mode::HighCompression);
+        do_test(data, &compressed, &[]);
+
+        let (desc, _) = descriptor(&compressed).unwrap();
+        assert_eq!(desc.content_size, Some(data.len() as u64));
+
+        let random = include_bytes!("testdata/random.dat");
+        do_test(random, &compress(random, super::super::Mode::Fast), &[]);
+    }
+
+    #[test]
+    fn random() {
+        // Incompressible data is stored in uncompressed blocks
+        do_test(
+            include_bytes!("testdata/random.dat"),
+            include_bytes!("testdata/random.lz4f"),
+            &[],
+        );
+    }
+
+    #[test]
+    fn corrupt() {
+        let compressed = include_bytes!("testdata/lorem3.lz4f");
+        let len = compressed.len();
+
+        do_test_corrupt(&compressed[..len - 1], len - 1, ErrorKind::Truncated);
+
+        let mut buf = [0u8; 2048];
+        let mut corrupt = |idx: usize, position: usize, kind: ErrorKind<()>| {
+            buf[..len].copy_from_slice(compressed);
+            buf[idx] ^= 0x01;
+            do_test_corrupt(&buf[..len], position, kind);
+        };
+
+        corrupt(0, 0, ErrorKind::BadMagic);
+        corrupt(5, 4, ErrorKind::BadDescriptor);
+        corrupt(6, 14, ErrorKind::HeaderChecksum);
+        // First block (uncompressed) starts at offset 15, followed by its checksum
+        corrupt(20, 15 + 4 + 128, ErrorKind::BlockChecksum);
+        corrupt(len - 2, len - 4, ErrorKind::ContentChecksum);
+
+        // Trailing garbage
+        buf[..len].copy_from_slice(compressed);
+        do_test_corrupt(&buf[..len + 1], len, ErrorKind::TrailingData);
+    }
+}
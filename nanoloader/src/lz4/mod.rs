@@ -1,66 +1,178 @@
+pub mod frame;
+pub mod sink;
+pub mod stream;
+mod xxhash;
+
+#[cfg(any(feature = "std", test))]
+mod compress;
+#[cfg(any(feature = "std", test))]
+pub use compress::{Mode, compress};
+
+/// Reason for a decoding failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind<E> {
+    /// Input ended prematurely
+    Truncated,
+    /// Literal or match length does not fit into `usize`
+    LengthOverflow,
+    /// Back-reference with an offset of zero
+    ZeroOffset,
+    /// Frame does not start with the LZ4 magic number
+    BadMagic,
+    /// Unsupported version or reserved bits set in frame descriptor
+    BadDescriptor,
+    HeaderChecksum,
+    /// Block exceeds the maximum block size of the frame
+    BlockSize,
+    BlockChecksum,
+    ContentChecksum,
+    /// Decompressed size does not match the frame's content size
+    ContentSize,
+    /// Unexpected data following the frame
+    TrailingData,
+    /// Sink failed to process the output
+    Sink(E),
+    /// Streaming decoder already failed, at the position of the original error
+    Failed,
+}
+
+/// Decoding error, with the offset into the input at which it occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error<E> {
+    pub position: usize,
+    pub kind: ErrorKind<E>,
+}
+
+impl<E> Error<E> {
+    pub fn new(position: usize, kind: ErrorKind<E>) -> Self {
+        Self { position, kind }
+    }
+
+    fn sink(position: usize, error: E) -> Self {
+        Self::new(position, ErrorKind::Sink(error))
+    }
+
+    /// Shift the error position by `base`
+    fn offset(self, base: usize) -> Self {
+        Self::new(base + self.position, self.kind)
+    }
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?} at input offset {}", self.kind, self.position)
+    }
+}
+
 pub trait Sink {
-    fn literal(&mut self, data: &[u8]) -> Option<()>;
-    fn backref(&mut self, offset: usize, length: usize) -> Option<()>;
+    type Error;
+
+    fn literal(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+    fn backref(&mut self, offset: usize, length: usize) -> Result<(), Self::Error>;
+}
+
+/// Sinks that can read back the output they have produced
+pub trait Readback: Sink {
+    /// Read the byte `distance` bytes back from the current end of the output (1 = last byte)
+    fn readback(&mut self, distance: usize) -> Result<u8, Self::Error>;
+}
+
+type Iter<'a> = core::slice::Iter<'a, u8>;
+
+/// Current position of the iterator within the source
+fn position(source: &[u8], it: &Iter) -> usize {
+    source.len() - it.as_slice().len()
 }
 
-fn extend_length<'a>(len: usize, it: &mut impl Iterator<Item = &'a u8>) -> Option<usize> {
+fn next_byte<E>(source: &[u8], it: &mut Iter) -> Result<usize, Error<E>> {
+    let pos = position(source, it);
+    it.next()
+        .map(|x| *x as usize)
+        .ok_or(Error::new(pos, ErrorKind::Truncated))
+}
+
+fn extend_length<E>(len: usize, source: &[u8], it: &mut Iter) -> Result<usize, Error<E>> {
     let mut length: usize = len;
     if length == 15 {
         loop {
-            let len = it.next().map(|x| *x as usize)?;
-            length = length.checked_add(len)?;
+            let pos = position(source, it);
+            let len = next_byte(source, it)?;
+            length = length
+                .checked_add(len)
+                .ok_or(Error::new(pos, ErrorKind::LengthOverflow))?;
             if len != 255 {
                 break;
             }
         }
     }
-    Some(length)
+    Ok(length)
 }
 
-pub fn decompress(source: &[u8], sink: &mut impl Sink) -> Option<()> {
+pub fn decompress<S: Sink>(source: &[u8], sink: &mut S) -> Result<(), Error<S::Error>> {
     let mut it = source.iter();
 
     loop {
-        let token = it.next().map(|x| *x as usize)?;
+        let token = next_byte(source, &mut it)?;
 
         let literal_len = token >> 4;
         let match_len = token & 0x0f;
 
-        let literal_len = extend_length(literal_len, &mut it)?;
+        let literal_len = extend_length(literal_len, source, &mut it)?;
 
-        let (literals, more) = it.as_slice().split_at_checked(literal_len)?;
+        let pos = position(source, &it);
+        let (literals, more) = it
+            .as_slice()
+            .split_at_checked(literal_len)
+            .ok_or(Error::new(source.len(), ErrorKind::Truncated))?;
 
-        sink.literal(literals)?;
+        sink.literal(literals).map_err(|e| Error::sink(pos, e))?;
 
         it = more.iter();
 
         let Some(offset_lsb) = it.next().map(|x| *x as usize) else {
             // The last block only contains literals, so we're done here.
-            return Some(());
+            return Ok(());
         };
 
-        let offset_msb = it.next().map(|x| *x as usize)?;
+        let offset_msb = next_byte(source, &mut it)?;
 
+        // Errors from here on are reported at the last byte read
         let offset = (offset_msb << 8) | offset_lsb;
+        if offset == 0 {
+            return Err(Error::new(position(source, &it) - 1, ErrorKind::ZeroOffset));
+        }
 
-        let match_len = extend_length(match_len, &mut it)?.checked_add(4)?;
+        let match_len = extend_length(match_len, source, &mut it)?;
+        let pos = position(source, &it) - 1;
+        let match_len = match_len
+            .checked_add(4)
+            .ok_or(Error::new(pos, ErrorKind::LengthOverflow))?;
 
-        sink.backref(offset, match_len)?;
+        sink.backref(offset, match_len)
+            .map_err(|e| Error::sink(pos, e))?;
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
     use super::*;
 
-    struct BufferSink<'a, const SIZE: usize> {
+    pub struct BufferSink<'a, const SIZE: usize> {
         length: usize,
         buffer: [u8; SIZE],
         dict: &'a [u8],
     }
 
-    impl<const SIZE: usize> BufferSink<'_, SIZE> {
-        fn as_slice(&self) -> &[u8] {
+    impl<'a, const SIZE: usize> BufferSink<'a, SIZE> {
+        pub fn new(dict: &'a [u8]) -> Self {
+            Self {
+                length: 0,
+                buffer: [0; SIZE],
+                dict,
+            }
+        }
+
+        pub fn as_slice(&self) -> &[u8] {
             &self.buffer[..self.length]
         }
 
@@ -74,36 +186,49 @@ mod tests {
     }
 
     impl<const SIZE: usize> Sink for BufferSink<'_, SIZE> {
-        fn literal(&mut self, data: &[u8]) -> Option<()> {
+        type Error = ();
+
+        fn literal(&mut self, data: &[u8]) -> Result<(), ()> {
             self.buffer[self.length..self.length + data.len()].copy_from_slice(data);
             self.length += data.len();
-            Some(())
+            Ok(())
         }
 
-        fn backref(&mut self, offset: usize, length: usize) -> Option<()> {
+        fn backref(&mut self, offset: usize, length: usize) -> Result<(), ()> {
             let offset = self.length as isize - offset as isize;
 
             for i in 0..length {
                 self.buffer[self.length + i] = self.get(offset + i as isize);
             }
             self.length += length;
-            Some(())
+            Ok(())
+        }
+    }
+
+    impl<const SIZE: usize> Readback for BufferSink<'_, SIZE> {
+        fn readback(&mut self, distance: usize) -> Result<u8, ()> {
+            Ok(self.get(self.length as isize - distance as isize))
         }
     }
 
     fn do_test(data: &[u8], compressed: &[u8], dict: &[u8]) {
-        let mut sink = BufferSink::<1024> {
-            length: 0,
-            buffer: [0; 1024],
-            dict,
-        };
+        let mut sink = BufferSink::<1024>::new(dict);
 
         let result = decompress(compressed, &mut sink);
 
-        assert!(result.is_some());
+        assert!(result.is_ok());
         assert_eq!(sink.as_slice(), data)
     }
 
+    fn do_test_error(compressed: &[u8], position: usize, kind: ErrorKind<()>) {
+        let mut sink = BufferSink::<1024>::new(&[]);
+
+        assert_eq!(
+            decompress(compressed, &mut sink),
+            Err(Error::new(position, kind))
+        );
+    }
+
     #[test]
     fn empty() {
         do_test(b"", b"\0", &[]);
@@ -126,4 +251,14 @@ mod tests {
             include_bytes!("testdata/lorem2.dct"),
         );
     }
+
+    #[test]
+    fn errors() {
+        do_test_error(b"", 0, ErrorKind::Truncated);
+        do_test_error(b"\x30ab", 3, ErrorKind::Truncated);
+        do_test_error(b"\xf0\xff", 2, ErrorKind::Truncated);
+        do_test_error(b"\x10a\x00\x00\x00", 3, ErrorKind::ZeroOffset);
+        do_test_error(b"\x10a\x01", 3, ErrorKind::Truncated);
+        do_test_error(b"\x1fa\x01\x00\xff", 5, ErrorKind::Truncated);
+    }
 }
@@ -0,0 +1,380 @@
+//! Sinks for decompressing into memory that cannot hold the entire output
+
+use super::{Readback, Sink};
+use crate::{FlashProgrammer, NanoReason};
+
+/// Destination for decompressed data
+pub trait Output {
+    type Error;
+
+    fn write(&mut self, value: u8) -> Result<(), Self::Error>;
+}
+
+/// Destination that can read back previously written data
+pub trait ReadOutput: Output {
+    /// Read the byte at `offset` from the start of the output
+    fn read(&mut self, offset: usize) -> Result<u8, Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkError<E> {
+    /// Back-reference of zero, or reaching before the start of output and dictionary
+    InvalidOffset(usize),
+    /// Back-reference reaching beyond the RAM window
+    OutsideWindow(usize),
+    /// Output growing beyond the limit
+    Overflow(usize),
+    /// Output failed
+    Output(E),
+}
+
+/// Position of a back-referenced byte
+enum Source {
+    /// Offset from the start of the output
+    Output(usize),
+    /// Index into the dictionary
+    Dict(usize),
+}
+
+/// Locate the byte `distance` bytes before `position`, which may lie within the dictionary
+fn locate<E>(position: usize, distance: usize, dict: &[u8]) -> Result<Source, SinkError<E>> {
+    if distance == 0 {
+        return Err(SinkError::InvalidOffset(distance));
+    }
+    match position.checked_sub(distance) {
+        Some(offset) => Ok(Source::Output(offset)),
+        None => dict
+            .len()
+            .checked_sub(distance - position)
+            .map(Source::Dict)
+            .ok_or(SinkError::InvalidOffset(distance)),
+    }
+}
+
+/// Check that `count` more bytes fit into an output of at most `limit` bytes
+fn reserve<E>(length: usize, count: usize, limit: usize) -> Result<(), SinkError<E>> {
+    match length.checked_add(count) {
+        Some(end) if end <= limit => Ok(()),
+        _ => Err(SinkError::Overflow(limit)),
+    }
+}
+
+/// Sink that resolves back-references from a RAM window of the last `N` bytes of output
+///
+/// Back-references reaching further back than the window (but not into the dictionary) are
+/// rejected, so `N` should match the window size the data was compressed with.
+pub struct RingSink<'a, O, const N: usize> {
+    output: O,
+    window: [u8; N],
+    length: usize,
+    limit: usize,
+    dict: &'a [u8],
+}
+
+impl<'a, O: Output, const N: usize> RingSink<'a, O, N> {
+    pub fn new(output: O, dict: &'a [u8]) -> Self {
+        const { assert!(N > 0) }
+        Self {
+            output,
+            window: [0; N],
+            length: 0,
+            limit: usize::MAX,
+            dict,
+        }
+    }
+
+    /// Reject any literal or back-reference that would grow the output beyond `limit` bytes
+    pub fn with_limit(self, limit: usize) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// Number of bytes written to the output so far
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    fn get(&self, position: usize, distance: usize) -> Result<u8, SinkError<O::Error>> {
+        match locate(position, distance, self.dict)? {
+            Source::Output(_) if distance > N => Err(SinkError::OutsideWindow(distance)),
+            Source::Output(_) => Ok(self.window[(position - distance) % N]),
+            Source::Dict(idx) => Ok(self.dict[idx]),
+        }
+    }
+
+    fn put(&mut self, value: u8) -> Result<(), SinkError<O::Error>> {
+        self.output.write(value).map_err(SinkError::Output)?;
+        self.window[self.length % N] = value;
+        self.length += 1;
+        Ok(())
+    }
+}
+
+impl<O: Output, const N: usize> Sink for RingSink<'_, O, N> {
+    type Error = SinkError<O::Error>;
+
+    fn literal(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        reserve(self.length, data.len(), self.limit)?;
+        data.iter().try_for_each(|b| self.put(*b))
+    }
+
+    fn backref(&mut self, offset: usize, length: usize) -> Result<(), Self::Error> {
+        reserve(self.length, length, self.limit)?;
+        for _ in 0..length {
+            let value = self.get(self.length, offset)?;
+            self.put(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<O: Output, const N: usize> Readback for RingSink<'_, O, N> {
+    fn readback(&mut self, distance: usize) -> Result<u8, Self::Error> {
+        self.get(self.length, distance)
+    }
+}
+
+/// Sink that resolves back-references by reading back the output itself
+///
+/// This needs no RAM beyond the sink itself, but every back-referenced byte is read from the
+/// output, e.g. from already programmed Flash.
+pub struct FlashSink<'a, O> {
+    output: O,
+    length: usize,
+    limit: usize,
+    dict: &'a [u8],
+}
+
+impl<'a, O: ReadOutput> FlashSink<'a, O> {
+    pub fn new(output: O, dict: &'a [u8]) -> Self {
+        Self {
+            output,
+            length: 0,
+            limit: usize::MAX,
+            dict,
+        }
+    }
+
+    /// Reject any literal or back-reference that would grow the output beyond `limit` bytes
+    pub fn with_limit(self, limit: usize) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// Number of bytes written to the output so far
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn into_output(self) -> O {
+        self.output
+    }
+
+    fn get(&mut self, position: usize, distance: usize) -> Result<u8, SinkError<O::Error>> {
+        match locate(position, distance, self.dict)? {
+            Source::Output(offset) => self.output.read(offset).map_err(SinkError::Output),
+            Source::Dict(idx) => Ok(self.dict[idx]),
+        }
+    }
+
+    fn put(&mut self, value: u8) -> Result<(), SinkError<O::Error>> {
+        self.output.write(value).map_err(SinkError::Output)?;
+        self.length += 1;
+        Ok(())
+    }
+}
+
+impl<O: ReadOutput> Sink for FlashSink<'_, O> {
+    type Error = SinkError<O::Error>;
+
+    fn literal(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        reserve(self.length, data.len(), self.limit)?;
+        data.iter().try_for_each(|b| self.put(*b))
+    }
+
+    fn backref(&mut self, offset: usize, length: usize) -> Result<(), Self::Error> {
+        reserve(self.length, length, self.limit)?;
+        for _ in 0..length {
+            let value = self.get(self.length, offset)?;
+            self.put(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<O: ReadOutput> Readback for FlashSink<'_, O> {
+    fn readback(&mut self, distance: usize) -> Result<u8, Self::Error> {
+        self.get(self.length, distance)
+    }
+}
+
+/// Program the output into the firmware area
+impl<P: FlashProgrammer> Output for &mut P {
+    type Error = NanoReason;
+
+    fn write(&mut self, value: u8) -> Result<(), NanoReason> {
+        self.program_write(value)
+    }
+}
+
+impl<P: FlashProgrammer> ReadOutput for &mut P {
+    fn read(&mut self, offset: usize) -> Result<u8, NanoReason> {
+        self.program_read(offset)
+    }
+}
+
+/// Read beyond the end of a [`Vec`](std::vec::Vec) output
+#[cfg(any(feature = "std", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange(pub usize);
+
+#[cfg(any(feature = "std", test))]
+impl Output for std::vec::Vec<u8> {
+    type Error = OutOfRange;
+
+    fn write(&mut self, value: u8) -> Result<(), Self::Error> {
+        self.push(value);
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl ReadOutput for std::vec::Vec<u8> {
+    fn read(&mut self, offset: usize) -> Result<u8, Self::Error> {
+        self.get(offset).copied().ok_or(OutOfRange(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Error, ErrorKind, decompress, frame};
+    use super::*;
+    use std::vec::Vec;
+
+    const DATA: &[u8] = include_bytes!("testdata/lorem2.dat");
+    const DICT: &[u8] = include_bytes!("testdata/lorem2.dct");
+
+    type Result<T> = core::result::Result<T, Error<SinkError<OutOfRange>>>;
+
+    fn ring<const N: usize>(compressed: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+        let mut sink = RingSink::<_, N>::new(Vec::new(), dict);
+        decompress(compressed, &mut sink)?;
+        Ok(sink.into_output())
+    }
+
+    fn flash(compressed: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+        let mut sink = FlashSink::new(Vec::new(), dict);
+        decompress(compressed, &mut sink)?;
+        Ok(sink.into_output())
+    }
+
+    #[test]
+    fn lorem() {
+        let lorem1 = include_bytes!("testdata/lorem1.lz4");
+        let lorem2 = include_bytes!("testdata/lorem2.lz4");
+
+        assert_eq!(ring::<1024>(lorem1, &[]).unwrap(), DATA);
+        assert_eq!(ring::<1024>(lorem2, DICT).unwrap(), DATA);
+        assert_eq!(flash(lorem1, &[]).unwrap(), DATA);
+        assert_eq!(flash(lorem2, DICT).unwrap(), DATA);
+    }
+
+    #[test]
+    fn window() {
+        // A window smaller than the longest back-reference cannot decode the data, but must
+        // report that instead of producing garbage.
+        let lorem1 = include_bytes!("testdata/lorem1.lz4");
+
+        assert!(matches!(
+            ring::<16>(lorem1, &[]),
+            Err(Error {
+                kind: ErrorKind::Sink(SinkError::OutsideWindow(_)),
+                ..
+            })
+        ));
+        assert_eq!(ring::<500>(lorem1, &[]).unwrap(), DATA);
+    }
+
+    #[test]
+    fn frame() {
+        // Content checksum requires reading back the output
+        let lorem2 = include_bytes!("testdata/lorem2.lz4f");
+
+        let mut sink = RingSink::<_, 512>::new(Vec::new(), DICT);
+        assert!(frame::decompress(lorem2, &mut sink).is_ok());
+        assert_eq!(sink.into_output(), DATA);
+
+        let mut sink = FlashSink::new(Vec::new(), DICT);
+        assert!(frame::decompress(lorem2, &mut sink).is_ok());
+        assert_eq!(sink.into_output(), DATA);
+    }
+
+    #[test]
+    fn invalid_offset() {
+        // Four literals, then a back-reference with the given offset
+        let block = |offset: u16| {
+            let [lo, hi] = offset.to_le_bytes();
+            [0x40, b'a', b'b', b'c', b'd', lo, hi, 0x00]
+        };
+
+        let invalid = |offset| {
+            Err(Error::new(
+                6,
+                ErrorKind::Sink(SinkError::InvalidOffset(offset)),
+            ))
+        };
+
+        for (offset, dict, result) in [
+            (0, &b""[..], Err(Error::new(6, ErrorKind::ZeroOffset))),
+            (4, b"", Ok(b"abcdabcd".to_vec())),
+            (5, b"", invalid(5)),
+            (5, b"x", Ok(b"abcdxabc".to_vec())),
+            (6, b"x", invalid(6)),
+        ] {
+            let block = block(offset as u16);
+            assert_eq!(ring::<64>(&block, dict), result);
+            assert_eq!(flash(&block, dict), result);
+        }
+    }
+
+    #[test]
+    fn limit() {
+        // Four literals, then a back-reference of four bytes
+        let block = [0x40, b'a', b'b', b'c', b'd', 4, 0, 0x00];
+
+        let mut sink = FlashSink::new(Vec::new(), &[]).with_limit(8);
+        assert_eq!(decompress(&block, &mut sink), Ok(()));
+        assert_eq!(sink.into_output(), b"abcdabcd");
+
+        // Nothing of a literal or back-reference crossing the limit is written
+        for (limit, position, output) in [(3, 1, &b""[..]), (7, 6, b"abcd")] {
+            let overflow = Err(Error::new(
+                position,
+                ErrorKind::Sink(SinkError::Overflow(limit)),
+            ));
+            let mut sink = RingSink::<_, 64>::new(Vec::new(), &[]).with_limit(limit);
+            assert_eq!(decompress(&block, &mut sink), overflow);
+            assert_eq!(sink.into_output(), output);
+            let mut sink = FlashSink::new(Vec::new(), &[]).with_limit(limit);
+            assert_eq!(decompress(&block, &mut sink), overflow);
+            assert_eq!(sink.into_output(), output);
+        }
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let mut output = b"abc".to_vec();
+        assert_eq!(output.read(2), Ok(b'c'));
+        assert_eq!(output.read(3), Err(OutOfRange(3)));
+    }
+}
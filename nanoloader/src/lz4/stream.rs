@@ -0,0 +1,279 @@
+//! Incremental LZ4 block decoding
+
+use super::{Error, ErrorKind, Sink};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Expecting a token
+    Token,
+    /// Reading the literal length extension
+    LiteralLength { length: usize, match_len: usize },
+    /// Copying literals
+    Literals { remaining: usize, match_len: usize },
+    /// Expecting the low byte of the offset, or the end of the block
+    OffsetLo { match_len: usize },
+    /// Expecting the high byte of the offset
+    OffsetHi { lo: u8, match_len: usize },
+    /// Reading the match length extension
+    MatchLength { offset: usize, length: usize },
+    /// Decoding failed at `position`
+    Failed { position: usize },
+}
+
+/// LZ4 block decoder that accepts its input in arbitrary chunks
+///
+/// The output is identical to that of [`super::decompress`], except that literals may be passed
+/// to the sink in several pieces.
+pub struct Decoder {
+    state: State,
+    position: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Token,
+            position: 0,
+        }
+    }
+
+    /// Decode the next chunk of input
+    ///
+    /// Once this fails, all subsequent calls fail with [`ErrorKind::Failed`] at the position of
+    /// the original error. Error positions are relative to the start of the entire input, not the
+    /// current chunk.
+    pub fn feed<S: Sink>(&mut self, input: &[u8], sink: &mut S) -> Result<(), Error<S::Error>> {
+        let result = self.run(input, sink);
+        if let Err(e) = &result {
+            self.state = State::Failed {
+                position: e.position,
+            };
+        }
+        result
+    }
+
+    /// Signal the end of the input
+    ///
+    /// This fails unless the input ended right after the literals of the last sequence.
+    pub fn finish<E>(self) -> Result<(), Error<E>> {
+        match self.state {
+            State::OffsetLo { .. } => Ok(()),
+            State::Failed { position } => Err(Error::new(position, ErrorKind::Failed)),
+            _ => Err(Error::new(self.position, ErrorKind::Truncated)),
+        }
+    }
+
+    fn run<S: Sink>(&mut self, mut input: &[u8], sink: &mut S) -> Result<(), Error<S::Error>> {
+        loop {
+            self.state = match self.state {
+                State::Failed { position } => Err(Error::new(position, ErrorKind::Failed))?,
+
+                State::Literals {
+                    remaining: 0,
+                    match_len,
+                } => State::OffsetLo { match_len },
+
+                State::Literals {
+                    remaining,
+                    match_len,
+                } => {
+                    let n = remaining.min(input.len());
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    let (literals, rest) = input.split_at(n);
+                    sink.literal(literals)
+                        .map_err(|e| Error::new(self.position, ErrorKind::Sink(e)))?;
+                    input = rest;
+                    self.position += n;
+                    State::Literals {
+                        remaining: remaining - n,
+                        match_len,
+                    }
+                }
+
+                state => {
+                    let Some((&byte, rest)) = input.split_first() else {
+                        return Ok(());
+                    };
+                    input = rest;
+                    let state = Self::step(state, byte as usize, sink)
+                        .map_err(|kind| Error::new(self.position, kind))?;
+                    self.position += 1;
+                    state
+                }
+            };
+        }
+    }
+
+    fn step<S: Sink>(
+        state: State,
+        byte: usize,
+        sink: &mut S,
+    ) -> Result<State, ErrorKind<S::Error>> {
+        Ok(match state {
+            State::Token => {
+                let literal_len = byte >> 4;
+                let match_len = byte & 0x0f;
+                if literal_len == 15 {
+                    State::LiteralLength {
+                        length: literal_len,
+                        match_len,
+                    }
+                } else {
+                    State::Literals {
+                        remaining: literal_len,
+                        match_len,
+                    }
+                }
+            }
+
+            State::LiteralLength { length, match_len } => {
+                let length = length.checked_add(byte).ok_or(ErrorKind::LengthOverflow)?;
+                if byte == 255 {
+                    State::LiteralLength { length, match_len }
+                } else {
+                    State::Literals {
+                        remaining: length,
+                        match_len,
+                    }
+                }
+            }
+
+            State::OffsetLo { match_len } => State::OffsetHi {
+                lo: byte as u8,
+                match_len,
+            },
+
+            State::OffsetHi { lo, match_len } => {
+                let offset = (byte << 8) | lo as usize;
+                if offset == 0 {
+                    Err(ErrorKind::ZeroOffset)?;
+                }
+                if match_len == 15 {
+                    State::MatchLength {
+                        offset,
+                        length: match_len,
+                    }
+                } else {
+                    sink.backref(offset, match_len + 4)
+                        .map_err(ErrorKind::Sink)?;
+                    State::Token
+                }
+            }
+
+            State::MatchLength { offset, length } => {
+                let length = length.checked_add(byte).ok_or(ErrorKind::LengthOverflow)?;
+                if byte == 255 {
+                    State::MatchLength { offset, length }
+                } else {
+                    let length = length.checked_add(4).ok_or(ErrorKind::LengthOverflow)?;
+                    sink.backref(offset, length).map_err(ErrorKind::Sink)?;
+                    State::Token
+                }
+            }
+
+            State::Literals { .. } | State::Failed { .. } => unreachable!(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sink::{FlashSink, OutOfRange, SinkError};
+    use super::super::{Mode, compress, decompress};
+    use super::*;
+
+    use proptest::prelude::*;
+    use std::vec::Vec;
+
+    type Result<T> = core::result::Result<T, Error<SinkError<OutOfRange>>>;
+
+    fn oneshot(compressed: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+        let mut sink = FlashSink::new(Vec::new(), dict);
+        decompress(compressed, &mut sink)?;
+        Ok(sink.into_output())
+    }
+
+    fn chunked(compressed: &[u8], dict: &[u8], chunk: usize) -> Result<Vec<u8>> {
+        let mut sink = FlashSink::new(Vec::new(), dict);
+        let mut decoder = Decoder::new();
+        for c in compressed.chunks(chunk) {
+            decoder.feed(c, &mut sink)?;
+        }
+        // An empty chunk must not make a difference
+        decoder.feed(&[], &mut sink)?;
+        decoder.finish()?;
+        Ok(sink.into_output())
+    }
+
+    fn check(compressed: &[u8], dict: &[u8]) {
+        let expected = oneshot(compressed, dict);
+        for chunk in 1..=compressed.len().max(1) {
+            assert_eq!(chunked(compressed, dict, chunk), expected);
+        }
+    }
+
+    #[test]
+    fn lorem() {
+        check(include_bytes!("testdata/lorem1.lz4"), &[]);
+        check(
+            include_bytes!("testdata/lorem2.lz4"),
+            include_bytes!("testdata/lorem2.dct"),
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let compressed = include_bytes!("testdata/lorem1.lz4");
+        for len in 0..compressed.len() {
+            for chunk in [1, 7, 64] {
+                // The one-shot decoder may succeed if the input ends right after a run of
+                // literals, in which case the streaming decoder must agree. Errors, including
+                // their position, must be the same as well.
+                let expected = oneshot(&compressed[..len], &[]);
+                assert_eq!(chunked(&compressed[..len], &[], chunk), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn long_lengths() {
+        // Literal and match lengths well beyond 255 need several extension bytes
+        let mut data = Vec::new();
+        data.extend((0..600u32).map(|x| (x * 7 + x / 13) as u8));
+        data.extend(core::iter::repeat_n(0x55, 1500));
+        data.extend(b"trailing literals");
+
+        check(&compress(&data, &[], Mode::HighCompression), &[]);
+    }
+
+    #[test]
+    fn failed() {
+        // Back-reference before the start of the output, then more input
+        let mut sink = FlashSink::new(Vec::new(), &[]);
+        let mut decoder = Decoder::new();
+        let invalid = Error::new(3, ErrorKind::Sink(SinkError::InvalidOffset(2)));
+        assert_eq!(decoder.feed(b"\x10a\x02\x00", &mut sink), Err(invalid));
+
+        // The original position is kept, and the failure is not mistaken for truncation
+        let failed = Error::new(3, ErrorKind::Failed);
+        assert_eq!(decoder.feed(b"\x10b", &mut sink), Err(failed));
+        assert_eq!(decoder.finish(), Err(failed));
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary(input in prop::collection::vec(any::<u8>(), 0..256), chunk in 1..16usize) {
+            // On failure, the one-shot decoder may stop before emitting literals that the
+            // streaming decoder has already forwarded, but the error must be the same.
+            prop_assert_eq!(oneshot(&input, b"dictionary"), chunked(&input, b"dictionary", chunk));
+        }
+    }
+}
@@ -1,3 +1,5 @@
+import random
+import subprocess
 from typing import Optional
 
 import lz4.block
@@ -20,5 +22,25 @@ def create(name:str, data:bytes, dictionary:Optional[bytes]=None) -> None:
         fh.write(lz4.block.compress(data, dict=dictionary, mode='high_compression', compression=12, store_size=False))
 
 
+def create_frame(name:str, data:bytes, *args:str, dictionary:Optional[str]=None) -> None:
+    # Frames are produced with the reference lz4 command line tool
+    cmd = ['lz4', '-q', '-c', *args]
+    if dictionary:
+        cmd += ['-D', dictionary]
+    with open(f'{name}.lz4f', 'wb') as fh:
+        fh.write(subprocess.run(cmd, input=data, stdout=subprocess.PIPE, check=True).stdout)
+
+
 create('lorem1', lorem)
 create('lorem2', lorem, 'Iaculis massa nisl malesuada'.encode('utf-8'))
+
+rnd = random.Random(42)
+with open('random.dat', 'wb') as fh:
+    fh.write(bytes(rnd.getrandbits(8) for _ in range(600)))
+
+create_frame('empty', b'')
+create_frame('lorem1', lorem, '-12')
+create_frame('lorem2', lorem, '-12', dictionary='lorem2.dct')
+create_frame('lorem3', lorem, '-12', '-B128', '-BD', '-BX', '--content-size')
+with open('random.dat', 'rb') as fh:
+    create_frame('random', fh.read(), '-BX', '--content-size')
@@ -0,0 +1,151 @@
+//! Streaming xxHash32, as used by the LZ4 frame format
+
+const PRIME1: u32 = 0x9e37_79b1;
+const PRIME2: u32 = 0x85eb_ca77;
+const PRIME3: u32 = 0xc2b2_ae3d;
+const PRIME4: u32 = 0x27d4_eb2f;
+const PRIME5: u32 = 0x1656_67b1;
+
+pub struct XxHash32 {
+    acc: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+    total: u32,
+    large: bool,
+    seed: u32,
+}
+
+#[inline]
+fn round(acc: u32, lane: u32) -> u32 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME2))
+        .rotate_left(13)
+        .wrapping_mul(PRIME1)
+}
+
+#[inline]
+fn lane(data: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes([data[idx], data[idx + 1], data[idx + 2], data[idx + 3]])
+}
+
+impl XxHash32 {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            acc: [
+                seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+                seed.wrapping_add(PRIME2),
+                seed,
+                seed.wrapping_sub(PRIME1),
+            ],
+            buffer: [0; 16],
+            buffered: 0,
+            total: 0,
+            large: false,
+            seed,
+        }
+    }
+
+    pub fn checksum(data: &[u8]) -> u32 {
+        let mut h = Self::new(0);
+        h.update(data);
+        h.finish()
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (i, acc) in self.acc.iter_mut().enumerate() {
+            *acc = round(*acc, lane(stripe, i * 4));
+        }
+        self.large = true;
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total = self.total.wrapping_add(data.len() as u32);
+
+        if self.buffered > 0 {
+            let n = data.len().min(16 - self.buffered);
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+
+            if self.buffered < 16 {
+                return;
+            }
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+
+        let mut stripes = data.chunks_exact(16);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub fn finish(&self) -> u32 {
+        let mut h = if self.large {
+            self.acc[0]
+                .rotate_left(1)
+                .wrapping_add(self.acc[1].rotate_left(7))
+                .wrapping_add(self.acc[2].rotate_left(12))
+                .wrapping_add(self.acc[3].rotate_left(18))
+        } else {
+            self.seed.wrapping_add(PRIME5)
+        };
+
+        h = h.wrapping_add(self.total);
+
+        let mut tail = self.buffer[..self.buffered].chunks_exact(4);
+        for word in &mut tail {
+            h = h
+                .wrapping_add(lane(word, 0).wrapping_mul(PRIME3))
+                .rotate_left(17)
+                .wrapping_mul(PRIME4);
+        }
+        for b in tail.remainder() {
+            h = h
+                .wrapping_add((*b as u32).wrapping_mul(PRIME5))
+                .rotate_left(11)
+                .wrapping_mul(PRIME1);
+        }
+
+        h ^= h >> 15;
+        h = h.wrapping_mul(PRIME2);
+        h ^= h >> 13;
+        h = h.wrapping_mul(PRIME3);
+        h ^= h >> 16;
+        h
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference() {
+        assert_eq!(XxHash32::checksum(b""), 0x02cc_5d05);
+        assert_eq!(XxHash32::checksum(b"a"), 0x550d_7456);
+        assert_eq!(XxHash32::checksum(b"abc"), 0x32d1_53ff);
+        assert_eq!(
+            XxHash32::checksum(b"Nobody inspects the spammish repetition"),
+            0xe229_3b2f
+        );
+    }
+
+    #[test]
+    fn streaming() {
+        let data = include_bytes!("testdata/lorem1.dat");
+        let expected = XxHash32::checksum(data);
+
+        for step in [1, 3, 16, 17, 100] {
+            let mut h = XxHash32::new(0);
+            for chunk in data.chunks(step) {
+                h.update(chunk);
+            }
+            assert_eq!(h.finish(), expected);
+        }
+    }
+}
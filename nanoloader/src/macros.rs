@@ -0,0 +1,31 @@
+//! Logging that compiles out without the `log` feature
+//!
+//! The arguments are still type-checked, so that both configurations build the same code.
+
+#[cfg(feature = "log")]
+macro_rules! info {
+    ($($arg:tt)+) => { log::info!($($arg)+) };
+}
+
+#[cfg(feature = "log")]
+macro_rules! warn {
+    ($($arg:tt)+) => { log::warn!($($arg)+) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
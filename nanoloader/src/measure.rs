@@ -0,0 +1,188 @@
+//! Measured boot
+//!
+//! Right before booting, the bootloader hands the verified firmware image to a [`Measure`]
+//! hook. With the `measured` feature, [`Dice`] measures it with SHA-256, derives a compound
+//! device identifier (CDI) from the unique device secret (UDS) and the measurement, DICE-style,
+//! locks the UDS away until the next reset and leaves both in a [`Handover`] for the application.
+
+use crate::NanoResult;
+
+/// Measurement of the firmware about to be booted
+pub trait Measure {
+    /// Called with the load address and the verified firmware image, before booting it
+    fn measure(&mut self, load_address: usize, image: &[u8]) -> NanoResult;
+}
+
+/// No measurement
+impl Measure for () {
+    fn measure(&mut self, _load_address: usize, _image: &[u8]) -> NanoResult {
+        crate::OK
+    }
+}
+
+/// Measurement results passed to the application, in RAM that is not initialized by it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Handover {
+    /// [`Handover::MAGIC`] once written by the bootloader
+    pub magic: u32,
+    /// Size of the measured firmware image
+    pub fwsize: u32,
+    /// SHA-256 over the load address (32-bit, little-endian) and the firmware image
+    pub measurement: [u8; 32],
+    /// Compound device identifier: HMAC-SHA256 over the measurement, keyed with the UDS
+    pub cdi: [u8; 32],
+}
+
+impl Handover {
+    pub const MAGIC: u32 = 0x4e4c_4d42; // "NLMB"
+
+    /// Take the handover left by the bootloader, and clear it so that the CDI does not linger
+    ///
+    /// # Safety
+    ///
+    /// `handover` must point to the RAM reserved for the handover.
+    pub unsafe fn take(handover: *mut Handover) -> Option<Handover> {
+        // SAFETY: Guaranteed by caller
+        unsafe {
+            let taken = core::ptr::read_volatile(handover);
+            wipe(handover);
+            (taken.magic == Self::MAGIC).then_some(taken)
+        }
+    }
+}
+
+/// Overwrite `value` with zeroes, in a way that is not optimized away
+///
+/// # Safety
+///
+/// `value` must be valid for writes, and all zeroes must be a valid `T`.
+unsafe fn wipe<T>(value: *mut T) {
+    let bytes = value as *mut u8;
+    for i in 0..size_of::<T>() {
+        // SAFETY: Guaranteed by caller
+        unsafe { core::ptr::write_volatile(bytes.add(i), 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+/// Unique device secret, provided by the HAL
+pub trait DeviceSecret {
+    /// Read the secret into `uds`
+    fn read(&mut self, uds: &mut [u8; 32]) -> NanoResult;
+
+    /// Make the secret inaccessible until the next reset (e.g. by setting a read-protection bit)
+    fn lock(&mut self);
+}
+
+/// Stack cleared after [`cdi`] returns, with headroom over the under 1K it uses on Cortex-M
+#[cfg(feature = "measured")]
+const CDI_STACK: usize = 1536;
+
+/// HMAC-SHA256 over `measurement`, keyed with `uds`, which is wiped afterwards
+///
+/// The key blocks and hash state are left on the stack below this frame, for [`scrub_stack`] to
+/// clear.
+#[cfg(feature = "measured")]
+#[inline(never)]
+fn cdi(measurement: &[u8; 32], uds: &mut [u8; 32]) -> [u8; 32] {
+    let cdi = hmac_sha256::HMAC::mac(measurement, &uds[..]);
+    // SAFETY: Exclusive reference
+    unsafe { wipe(uds) };
+    cdi
+}
+
+/// Clear the stack below the caller's frame, where [`cdi`] ran
+#[cfg(feature = "measured")]
+#[inline(never)]
+fn scrub_stack() {
+    let mut stack = [0u8; CDI_STACK];
+    // SAFETY: Local array
+    unsafe { wipe(&mut stack) };
+}
+
+/// DICE-style measured boot, leaving the results at `handover`
+#[cfg(feature = "measured")]
+pub struct Dice<S> {
+    pub secret: S,
+    pub handover: *mut Handover,
+}
+
+#[cfg(feature = "measured")]
+impl<S: DeviceSecret> Measure for Dice<S> {
+    fn measure(&mut self, load_address: usize, image: &[u8]) -> NanoResult {
+        let mut hash = hmac_sha256::Hash::new();
+        hash.update((load_address as u32).to_le_bytes());
+        hash.update(image);
+        let measurement = hash.finalize();
+
+        let mut uds = [0; 32];
+        let read = self.secret.read(&mut uds);
+        let cdi = cdi(&measurement, &mut uds);
+        scrub_stack();
+        self.secret.lock();
+        read?;
+
+        info!("Firmware measured");
+
+        let handover = Handover {
+            magic: Handover::MAGIC,
+            fwsize: image.len() as u32,
+            measurement,
+            cdi,
+        };
+        // SAFETY: The HAL reserved the RAM for the handover
+        unsafe { core::ptr::write_volatile(self.handover, handover) };
+        crate::OK
+    }
+}
+
+#[cfg(all(test, feature = "measured"))]
+mod tests {
+    use super::*;
+
+    struct TestSecret(bool);
+
+    impl DeviceSecret for TestSecret {
+        fn read(&mut self, uds: &mut [u8; 32]) -> NanoResult {
+            assert!(!self.0);
+            uds.fill(0x5a);
+            crate::OK
+        }
+
+        fn lock(&mut self) {
+            self.0 = true;
+        }
+    }
+
+    #[test]
+    fn dice() {
+        let mut handover = core::mem::MaybeUninit::<Handover>::zeroed();
+        let image = [1, 2, 3, 4, 5];
+
+        let mut dice = Dice {
+            secret: TestSecret(false),
+            handover: handover.as_mut_ptr(),
+        };
+        dice.measure(0x4000, &image).unwrap();
+        assert!(dice.secret.0);
+
+        let taken = unsafe { Handover::take(handover.as_mut_ptr()) }.unwrap();
+        assert_eq!(taken.fwsize, 5);
+        assert_eq!(
+            taken.measurement,
+            hmac_sha256::Hash::hash(&[0x00, 0x40, 0, 0, 1, 2, 3, 4, 5])
+        );
+        assert_eq!(
+            taken.cdi,
+            hmac_sha256::HMAC::mac(taken.measurement, [0x5a; 32])
+        );
+
+        // Single use, and a different image gives a different CDI
+        assert!(unsafe { Handover::take(handover.as_mut_ptr()) }.is_none());
+        dice.secret.0 = false;
+        dice.measure(0x4000, &image[1..]).unwrap();
+        let other = unsafe { Handover::take(handover.as_mut_ptr()) }.unwrap();
+        assert_ne!(other.cdi, taken.cdi);
+    }
+}
@@ -0,0 +1,505 @@
+//! Update pointer storage
+//!
+//! HALs keep the addresses of pending updates in an options page, where applications program
+//! them. Slots are used in order; the bootloader skips cleared slots and acts on the first one
+//! that is not. The pending slots following it form a queue, which the bootloader works through
+//! in order, clearing each slot once its update is done. Once all slots are cleared, the page
+//! has to be erased before it can be used again, which [`Banked`] storage does without ever
+//! losing a pending update.
+
+use core::marker::PhantomData;
+
+/// State of an update pointer slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// Erased, available for a new update pointer
+    Free,
+    /// Update was processed
+    Cleared,
+    /// Update is pending
+    Pending(usize),
+}
+
+/// Flash page holding update pointers
+pub trait OptionsFlash {
+    type Error;
+
+    /// Contents of the page, memory-mapped
+    fn page(&self) -> &[u8];
+
+    /// Check whether the 64-bit word at `offset` is erased
+    ///
+    /// Devices with ECC should override this with a hardware blank check.
+    fn is_blank(&self, offset: usize) -> Result<bool, Self::Error> {
+        Ok(read::<8>(self.page(), offset) == [0xff; 8])
+    }
+
+    /// Program data at `offset`, which is aligned to the length of the data
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn erase(&mut self) -> Result<(), Self::Error>;
+}
+
+pub(crate) fn read<const N: usize>(page: &[u8], offset: usize) -> [u8; N] {
+    let mut buf = [0; N];
+    for (i, b) in buf.iter_mut().enumerate() {
+        // SAFETY: Reference to an element of the page; Flash may change when programmed
+        *b = unsafe { core::ptr::read_volatile(&page[offset + i]) };
+    }
+    buf
+}
+
+/// Encoding of a single update pointer
+pub trait Format {
+    /// Size of a slot in bytes
+    const SLOT_SZ: usize;
+
+    fn slot<F: OptionsFlash>(flash: &F, offset: usize) -> Result<Slot, F::Error>;
+    fn set<F: OptionsFlash>(flash: &mut F, offset: usize, address: usize) -> Result<(), F::Error>;
+    fn clear<F: OptionsFlash>(flash: &mut F, offset: usize) -> Result<(), F::Error>;
+}
+
+/// Single 32-bit word per update, as used by the Test Loader
+///
+/// Erased words (0xffffffff) are free, zero words are cleared and any other value is the address
+/// of a pending update.
+pub struct Word;
+
+impl Format for Word {
+    const SLOT_SZ: usize = size_of::<u32>();
+
+    fn slot<F: OptionsFlash>(flash: &F, offset: usize) -> Result<Slot, F::Error> {
+        Ok(match u32::from_le_bytes(read(flash.page(), offset)) {
+            u32::MAX => Slot::Free,
+            0 => Slot::Cleared,
+            address => Slot::Pending(address as usize),
+        })
+    }
+
+    fn set<F: OptionsFlash>(flash: &mut F, offset: usize, address: usize) -> Result<(), F::Error> {
+        flash.program(offset, &(address as u32).to_le_bytes())
+    }
+
+    fn clear<F: OptionsFlash>(flash: &mut F, offset: usize) -> Result<(), F::Error> {
+        flash.program(offset, &[0; 4])
+    }
+}
+
+/// Pair of 64-bit words per update, as used by the MSPM0C Loader
+///
+/// The first word holds the address and the second word is programmed to zero once the update
+/// has been processed. Erased words are detected with [`OptionsFlash::is_blank`].
+pub struct Pair;
+
+impl Format for Pair {
+    const SLOT_SZ: usize = 2 * size_of::<u64>();
+
+    fn slot<F: OptionsFlash>(flash: &F, offset: usize) -> Result<Slot, F::Error> {
+        Ok(if flash.is_blank(offset)? {
+            Slot::Free
+        } else if flash.is_blank(offset + size_of::<u64>())? {
+            Slot::Pending(u64::from_le_bytes(read(flash.page(), offset)) as usize)
+        } else {
+            Slot::Cleared
+        })
+    }
+
+    fn set<F: OptionsFlash>(flash: &mut F, offset: usize, address: usize) -> Result<(), F::Error> {
+        flash.program(offset, &(address as u64).to_le_bytes())
+    }
+
+    fn clear<F: OptionsFlash>(flash: &mut F, offset: usize) -> Result<(), F::Error> {
+        flash.program(offset + size_of::<u64>(), &[0; 8])
+    }
+}
+
+/// Update pointer storage
+pub trait UpdatePointers {
+    type Error;
+
+    /// Total number of slots
+    fn slots(&self) -> usize;
+
+    fn slot(&self, index: usize) -> Result<Slot, Self::Error>;
+
+    /// Program an update pointer into a free slot
+    fn set(&mut self, index: usize, address: usize) -> Result<(), Self::Error>;
+
+    /// Mark a pending slot as cleared
+    fn clear(&mut self, index: usize) -> Result<(), Self::Error>;
+
+    /// Reclaim cleared slots, keeping pending updates pending and in order
+    ///
+    /// Returns whether any slots were reclaimed.
+    fn compact(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Index and address of the pending update, if any
+    fn pending(&self) -> Result<Option<(usize, usize)>, Self::Error> {
+        self.queued(0)
+    }
+
+    /// Index and address of the update at `position` in the queue, the pending one being first
+    fn queued(&self, position: usize) -> Result<Option<(usize, usize)>, Self::Error> {
+        let mut found = 0;
+        for index in 0..self.slots() {
+            match self.slot(index)? {
+                Slot::Cleared => continue,
+                Slot::Pending(address) if found == position => return Ok(Some((index, address))),
+                Slot::Pending(_) => found += 1,
+                Slot::Free => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Number of slots following the cleared ones
+    fn remaining(&self) -> Result<usize, Self::Error> {
+        let slots = self.slots();
+        for index in 0..slots {
+            if self.slot(index)? != Slot::Cleared {
+                return Ok(slots - index);
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Update pointers in a single options page, which cannot be compacted safely
+pub struct Single<F: OptionsFlash, T: Format> {
+    flash: F,
+    _format: PhantomData<T>,
+}
+
+impl<F: OptionsFlash, T: Format> Single<F, T> {
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            _format: PhantomData,
+        }
+    }
+
+    pub fn into_flash(self) -> F {
+        self.flash
+    }
+}
+
+impl<F: OptionsFlash, T: Format> UpdatePointers for Single<F, T> {
+    type Error = F::Error;
+
+    fn slots(&self) -> usize {
+        self.flash.page().len() / T::SLOT_SZ
+    }
+
+    fn slot(&self, index: usize) -> Result<Slot, Self::Error> {
+        T::slot(&self.flash, index * T::SLOT_SZ)
+    }
+
+    fn set(&mut self, index: usize, address: usize) -> Result<(), Self::Error> {
+        T::set(&mut self.flash, index * T::SLOT_SZ, address)
+    }
+
+    fn clear(&mut self, index: usize) -> Result<(), Self::Error> {
+        T::clear(&mut self.flash, index * T::SLOT_SZ)
+    }
+}
+
+/// Update pointers in two alternating options pages
+///
+/// The last 16 bytes of each page hold a header with a 64-bit sequence number followed by its
+/// complement, and the page with the highest valid sequence number is active. An erased header
+/// counts as sequence number 0, and the first page wins a tie. This way, a pair of erased pages
+/// is ready to use, and a page previously used as [`Single`] storage (with its last slots unused)
+/// remains active.
+///
+/// Compaction erases the inactive page, copies the pending update pointers into it in order and
+/// only then writes its header. Until the header is complete, the previous page remains active.
+pub struct Banked<F: OptionsFlash, T: Format> {
+    pages: [F; 2],
+    active: usize,
+    _format: PhantomData<T>,
+}
+
+impl<F: OptionsFlash, T: Format> Banked<F, T> {
+    const HEADER_SZ: usize = 2 * size_of::<u64>();
+
+    pub fn new(pages: [F; 2]) -> Result<Self, F::Error> {
+        let seq0 = Self::sequence(&pages[0])?;
+        let seq1 = Self::sequence(&pages[1])?;
+        Ok(Self {
+            active: if seq1 > seq0 { 1 } else { 0 },
+            pages,
+            _format: PhantomData,
+        })
+    }
+
+    pub fn into_pages(self) -> [F; 2] {
+        self.pages
+    }
+
+    /// Index of the active page
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Sequence number of a page, `None` if its header is invalid
+    fn sequence(page: &F) -> Result<Option<u64>, F::Error> {
+        let offset = page.page().len() - Self::HEADER_SZ;
+        if page.is_blank(offset)? && page.is_blank(offset + size_of::<u64>())? {
+            return Ok(Some(0));
+        }
+        let seq = u64::from_le_bytes(read(page.page(), offset));
+        let check = u64::from_le_bytes(read(page.page(), offset + size_of::<u64>()));
+        Ok((seq == !check).then_some(seq))
+    }
+}
+
+impl<F: OptionsFlash, T: Format> UpdatePointers for Banked<F, T> {
+    type Error = F::Error;
+
+    fn slots(&self) -> usize {
+        (self.pages[self.active].page().len() - Self::HEADER_SZ) / T::SLOT_SZ
+    }
+
+    fn slot(&self, index: usize) -> Result<Slot, Self::Error> {
+        T::slot(&self.pages[self.active], index * T::SLOT_SZ)
+    }
+
+    fn set(&mut self, index: usize, address: usize) -> Result<(), Self::Error> {
+        T::set(&mut self.pages[self.active], index * T::SLOT_SZ, address)
+    }
+
+    fn clear(&mut self, index: usize) -> Result<(), Self::Error> {
+        T::clear(&mut self.pages[self.active], index * T::SLOT_SZ)
+    }
+
+    fn compact(&mut self) -> Result<bool, Self::Error> {
+        let slots = self.slots();
+        let cleared = slots - self.remaining()?;
+        if cleared == 0 {
+            return Ok(false);
+        }
+
+        let seq = Self::sequence(&self.pages[self.active])?.unwrap_or(0) + 1;
+        let next = 1 - self.active;
+        let [first, second] = &mut self.pages;
+        let (active, page) = match next {
+            0 => (&*second, first),
+            _ => (&*first, second),
+        };
+
+        page.erase()?;
+        for index in cleared..slots {
+            let Slot::Pending(address) = T::slot(active, index * T::SLOT_SZ)? else {
+                break;
+            };
+            T::set(page, (index - cleared) * T::SLOT_SZ, address)?;
+        }
+        let offset = page.page().len() - Self::HEADER_SZ;
+        page.program(offset, &seq.to_le_bytes())?;
+        page.program(offset + size_of::<u64>(), &(!seq).to_le_bytes())?;
+
+        self.active = next;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    /// Options page in RAM that fails once its operation budget is used up
+    ///
+    /// The failing operation is left half done: an erase only erases the first half of the
+    /// page, and programming only clears half of the bits to be cleared.
+    struct RamFlash {
+        data: Vec<u8>,
+        budget: Rc<Cell<usize>>,
+    }
+
+    impl RamFlash {
+        fn new(size: usize, budget: &Rc<Cell<usize>>) -> Self {
+            Self {
+                data: std::vec![0xff; size],
+                budget: budget.clone(),
+            }
+        }
+
+        fn spend(&self) -> Result<(), ()> {
+            match self.budget.get() {
+                0 => Err(()),
+                n => {
+                    self.budget.set(n - 1);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    impl OptionsFlash for RamFlash {
+        type Error = ();
+
+        fn page(&self) -> &[u8] {
+            &self.data
+        }
+
+        fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), ()> {
+            let failed = self.spend().is_err();
+            let target = &mut self.data[offset..offset + data.len()];
+            if failed {
+                for (i, (t, d)) in target.iter_mut().zip(data).enumerate() {
+                    *t &= if i % 2 == 0 { *d } else { 0xff };
+                }
+                return Err(());
+            }
+            for (t, d) in target.iter_mut().zip(data) {
+                assert_eq!(*t & d, *d, "programming must only clear bits");
+                *t = *d;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self) -> Result<(), ()> {
+            if self.spend().is_err() {
+                let half = self.data.len() / 2;
+                self.data[..half].fill(0xff);
+                self.data[half..].fill(0x5a);
+                return Err(());
+            }
+            self.data.fill(0xff);
+            Ok(())
+        }
+    }
+
+    fn banked<T: Format>(pages: [RamFlash; 2]) -> Banked<RamFlash, T> {
+        Banked::new(pages).unwrap()
+    }
+
+    #[test]
+    fn single() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut words = Single::<_, Word>::new(RamFlash::new(16, &budget));
+        assert_eq!(words.slots(), 4);
+        words.set(0, 0x1234).unwrap();
+        words.clear(0).unwrap();
+        words.set(1, 0x5678).unwrap();
+        assert_eq!(words.pending(), Ok(Some((1, 0x5678))));
+        assert_eq!(words.remaining(), Ok(3));
+        assert_eq!(words.compact(), Ok(false));
+        assert_eq!(
+            words.into_flash().data,
+            [
+                0, 0, 0, 0, 0x78, 0x56, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+            ]
+        );
+    }
+
+    fn compact<T: Format>() {
+        let budget = Rc::new(Cell::new(usize::MAX));
+        let mut pointers =
+            banked::<T>([RamFlash::new(1024, &budget), RamFlash::new(1024, &budget)]);
+        let slots = pointers.slots();
+
+        // Nothing to reclaim
+        assert_eq!(pointers.compact(), Ok(false));
+
+        for round in 0..3 {
+            for index in 0..slots {
+                pointers.set(index, 0x1000 + round).unwrap();
+                pointers.clear(index).unwrap();
+            }
+            assert_eq!(pointers.remaining(), Ok(0));
+            assert_eq!(pointers.compact(), Ok(true));
+            assert_eq!(pointers.remaining(), Ok(slots));
+        }
+
+        // Pending update is preserved
+        pointers.set(0, 0x2000).unwrap();
+        pointers.clear(0).unwrap();
+        pointers.set(1, 0x3000).unwrap();
+        assert_eq!(pointers.compact(), Ok(true));
+        assert_eq!(pointers.pending(), Ok(Some((0, 0x3000))));
+        assert_eq!(pointers.remaining(), Ok(slots));
+
+        // So is a queue, in order
+        pointers.clear(0).unwrap();
+        pointers.set(1, 0x4000).unwrap();
+        pointers.set(2, 0x5000).unwrap();
+        assert_eq!(pointers.compact(), Ok(true));
+        assert_eq!(pointers.queued(0), Ok(Some((0, 0x4000))));
+        assert_eq!(pointers.queued(1), Ok(Some((1, 0x5000))));
+        assert_eq!(pointers.queued(2), Ok(None));
+        assert_eq!(pointers.slot(2), Ok(Slot::Free));
+    }
+
+    #[test]
+    fn compact_word() {
+        compact::<Word>();
+    }
+
+    #[test]
+    fn compact_pair() {
+        compact::<Pair>();
+    }
+
+    fn power_fail<T: Format>() {
+        let queue = |pointers: &Banked<RamFlash, T>| {
+            (0..)
+                .map_while(|position| pointers.queued(position).unwrap())
+                .map(|(_, address)| address)
+                .collect::<Vec<_>>()
+        };
+
+        for pending in [&[][..], &[0x3000], &[0x3000, 0x4000]] {
+            for fail_after in 0.. {
+                let budget = Rc::new(Cell::new(usize::MAX));
+                let mut pointers =
+                    banked::<T>([RamFlash::new(1024, &budget), RamFlash::new(1024, &budget)]);
+
+                // Fill the first page and compact into the second one, so that the next
+                // compaction has to erase a used page
+                let slots = pointers.slots();
+                for index in 0..slots {
+                    pointers.set(index, 0x1000).unwrap();
+                    pointers.clear(index).unwrap();
+                }
+                pointers.compact().unwrap();
+                let cleared = slots - pending.len().max(1);
+                for index in 0..cleared {
+                    pointers.set(index, 0x2000).unwrap();
+                    pointers.clear(index).unwrap();
+                }
+                for (i, &address) in pending.iter().enumerate() {
+                    pointers.set(cleared + i, address).unwrap();
+                }
+
+                budget.set(fail_after);
+                let result = pointers.compact();
+
+                // Whatever happened, the queue is the same after a reset
+                let pointers = banked::<T>(pointers.into_pages());
+                assert_eq!(queue(&pointers), pending);
+
+                if result.is_ok() {
+                    assert_eq!(pointers.remaining(), Ok(slots));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn power_fail_word() {
+        power_fail::<Word>();
+    }
+
+    #[test]
+    fn power_fail_pair() {
+        power_fail::<Pair>();
+    }
+}
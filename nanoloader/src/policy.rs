@@ -0,0 +1,96 @@
+//! Update policy
+//!
+//! Before installing a pending update, the bootloader asks `Reporter::update_policy` for a
+//! [`Decision`]. The HAL can defer the update, e.g. while the battery is low or outside a
+//! maintenance window, or reject it for good, e.g. when it is for another hardware revision. The
+//! decision is passed to `Reporter::record_decision`, which can leave it in a RAM word for the
+//! application with [`record`].
+
+/// Outcome of the update policy, with a reason code defined by the HAL for the application
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Install the update now
+    Install,
+    /// Keep the update pending, and boot the current firmware
+    Defer(u16),
+    /// Clear the update without installing it
+    Reject(u16),
+}
+
+/// Marker in the top byte of a recorded decision
+const MARKER: u32 = 0x4e; // "N"
+
+impl Decision {
+    /// Encoding as a single word: marker, verdict and reason code
+    pub fn to_word(self) -> u32 {
+        let (verdict, reason) = match self {
+            Decision::Install => (0, 0),
+            Decision::Defer(reason) => (1, reason),
+            Decision::Reject(reason) => (2, reason),
+        };
+        (MARKER << 24) | (verdict << 16) | reason as u32
+    }
+
+    /// Decode a word written by [`Decision::to_word`]
+    pub fn from_word(word: u32) -> Option<Decision> {
+        if word >> 24 != MARKER {
+            return None;
+        }
+        let reason = word as u16;
+        match (word >> 16) as u8 {
+            0 if reason == 0 => Some(Decision::Install),
+            1 => Some(Decision::Defer(reason)),
+            2 => Some(Decision::Reject(reason)),
+            _ => None,
+        }
+    }
+}
+
+/// Record `decision` for the application
+///
+/// # Safety
+///
+/// `word` must point to RAM that is not otherwise used by the bootloader or the application.
+pub unsafe fn record(word: *mut u32, decision: Decision) {
+    // SAFETY: Guaranteed by caller
+    unsafe { core::ptr::write_volatile(word, decision.to_word()) };
+}
+
+/// Take the decision recorded by the bootloader on the last reset, if it found an update
+///
+/// # Safety
+///
+/// `word` must point to the RAM word the bootloader records decisions in.
+pub unsafe fn take(word: *mut u32) -> Option<Decision> {
+    // SAFETY: Guaranteed by caller
+    unsafe {
+        let decision = Decision::from_word(core::ptr::read_volatile(word));
+        core::ptr::write_volatile(word, 0);
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words() {
+        for decision in [
+            Decision::Install,
+            Decision::Defer(0),
+            Decision::Defer(0xffff),
+            Decision::Reject(42),
+        ] {
+            assert_eq!(Decision::from_word(decision.to_word()), Some(decision));
+        }
+        assert_eq!(Decision::from_word(0), None);
+        assert_eq!(Decision::from_word(0x4e00_0001), None);
+        assert_eq!(Decision::from_word(0x4e03_0000), None);
+
+        let mut word = 0;
+        unsafe { record(&mut word, Decision::Reject(7)) };
+        assert_eq!(unsafe { take(&mut word) }, Some(Decision::Reject(7)));
+        assert_eq!(unsafe { take(&mut word) }, None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Installation progress
+//!
+//! While an update is installed, the bootloader reports its progress to `Reporter::progress`.
+//! Pages are erased by the [`FlashProgrammer`] as they are programmed, so erasing is part of the
+//! [`Phase::Program`] phase, which is reported once per page.
+
+use crate::{FlashProgrammer, NanoResult, Reporter};
+
+/// Installation phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Checking the checksum of the update, in bytes
+    Verify,
+    /// Erasing and programming the firmware area, in bytes of new firmware
+    Program,
+    /// Checking the new firmware, in bytes
+    VerifyAfter,
+}
+
+/// Programmer that reports progress at the start of each page, and once finished
+pub(crate) struct Tracked<'a, P, R> {
+    programmer: &'a mut P,
+    reporter: &'a mut R,
+    page_sz: usize,
+    written: usize,
+    total: usize,
+}
+
+impl<'a, P: FlashProgrammer, R: Reporter> Tracked<'a, P, R> {
+    /// Track programming `total` bytes, in pages of `page_sz` bytes (a power of two)
+    pub(crate) fn new(
+        programmer: &'a mut P,
+        reporter: &'a mut R,
+        page_sz: usize,
+        total: usize,
+    ) -> Self {
+        Tracked {
+            programmer,
+            reporter,
+            page_sz,
+            written: 0,
+            total,
+        }
+    }
+}
+
+impl<P: FlashProgrammer, R: Reporter> FlashProgrammer for Tracked<'_, P, R> {
+    fn program_start(&mut self) -> NanoResult {
+        self.written = 0;
+        self.programmer.program_start()
+    }
+
+    fn program_write(&mut self, value: u8) -> NanoResult {
+        if self.written & (self.page_sz - 1) == 0 {
+            self.reporter
+                .progress(Phase::Program, self.written, self.total);
+        }
+        self.programmer.program_write(value)?;
+        self.written += 1;
+        crate::OK
+    }
+
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        self.programmer.program_read(offset)
+    }
+
+    fn program_finish(&mut self) -> NanoResult {
+        self.programmer.program_finish()?;
+        self.reporter
+            .progress(Phase::Program, self.written, self.total);
+        crate::OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NanoReason;
+    use std::vec::Vec;
+
+    struct NullProgrammer;
+
+    impl FlashProgrammer for NullProgrammer {
+        fn program_start(&mut self) -> NanoResult {
+            crate::OK
+        }
+
+        fn program_write(&mut self, _value: u8) -> NanoResult {
+            crate::OK
+        }
+
+        fn program_read(&mut self, _offset: usize) -> NanoResult<u8> {
+            Ok(0)
+        }
+
+        fn program_finish(&mut self) -> NanoResult {
+            crate::OK
+        }
+    }
+
+    struct Recorder(Vec<(Phase, usize, usize)>);
+
+    impl Reporter for Recorder {
+        fn abort(&mut self, reason: NanoReason) -> ! {
+            panic!("{reason:?}")
+        }
+
+        fn progress(&mut self, phase: Phase, done: usize, total: usize) {
+            self.0.push((phase, done, total));
+        }
+    }
+
+    #[test]
+    fn pages() {
+        let mut programmer = NullProgrammer;
+        let mut reporter = Recorder(Vec::new());
+        let mut tracked = Tracked::new(&mut programmer, &mut reporter, 256, 600);
+        tracked.program_start().unwrap();
+        for _ in 0..600 {
+            tracked.program_write(0).unwrap();
+        }
+        tracked.program_finish().unwrap();
+
+        let phase = Phase::Program;
+        assert_eq!(
+            reporter.0,
+            [
+                (phase, 0, 600),
+                (phase, 256, 600),
+                (phase, 512, 600),
+                (phase, 600, 600)
+            ]
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! Recovery requests from the application
+//!
+//! Before resetting, the application can write [`MAGIC`] to a RAM word that is not initialized
+//! by the bootloader, to make the bootloader stay in recovery mode instead of booting it.
+
+/// Value of the recovery word requesting recovery mode
+pub const MAGIC: u32 = 0x4e4c_5243; // "NLRC"
+
+/// Check for a recovery request and clear it, so that it only applies to a single reset
+///
+/// # Safety
+///
+/// `word` must point to RAM that is not otherwise used by the bootloader.
+pub unsafe fn take_request(word: *mut u32) -> bool {
+    // SAFETY: Guaranteed by caller
+    unsafe {
+        let requested = core::ptr::read_volatile(word) == MAGIC;
+        core::ptr::write_volatile(word, 0);
+        requested
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_use() {
+        let mut word = MAGIC;
+        assert!(unsafe { take_request(&mut word) });
+        assert_eq!(word, 0);
+        assert!(!unsafe { take_request(&mut word) });
+    }
+}
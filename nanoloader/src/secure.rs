@@ -0,0 +1,90 @@
+//! Handoff to a non-secure application on ARMv8-M Mainline (Cortex-M33)
+//!
+//! The bootloader runs in the Secure state. It either boots the firmware as the non-secure
+//! application with [`Nano::boot_non_secure`], or boots a secure image as usual, which then starts
+//! the non-secure application with [`bootload_ns`]. Either way, the memory used by the application
+//! is made non-secure by an [`Attribution`] first.
+//!
+//! This requires a `thumbv8m.main` target; ARMv8-M Baseline (`thumbv8m.base`) is not supported.
+//!
+//! [`Nano::boot_non_secure`]: crate::Nano::boot_non_secure
+
+use crate::NanoResult;
+
+pub use cortex_m::peripheral::sau::{SauError, SauRegion, SauRegionAttribute};
+
+/// Security attribution of the memory used by the non-secure application
+pub trait Attribution {
+    /// Configure the SAU, and any implementation defined attribution (IDAU, TrustZone
+    /// controllers), so that the application's code, data and peripherals are non-secure
+    fn configure(&mut self) -> NanoResult;
+}
+
+/// Program and enable the SAU with the given regions, all other memory stays secure
+pub fn sau(regions: &[SauRegion]) -> Result<(), SauError> {
+    // SAFETY: Only used by the bootloader in the Secure state, before the handoff
+    let mut sau = unsafe { cortex_m::Peripherals::steal() }.SAU;
+    sau.init(regions)
+}
+
+/// Save and clear the secure floating point state, on the stack that is left behind
+#[cfg(target_abi = "eabihf")]
+macro_rules! clear_fp {
+    () => {
+        "sub sp, #0x88\nvlstm sp"
+    };
+}
+
+#[cfg(not(target_abi = "eabihf"))]
+macro_rules! clear_fp {
+    () => {
+        ""
+    };
+}
+
+/// Start the non-secure application with the vector table at `vector_table`
+///
+/// This sets `VTOR_NS` and `MSP_NS` from the vector table, and enters the reset handler with
+/// `BXNS`. The general purpose registers and the flags are cleared first, as are the floating
+/// point registers on hard-float targets (soft-float code never uses them), so no secure state
+/// is left to the application in registers.
+///
+/// # Safety
+///
+/// The application must be valid, and its memory must have been made non-secure.
+pub unsafe fn bootload_ns(vector_table: usize) -> ! {
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+
+    // SAFETY: Guaranteed by caller
+    unsafe {
+        let vectors = vector_table as *const u32;
+        let scb_ns = cortex_m::Peripherals::steal().SCBNS;
+        scb_ns.vtor.write(vector_table as u32);
+        cortex_m::register::msp::write_ns(vectors.read_volatile());
+
+        // BXNS takes the security state from bit 0, which has to be clear for non-secure
+        let reset = vectors.add(1).read_volatile() & !1;
+        core::arch::asm!(
+            clear_fp!(),
+            "mov lr, {reset}",
+            "movs r0, #0",
+            "movs r1, #0",
+            "movs r2, #0",
+            "movs r3, #0",
+            "movs r4, #0",
+            "movs r5, #0",
+            "movs r6, #0",
+            "movs r7, #0",
+            "mov r8, r0",
+            "mov r9, r0",
+            "mov r10, r0",
+            "mov r11, r0",
+            "mov r12, r0",
+            "msr apsr_nzcvq, r0",
+            "bxns lr",
+            reset = in(reg) reset,
+            options(noreturn),
+        )
+    }
+}
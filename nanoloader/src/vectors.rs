@@ -0,0 +1,118 @@
+//! Handing the vector table over to the firmware
+//!
+//! Cores with `SCB.VTOR` simply point it at the firmware. Plain Cortex-M0, and M0+ parts built
+//! without the VTOR option, always fetch vectors from address 0, so the bootloader either has the
+//! firmware's table remapped there, or keeps its own table and forwards every exception.
+
+/// How the vector table of the firmware at `FW_START` is made active, see [`Layout::VECTORS`]
+///
+/// [`Layout::VECTORS`]: crate::Layout::VECTORS
+#[derive(Debug, Clone, Copy)]
+pub enum Vectors {
+    /// Point `SCB.VTOR` at `FW_START`
+    Vtor,
+    /// Copy the first `len` words of the firmware's vector table to `ram`, then call `remap` to map
+    /// that RAM to address 0 (e.g. `SYSCFG_CFGR1.MEM_MODE` on STM32F0)
+    ///
+    /// The firmware must not use the RAM holding the copy.
+    Remap { ram: usize, len: usize, remap: fn() },
+    /// Keep the bootloader's vector table, which forwards exceptions to the firmware's table
+    /// with [`forward_exceptions!`](crate::forward_exceptions)
+    ///
+    /// The reset vector and the initial stack pointer are not forwarded, and faults in the
+    /// bootloader itself also end up in the firmware's handlers.
+    Forward,
+}
+
+/// Make the firmware's vector table active, right before booting it
+///
+/// # Safety
+///
+/// The firmware must be valid, and nothing of the bootloader may run after this except the jump.
+pub(crate) unsafe fn install(vectors: Vectors, fw_start: usize) {
+    match vectors {
+        // SAFETY: Always safe on Cortex-M with VTOR
+        Vectors::Vtor => unsafe {
+            (*cortex_m::peripheral::SCB::PTR)
+                .vtor
+                .write(fw_start as u32);
+        },
+        // SAFETY: The RAM is reserved for the copy, see `Vectors::Remap`
+        Vectors::Remap { ram, len, remap } => unsafe { remap_table(fw_start, ram, len, remap) },
+        Vectors::Forward => {}
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Copy the first `len` words of the vector table at `fw_start` to `ram`, then `remap` it
+///
+/// # Safety
+///
+/// `len` words must be readable at `fw_start` and writable at `ram`, without overlapping.
+unsafe fn remap_table(fw_start: usize, ram: usize, len: usize, remap: fn()) {
+    // SAFETY: Guaranteed by caller
+    unsafe { core::ptr::copy_nonoverlapping(fw_start as *const u32, ram as *mut u32, len) };
+    remap();
+}
+
+/// Forward all exceptions and interrupts of the bootloader to the vector table at `FW_START`,
+/// for [`Vectors::Forward`]
+///
+/// This defines the `DefaultHandler` and `HardFault` symbols of `cortex-m-rt`, so the bootloader
+/// must not define these handlers itself. The exception number in IPSR selects the firmware
+/// handler, which is entered by a tail branch so that it returns straight from the exception.
+#[macro_export]
+macro_rules! forward_exceptions {
+    ($layout:ty) => {
+        core::arch::global_asm!(
+            ".section .text.nanoloader_forward, \"ax\"",
+            ".global DefaultHandler",
+            ".type DefaultHandler, %function",
+            ".global HardFault",
+            ".type HardFault, %function",
+            ".thumb_func",
+            "DefaultHandler:",
+            ".thumb_func",
+            "HardFault:",
+            "    mrs r0, IPSR",
+            "    lsls r0, r0, #2",
+            "    ldr r1, ={fw_start}",
+            "    ldr r0, [r1, r0]",
+            "    bx r0",
+            ".ltorg",
+            ".size DefaultHandler, . - DefaultHandler",
+            ".size HardFault, . - HardFault",
+            fw_start = const <$layout as $crate::Layout>::FW_START,
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static REMAPPED: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn remap() {
+        let table: [u32; 48] = core::array::from_fn(|i| 0x4001 + 2 * i as u32);
+        let mut ram = [0xdead_beef_u32; 64];
+
+        // Only `len` words are copied before the RAM is remapped
+        // SAFETY: Both arrays hold at least 40 words
+        unsafe {
+            remap_table(
+                table.as_ptr() as usize,
+                ram.as_mut_ptr() as usize,
+                40,
+                || REMAPPED.store(true, Ordering::Relaxed),
+            )
+        };
+        assert!(REMAPPED.load(Ordering::Relaxed));
+        assert_eq!(ram[..40], table[..40]);
+        assert!(ram[40..].iter().all(|w| *w == 0xdead_beef));
+    }
+}
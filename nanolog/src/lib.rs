@@ -0,0 +1,53 @@
+//! `log` backends for Nano Loader bootloaders
+//!
+//! Each backend is a `Logger` that a board puts in a `static` and installs with its `init`
+//! method, which also sets the maximum level. Records are written as `<prefix><LEVEL> - <message>`.
+//! Records above the maximum level are dropped by the `log` macros before they are formatted;
+//! the `max_level_*` features of `log` drop them at compile time, which saves the most code.
+//!
+//! Each backend has a feature of the same name: `semihosting`, `rtt` and `uart`. All are enabled
+//! by default; boards pick theirs with `default-features = false`.
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(feature = "rtt")]
+pub mod rtt;
+#[cfg(feature = "semihosting")]
+pub mod semihosting;
+#[cfg(feature = "uart")]
+pub mod uart;
+
+pub use log::LevelFilter;
+
+/// Install `logger` with `level` as the maximum level
+///
+/// # Safety
+///
+/// Must not be called while a record is being logged, e.g. from an interrupt handler.
+#[cfg(any(feature = "semihosting", feature = "rtt", feature = "uart"))]
+unsafe fn install(logger: &'static dyn log::Log, level: LevelFilter) {
+    // SAFETY: Guaranteed by caller
+    unsafe {
+        // Without atomic compare-and-swap on ARMv6-M, only the racy variants are available
+        let _ = log::set_logger_racy(logger);
+        log::set_max_level_racy(level);
+    }
+}
+
+/// Write `record` to `out`, followed by `end`
+#[cfg(any(feature = "semihosting", feature = "rtt", feature = "uart"))]
+fn write(
+    out: &mut impl core::fmt::Write,
+    prefix: &str,
+    record: &log::Record,
+    end: &str,
+) -> core::fmt::Result {
+    out.write_str(prefix)?;
+    out.write_str(record.level().as_str())?;
+    out.write_str(" - ")?;
+    out.write_fmt(*record.args())?;
+    out.write_str(end)
+}
@@ -0,0 +1,185 @@
+//! SEGGER RTT backend
+//!
+//! Records are written to up channel 0 of an RTT control block in RAM, which the debug probe
+//! polls in the background without halting the core. If the host does not keep up, the end of a
+//! record is dropped rather than waiting for room.
+//!
+//! The probe finds the control block by scanning RAM for its ID, or by the symbol `_SEGGER_RTT`,
+//! which the board can give its `static` logger with `#[unsafe(export_name = "_SEGGER_RTT")]`.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{Ordering, compiler_fence};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Buffer descriptor, with offsets into the buffer
+#[repr(C)]
+struct Channel {
+    name: *const u8,
+    buffer: *mut u8,
+    size: usize,
+    /// Written by the target
+    write: usize,
+    /// Written by the host
+    read: usize,
+    flags: usize,
+}
+
+/// Control block with a single up channel, and its buffer of `N` bytes
+#[repr(C)]
+struct ControlBlock<const N: usize> {
+    id: [u8; 16],
+    max_up: u32,
+    max_down: u32,
+    up: Channel,
+    buffer: [u8; N],
+}
+
+/// Logger with an RTT buffer of `N` bytes
+///
+/// The logger starts with the control block, so that the probe finds it at the address of the
+/// `static`.
+#[repr(C)]
+pub struct Logger<const N: usize> {
+    block: UnsafeCell<ControlBlock<N>>,
+    prefix: &'static str,
+}
+
+// SAFETY: `Logger::new` requires that the logger is not used concurrently, and the pointers
+// only point into the logger itself
+unsafe impl<const N: usize> Send for Logger<N> {}
+unsafe impl<const N: usize> Sync for Logger<N> {}
+
+impl<const N: usize> Logger<N> {
+    const ID: [u8; 16] = *b"SEGGER RTT\0\0\0\0\0\0";
+    /// Trim records that do not fit
+    const NO_BLOCK_TRIM: usize = 1;
+
+    /// Logger that starts each record with `prefix`
+    ///
+    /// # Safety
+    ///
+    /// Records must not be logged concurrently, i.e. from interrupt handlers or other cores.
+    pub const unsafe fn new(prefix: &'static str) -> Self {
+        Logger {
+            block: UnsafeCell::new(ControlBlock {
+                id: [0; 16],
+                max_up: 0,
+                max_down: 0,
+                up: Channel {
+                    name: core::ptr::null(),
+                    buffer: core::ptr::null_mut(),
+                    size: 0,
+                    write: 0,
+                    read: 0,
+                    flags: 0,
+                },
+                buffer: [0; N],
+            }),
+            prefix,
+        }
+    }
+
+    /// Set up the control block, and install this logger with `level` as the maximum level
+    ///
+    /// # Safety
+    ///
+    /// Must not be called while a record is being logged, e.g. from an interrupt handler.
+    pub unsafe fn init(&'static self, level: LevelFilter) {
+        let block = self.block.get();
+        // SAFETY: Not used concurrently, and the host only looks at the block once it has its ID
+        unsafe {
+            (*block).max_up = 1;
+            (*block).max_down = 0;
+            (*block).up = Channel {
+                name: c"Terminal".as_ptr().cast(),
+                buffer: addr_of_mut!((*block).buffer).cast(),
+                size: N,
+                write: 0,
+                read: 0,
+                flags: Self::NO_BLOCK_TRIM,
+            };
+            // The ID is written last, so the host does not find a partially set up block
+            compiler_fence(Ordering::SeqCst);
+            addr_of_mut!((*block).id).write_volatile(Self::ID);
+
+            crate::install(self, level);
+        }
+    }
+}
+
+struct Writer<const N: usize>(*mut ControlBlock<N>);
+
+impl<const N: usize> fmt::Write for Writer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let block = self.0;
+        // SAFETY: The logger is not used concurrently, and the host only reads the buffer
+        // between the read and write offsets, and only writes the read offset
+        unsafe {
+            let read = addr_of!((*block).up.read).read_volatile();
+            let mut write = (*block).up.write;
+            for &b in s.as_bytes() {
+                let next = if write + 1 == N { 0 } else { write + 1 };
+                if next == read {
+                    break;
+                }
+                addr_of_mut!((*block).buffer[write]).write_volatile(b);
+                write = next;
+            }
+            compiler_fence(Ordering::SeqCst);
+            addr_of_mut!((*block).up.write).write_volatile(write);
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Log for Logger<N> {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let _ = crate::write(&mut Writer(self.block.get()), self.prefix, record, "\n");
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::boxed::Box;
+
+    #[test]
+    fn ring() {
+        let logger: &'static Logger<16> = Box::leak(Box::new(unsafe { Logger::new("") }));
+        unsafe { logger.init(LevelFilter::Info) };
+        let block = unsafe { &mut *logger.block.get() };
+        assert_eq!(&block.id[..10], b"SEGGER RTT");
+        assert_eq!(block.up.size, 16);
+
+        let log = |n: u32| {
+            logger.log(
+                &Record::builder()
+                    .level(Level::Info)
+                    .args(format_args!("rec {n}"))
+                    .build(),
+            )
+        };
+
+        // "INFO - rec 1\n" is 13 bytes, of which the host reads 10
+        log(1);
+        assert_eq!(&block.buffer[..13], b"INFO - rec 1\n");
+        assert_eq!(block.up.write, 13);
+        block.up.read = 10;
+
+        // The next record wraps around, and is trimmed to the 12 bytes that fit
+        log(2);
+        assert_eq!(block.up.write, 9);
+        assert_eq!(&block.buffer[13..], b"INF");
+        assert_eq!(&block.buffer[..9], b"O - rec 2");
+    }
+}
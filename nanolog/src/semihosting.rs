@@ -0,0 +1,52 @@
+//! Semihosting backend
+//!
+//! Each record is printed to the host's standard output. This is slow, and halts the core if no
+//! debugger or emulator is attached, so it is only meant for development.
+
+use core::fmt;
+
+use cortex_m_semihosting::hprint;
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub struct Logger {
+    prefix: &'static str,
+}
+
+impl Logger {
+    /// Logger that starts each record with `prefix`
+    pub const fn new(prefix: &'static str) -> Self {
+        Logger { prefix }
+    }
+
+    /// Install this logger, with `level` as the maximum level
+    ///
+    /// # Safety
+    ///
+    /// Must not be called while a record is being logged, e.g. from an interrupt handler.
+    pub unsafe fn init(&'static self, level: LevelFilter) {
+        // SAFETY: Guaranteed by caller
+        unsafe { crate::install(self, level) };
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // Printed in one go, so that records from the bootloader and the HAL do not interleave
+        hprint!("{}", Line(self.prefix, record));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Record with its prefix, written without the padding support of `&str`'s `Display`
+struct Line<'a>(&'static str, &'a Record<'a>);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::write(f, self.0, self.1, "\n")
+    }
+}
@@ -0,0 +1,100 @@
+//! Polled UART backend
+//!
+//! The board provides the UART, set up for its pins and baud rate. Records are written byte by
+//! byte, waiting for room in the transmitter, and end with CR LF for terminal programs.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Transmitter of a UART
+pub trait Uart {
+    /// Write `byte`, waiting until the transmitter can take it
+    fn write(&mut self, byte: u8);
+}
+
+pub struct Logger<U> {
+    uart: UnsafeCell<U>,
+    prefix: &'static str,
+}
+
+// SAFETY: `Logger::new` requires that the logger is not used concurrently
+unsafe impl<U: Send> Sync for Logger<U> {}
+
+impl<U: Uart + Send> Logger<U> {
+    /// Logger writing to `uart`, that starts each record with `prefix`
+    ///
+    /// # Safety
+    ///
+    /// Records must not be logged concurrently, i.e. from interrupt handlers or other cores.
+    pub const unsafe fn new(uart: U, prefix: &'static str) -> Self {
+        Logger {
+            uart: UnsafeCell::new(uart),
+            prefix,
+        }
+    }
+
+    /// Install this logger, with `level` as the maximum level
+    ///
+    /// # Safety
+    ///
+    /// Must not be called while a record is being logged, e.g. from an interrupt handler.
+    pub unsafe fn init(&'static self, level: LevelFilter) {
+        // SAFETY: Guaranteed by caller
+        unsafe { crate::install(self, level) };
+    }
+}
+
+struct Writer<'a, U>(&'a mut U);
+
+impl<U: Uart> fmt::Write for Writer<'_, U> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|b| self.0.write(b));
+        Ok(())
+    }
+}
+
+impl<U: Uart + Send> Log for Logger<U> {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // SAFETY: Records are not logged concurrently, see `Logger::new`
+        let uart = unsafe { &mut *self.uart.get() };
+        let _ = crate::write(&mut Writer(uart), self.prefix, record, "\r\n");
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::vec::Vec;
+
+    impl Uart for Vec<u8> {
+        fn write(&mut self, byte: u8) {
+            self.push(byte);
+        }
+    }
+
+    #[test]
+    fn records() {
+        let logger = unsafe { Logger::new(Vec::new(), "[NL] ") };
+        for (level, n) in [(Level::Info, 1), (Level::Debug, 2)] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .args(format_args!("record {n}"))
+                    .build(),
+            );
+        }
+        assert_eq!(
+            logger.uart.into_inner(),
+            b"[NL] INFO - record 1\r\n[NL] DEBUG - record 2\r\n"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Loading application images
+
+use crate::intelhex::{self, Segment};
+
+/// Load the segments of an ELF or Intel HEX file
+pub fn load(data: &[u8]) -> Result<Vec<Segment>, String> {
+    if data.starts_with(&elf::abi::ELFMAGIC) {
+        load_elf(data)
+    } else {
+        intelhex::segments(data)
+    }
+}
+
+fn load_elf(data: &[u8]) -> Result<Vec<Segment>, String> {
+    let elffile = elf::ElfBytes::<elf::endian::LittleEndian>::minimal_parse(data)
+        .map_err(|e| format!("{e}"))?;
+
+    let segments = elffile
+        .segments()
+        .ok_or_else(|| String::from("No segments found in ELF file"))?;
+
+    segments
+        .iter()
+        .filter(|phdr| phdr.p_type == elf::abi::PT_LOAD && phdr.p_filesz > 0)
+        .map(|phdr| {
+            Ok(Segment {
+                address: phdr.p_paddr as usize,
+                data: elffile
+                    .segment_data(&phdr)
+                    .map_err(|e| format!("{e}"))?
+                    .to_vec(),
+            })
+        })
+        .collect()
+}
+
+/// Flatten segments into a contiguous image starting at `start`
+///
+/// Gaps between segments are filled with 0xff (erased Flash). All segments must lie within
+/// `start..end`.
+pub fn flatten(segments: &[Segment], start: usize, end: usize) -> Result<Vec<u8>, String> {
+    let mut image = Vec::new();
+
+    for segment in segments {
+        let seg_end = segment.address + segment.data.len();
+        if segment.address < start || seg_end > end {
+            return Err(format!(
+                "Segment 0x{:08x}-0x{:08x} outside of firmware area 0x{:08x}-0x{:08x}",
+                segment.address, seg_end, start, end
+            ));
+        }
+
+        let (off, off_end) = (segment.address - start, seg_end - start);
+        if image.len() < off_end {
+            image.resize(off_end, 0xff);
+        }
+        image[off..off_end].copy_from_slice(&segment.data);
+    }
+
+    if image.is_empty() {
+        return Err(String::from("Image is empty"));
+    }
+    Ok(image)
+}
+
+/// Extract the memory region `start..end` from a set of segments
+///
+/// Unlike [`flatten`], data outside of the region is ignored, and the entire region is returned.
+/// Memory not covered by any segment reads as erased (0xff).
+pub fn region(segments: &[Segment], start: usize, end: usize) -> Vec<u8> {
+    let mut region = vec![0xff; end - start];
+
+    for segment in segments {
+        let seg_end = segment.address + segment.data.len();
+        let (lo, hi) = (segment.address.max(start), seg_end.min(end));
+        if lo < hi {
+            region[lo - start..hi - start]
+                .copy_from_slice(&segment.data[lo - segment.address..hi - segment.address]);
+        }
+    }
+    region
+}
@@ -0,0 +1,378 @@
+//! Inspecting Flash dumps
+
+use std::fmt::Write;
+
+use std::convert::Infallible;
+
+use nanoloader::check::{self, InstallFault, UpdateFault};
+use nanoloader::events::{self, Event};
+use nanoloader::options::{Banked, Format, OptionsFlash, Pair, Single, Slot, UpdatePointers, Word};
+use nanoloader::{NanoReason, UpdateInfo};
+
+use crate::package::{CRC32, Layout, PointerFormat};
+
+/// Options or event log page read from a dump
+pub struct DumpPage(pub Vec<u8>);
+
+impl OptionsFlash for DumpPage {
+    type Error = Infallible;
+
+    fn page(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn program(&mut self, _offset: usize, _data: &[u8]) -> Result<(), Infallible> {
+        unreachable!("dumps are read-only")
+    }
+
+    fn erase(&mut self) -> Result<(), Infallible> {
+        unreachable!("dumps are read-only")
+    }
+}
+
+/// Update pointers found in a dump
+pub struct Pointers {
+    /// Address of the active options page
+    pub page: usize,
+    pub pointers: Box<dyn UpdatePointers<Error = Infallible>>,
+}
+
+/// Decode update pointers from one options page, or two pages used alternately
+///
+/// For the pair format, the dump can only tell erased words by their contents, while the
+/// bootloader uses a blank check. Words programmed to all 1s are reported as erased.
+pub fn pointers(pages: Vec<(usize, Vec<u8>)>, format: PointerFormat) -> Result<Pointers, String> {
+    match format {
+        PointerFormat::Word => decode::<Word>(pages),
+        PointerFormat::Pair => decode::<Pair>(pages),
+    }
+}
+
+fn decode<T: Format + 'static>(pages: Vec<(usize, Vec<u8>)>) -> Result<Pointers, String> {
+    let mut pages = pages.into_iter();
+    match (pages.next(), pages.next(), pages.next()) {
+        (Some((page, data)), None, None) => Ok(Pointers {
+            page,
+            pointers: Box::new(Single::<_, T>::new(DumpPage(data))),
+        }),
+        (Some((a, data_a)), Some((b, data_b)), None) => {
+            let Ok(banked) = Banked::<_, T>::new([DumpPage(data_a), DumpPage(data_b)]);
+            Ok(Pointers {
+                page: [a, b][banked.active()],
+                pointers: Box::new(banked),
+            })
+        }
+        _ => Err(String::from("Expected one or two options pages")),
+    }
+}
+
+/// Describe the bootloader state found in a dump of the firmware area
+///
+/// `pointers` holds the update pointers, if the options pages were dumped. An explicitly
+/// given `update` address takes precedence over the queued update pointers.
+pub fn report(
+    fwarea: &[u8],
+    layout: &Layout,
+    pointers: Option<&Pointers>,
+    update: Option<usize>,
+) -> String {
+    let mut out = String::new();
+
+    out += &format!("Firmware: {}\n", firmware(fwarea, layout.size_off));
+
+    let queue = pointers.map_or(Vec::new(), |p| {
+        let Ok(slots) = (0..p.pointers.slots())
+            .map(|i| p.pointers.slot(i))
+            .collect::<Result<Vec<_>, _>>();
+        let count = |slot| slots.iter().filter(|s| **s == slot).count();
+        let queue: Vec<_> = (0..)
+            .map_while(|position| {
+                let Ok(queued) = p.pointers.queued(position);
+                queued.map(|(_, address)| address)
+            })
+            .collect();
+        let addresses: Vec<_> = queue.iter().map(|a| format!("0x{a:08x}")).collect();
+        writeln!(
+            out,
+            "Update pointers at 0x{:08x}: {} cleared, {} free, {}",
+            p.page,
+            count(Slot::Cleared),
+            count(Slot::Free),
+            match queue.len() {
+                0 => String::from("no pending update"),
+                1 => format!("pending update at {}", addresses[0]),
+                n => format!("{n} queued updates at {}", addresses.join(", ")),
+            }
+        )
+        .unwrap();
+        queue
+    });
+
+    let updates = match update {
+        Some(address) => vec![address],
+        None => queue,
+    };
+    for address in updates {
+        writeln!(
+            out,
+            "Update at 0x{address:08x}: {}",
+            self::update(fwarea, layout, address)
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn firmware(data: &[u8], size_off: usize) -> String {
+    match check::firmware(data, size_off, |d| CRC32.checksum(d)) {
+        Ok(fw) if fw.is_valid() => {
+            format!(
+                "valid, {} bytes, CRC 0x{:08x}",
+                fw.data.len(),
+                fw.crc_actual
+            )
+        }
+        Ok(fw) => format!(
+            "CRC mismatch, {} bytes, exp=0x{:08x}, act=0x{:08x}",
+            fw.data.len(),
+            fw.crc_expected,
+            fw.crc_actual
+        ),
+        Err(_) => match data.get(size_off..size_off + 4) {
+            Some(size) => format!(
+                "invalid size 0x{:08x}",
+                u32::from_le_bytes(size.try_into().unwrap())
+            ),
+            None => String::from("invalid size offset"),
+        },
+    }
+}
+
+fn fault(fault: UpdateFault) -> &'static str {
+    match fault {
+        UpdateFault::Address => "outside of firmware area",
+        UpdateFault::Size => "size exceeds firmware area",
+        UpdateFault::Checksum => "checksum mismatch",
+    }
+}
+
+fn update(fwarea: &[u8], layout: &Layout, address: usize) -> String {
+    let update = match check::update(fwarea, layout.fw_start, address, |d| CRC32.checksum(d)) {
+        Ok(update) => update,
+        Err(fault) => return format!("rejected, {}", self::fault(fault)),
+    };
+
+    let info = &update.info;
+    let summary = format!("valid, {} bytes, type {}", info.upsize, info.uptype);
+    let (kind, installed) = match info.uptype {
+        UpdateInfo::TYPE_PLAIN => (
+            "plain",
+            check::plain(&update, layout.fw_start, layout.page_size),
+        ),
+        UpdateInfo::TYPE_LZ4 => (
+            "lz4",
+            check::lz4(&update, layout.fw_start, layout.page_size),
+        ),
+        _ => return format!("{summary}, not installable (unknown type)"),
+    };
+
+    match installed {
+        Ok(size) if info.uptype == UpdateInfo::TYPE_PLAIN => format!(
+            "{summary} ({kind}), installs {} bytes into 0x{:08x}-0x{:08x}, new firmware: {}",
+            info.fwsize,
+            layout.fw_start,
+            layout.fw_start + size,
+            firmware(update.data, layout.size_off)
+        ),
+        Ok(size) => format!(
+            "{summary} ({kind}), installs {} bytes into 0x{:08x}-0x{:08x}",
+            info.fwsize,
+            layout.fw_start,
+            layout.fw_start + size,
+        ),
+        Err(InstallFault::Size) => format!(
+            "{summary} ({kind}), not installable (firmware size {} does not match payload)",
+            info.fwsize
+        ),
+        Err(InstallFault::Overlap) => {
+            format!("{summary} ({kind}), not installable (new firmware would overwrite update)")
+        }
+        Err(InstallFault::Format) => {
+            format!("{summary} ({kind}), not installable (not a plain LZ4 frame)")
+        }
+    }
+}
+
+/// Decode the event log from its pages, oldest event first
+pub fn events(pages: Vec<Vec<u8>>) -> String {
+    let pages: Vec<_> = pages.into_iter().map(DumpPage).collect();
+    let mut lines = Vec::new();
+    let Ok(()) = events::for_each(&pages, |event| lines.push(self::event(event)));
+
+    let mut out = format!("Event log: {} records\n", lines.len());
+    for line in lines {
+        writeln!(out, "  {line}").unwrap();
+    }
+    out
+}
+
+fn event(event: Result<Event, [u8; events::RECORD_SZ]>) -> String {
+    match event {
+        Ok(Event::Boot { fwsize }) => format!("booted firmware, {fwsize} bytes"),
+        Ok(Event::UpdateInvalid { address, fault }) => {
+            format!("update at 0x{address:08x} invalid, {}", self::fault(fault))
+        }
+        Ok(Event::Installed { address }) => format!("installed update at 0x{address:08x}"),
+        Ok(Event::InstallFailed { address }) => {
+            format!("installing update at 0x{address:08x} left no valid firmware")
+        }
+        Ok(Event::Deferred { address, reason }) => {
+            format!("update at 0x{address:08x} deferred, reason {reason}")
+        }
+        Ok(Event::Rejected { address, reason }) => {
+            format!("update at 0x{address:08x} rejected, reason {reason}")
+        }
+        Ok(Event::Factory { fwsize }) => format!("reinstalled factory image, {fwsize} bytes"),
+        Ok(Event::Abort(NanoReason::HalError(e))) => format!("aborted, HAL error {e}"),
+        Ok(Event::Abort(NanoReason::FwSizeInvalid)) => {
+            String::from("aborted, invalid firmware size")
+        }
+        Ok(Event::Abort(NanoReason::FwCrcMismatch)) => {
+            String::from("aborted, firmware CRC mismatch")
+        }
+        Err(record) => format!("corrupt record {record:02x?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image, intelhex};
+
+    #[test]
+    fn testloader() {
+        let layout = crate::package::tests::testloader();
+        let mut segments =
+            intelhex::segments(include_bytes!("../../moonbow/test/hello.patched.hex")).unwrap();
+        segments
+            .extend(intelhex::segments(include_bytes!("../../moonbow/test/hello2.up")).unwrap());
+
+        let fwarea = image::region(&segments, layout.fw_start, layout.fw_end);
+        let page = |address| (address, image::region(&segments, address, address + 1024));
+
+        let single = pointers(std::vec![page(0x3c00)], PointerFormat::Word).unwrap();
+        assert_eq!(
+            report(&fwarea, &layout, Some(&single), None),
+            "Firmware: valid, 612 bytes, CRC 0x8e1a9e90\n\
+             Update pointers at 0x00003c00: 0 cleared, 255 free, pending update at 0x0000c000\n\
+             Update at 0x0000c000: valid, 636 bytes, type 0 (plain), installs 620 bytes into \
+             0x00004000-0x00004400, new firmware: valid, 616 bytes, CRC 0xb72a2439\n"
+        );
+
+        let banked = pointers(std::vec![page(0x3c00), page(0x3800)], PointerFormat::Word).unwrap();
+        assert!(report(&fwarea, &layout, Some(&banked), None).contains(
+            "Update pointers at 0x00003c00: 0 cleared, 251 free, pending update at 0x0000c000\n"
+        ));
+
+        // Same firmware, compressed
+        let mut fwarea = fwarea;
+        let plain = &fwarea[0xc000 - layout.fw_start..][..636];
+        let lz4 = crate::package::update(&plain[size_of::<UpdateInfo>()..], true);
+        let mut compressed = fwarea.clone();
+        compressed[0xc000 - layout.fw_start..][..lz4.len()].copy_from_slice(&lz4);
+        assert!(
+            report(&compressed, &layout, None, Some(0xc000)).contains(&format!(
+                "Update at 0x0000c000: valid, {} bytes, type 1 (lz4), installs 620 bytes into \
+                 0x00004000-0x00004400\n",
+                lz4.len()
+            ))
+        );
+
+        // Corrupt the update
+        fwarea[0xc000 - layout.fw_start + 0x100] ^= 1;
+        assert!(
+            report(&fwarea, &layout, None, Some(0xc000))
+                .ends_with("Update at 0x0000c000: rejected, checksum mismatch\n")
+        );
+        assert!(
+            report(&fwarea, &layout, None, Some(0x3c00))
+                .ends_with("Update at 0x00003c00: rejected, outside of firmware area\n")
+        );
+    }
+
+    #[test]
+    fn event_log() {
+        // Second page is the newest, the first one holds the older records and a torn one
+        let page = |seq: u32, records: &[[u8; 8]]| {
+            let mut page = vec![0xff; 1024];
+            page[..4].copy_from_slice(&seq.to_le_bytes());
+            page[4..8].copy_from_slice(&(!seq).to_le_bytes());
+            for (i, record) in records.iter().enumerate() {
+                page[8 + i * 8..][..8].copy_from_slice(record);
+            }
+            page
+        };
+        let mut torn = Event::Installed { address: 0xc000 }.encode();
+        torn[5] = 0;
+        let old = page(7, &[Event::Boot { fwsize: 612 }.encode(), torn]);
+        let new = page(
+            8,
+            &[
+                Event::UpdateInvalid {
+                    address: 0xc000,
+                    fault: UpdateFault::Checksum,
+                }
+                .encode(),
+                Event::Abort(NanoReason::FwCrcMismatch).encode(),
+            ],
+        );
+
+        assert_eq!(
+            events(vec![new, old]),
+            "Event log: 4 records\n\
+             \x20 booted firmware, 612 bytes\n\
+             \x20 corrupt record [03, 66, 00, 00, 00, 00, 00, 00]\n\
+             \x20 update at 0x0000c000 invalid, checksum mismatch\n\
+             \x20 aborted, firmware CRC mismatch\n"
+        );
+        assert_eq!(events(vec![vec![0xff; 1024]]), "Event log: 0 records\n");
+    }
+
+    #[test]
+    fn banked() {
+        let layout = crate::package::tests::testloader();
+        // Second page is active, with one cleared update and one pending
+        let first = vec![0; 1024];
+        let mut second = vec![0xff; 1024];
+        second[..8].copy_from_slice(&0x2000u64.to_le_bytes());
+        second[8..16].fill(0);
+        second[16..24].copy_from_slice(&0x3000u64.to_le_bytes());
+        second[1008..1016].copy_from_slice(&1u64.to_le_bytes());
+        second[1016..].copy_from_slice(&(!1u64).to_le_bytes());
+
+        let mut queued = second.clone();
+        let p = pointers(vec![(0xc00, first), (0x3c00, second)], PointerFormat::Pair).unwrap();
+        assert_eq!(p.page, 0x3c00);
+        assert_eq!(p.pointers.slot(0), Ok(Slot::Cleared));
+        assert_eq!(p.pointers.pending(), Ok(Some((1, 0x3000))));
+        assert!(
+            report(&[], &layout, Some(&p), None)
+                .contains("Update pointers at 0x00003c00: 1 cleared, 61 free, pending update at")
+        );
+
+        // Another update queued after it
+        queued[32..40].copy_from_slice(&0x3400u64.to_le_bytes());
+        let p = pointers(
+            vec![(0xc00, vec![0; 1024]), (0x3c00, queued)],
+            PointerFormat::Pair,
+        )
+        .unwrap();
+        assert_eq!(p.pointers.queued(1), Ok(Some((2, 0x3400))));
+        let report = report(&[], &layout, Some(&p), None);
+        assert!(report.contains(
+            "1 cleared, 60 free, 2 queued updates at 0x00003000, 0x00003400\n\
+             Update at 0x00003000: rejected, outside of firmware area\n\
+             Update at 0x00003400: rejected, outside of firmware area\n"
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+//! Loading Intel HEX files
+
+#[derive(Debug)]
+pub struct Segment {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+pub fn segments(hexdata: &[u8]) -> Result<Vec<Segment>, String> {
+    let hexstr =
+        core::str::from_utf8(hexdata).map_err(|e| format!("Invalid UTF-8 string ({e:?})"))?;
+
+    let reader = ihex::Reader::new(hexstr);
+
+    let mut segments = Vec::<Segment>::new();
+
+    let mut address_base = 0_usize;
+
+    let mut segment_buf = Vec::<u8>::new();
+    let mut segment_start = 0_usize;
+
+    for rec in reader {
+        let rec = rec.map_err(|e| format!("Invalid record: {e}"))?;
+        match rec {
+            ihex::Record::Data { offset, mut value } => {
+                let segment_addr = segment_start + segment_buf.len();
+
+                let addr = address_base + offset as usize;
+
+                if addr != segment_addr {
+                    if !segment_buf.is_empty() {
+                        segments.push(Segment {
+                            address: segment_start,
+                            data: segment_buf,
+                        });
+                    }
+
+                    segment_buf = Vec::<u8>::new();
+                    segment_start = addr;
+                }
+                segment_buf.append(&mut value);
+            }
+            ihex::Record::EndOfFile => {
+                if !segment_buf.is_empty() {
+                    segments.push(Segment {
+                        address: segment_start,
+                        data: segment_buf,
+                    });
+                }
+                return Ok(segments);
+            }
+            ihex::Record::ExtendedSegmentAddress(esa) => {
+                address_base = (esa as usize) << 4;
+            }
+            ihex::Record::ExtendedLinearAddress(ela) => {
+                address_base = (ela as usize) << 16;
+            }
+            _ => (),
+        }
+    }
+    Err(String::from("Unexpected end of file"))
+}
+
+/// Create an Intel HEX file from a list of segments
+///
+/// Data records hold up to 16 bytes. Extended linear address records are only emitted once the
+/// upper 16 bits of the address change.
+pub fn create(segments: &[Segment]) -> Result<String, String> {
+    let mut records = Vec::new();
+    let mut address_base = 0_usize;
+
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(16).enumerate() {
+            let addr = segment.address + i * 16;
+            if addr + chunk.len() > 1 << 32 {
+                return Err(format!("Address 0x{addr:x} out of range"));
+            }
+            if addr >> 16 != address_base >> 16 {
+                address_base = addr & !0xffff;
+                records.push(ihex::Record::ExtendedLinearAddress((addr >> 16) as u16));
+            }
+            // Split records that would cross a 64K boundary
+            let split = (0x10000 - (addr & 0xffff)).min(chunk.len());
+            let (lo, hi) = chunk.split_at(split);
+            records.push(ihex::Record::Data {
+                offset: (addr & 0xffff) as u16,
+                value: lo.to_vec(),
+            });
+            if !hi.is_empty() {
+                address_base = addr + split;
+                records.push(ihex::Record::ExtendedLinearAddress(
+                    (address_base >> 16) as u16,
+                ));
+                records.push(ihex::Record::Data {
+                    offset: 0,
+                    value: hi.to_vec(),
+                });
+            }
+        }
+    }
+    records.push(ihex::Record::EndOfFile);
+
+    ihex::create_object_file_representation(&records).map_err(|e| format!("{e}"))
+}
@@ -0,0 +1,328 @@
+mod image;
+mod inspect;
+mod intelhex;
+mod package;
+
+use std::io::{Read, Write};
+
+use intelhex::Segment;
+use package::{Layout, PointerFormat};
+
+mod args {
+    use super::PointerFormat;
+
+    #[derive(clap::Parser)]
+    #[command(author, version, about = "Nano Loader packaging tool")]
+    pub struct Args {
+        #[command(subcommand)]
+        pub command: Command,
+    }
+
+    #[derive(clap::Subcommand)]
+    pub enum Command {
+        /// Patch firmware size and CRC into an application image
+        Patch {
+            #[command(flatten)]
+            layout: Layout,
+
+            /// Output format
+            #[arg(short, long, value_enum, default_value_t = Format::Ihex)]
+            format: Format,
+
+            /// Application image (ELF or Intel HEX)
+            input: clio::Input,
+
+            /// Patched firmware image
+            output: clio::Output,
+        },
+
+        /// Create an update from an application image
+        Update {
+            #[command(flatten)]
+            layout: Layout,
+
+            /// Input is already patched (only verify size and CRC)
+            #[arg(long)]
+            patched: bool,
+
+            /// Compress the firmware with LZ4
+            #[arg(long)]
+            lz4: bool,
+
+            /// Create Intel HEX file with the update placed at this address
+            #[arg(short, long, value_parser = parse_int)]
+            stage: Option<usize>,
+
+            /// Also place an update pointer at this address (requires --stage)
+            #[arg(short, long, value_parser = parse_int, requires = "stage")]
+            pointer: Option<usize>,
+
+            /// Update pointer format (defaults to the layout file, or word)
+            #[arg(long, value_enum)]
+            pointer_format: Option<PointerFormat>,
+
+            /// Application image (ELF or Intel HEX)
+            input: clio::Input,
+
+            /// Update file (raw, or Intel HEX if staged)
+            output: clio::Output,
+        },
+
+        /// Report the bootloader state found in a Flash dump
+        Inspect {
+            #[command(flatten)]
+            layout: Layout,
+
+            /// Dump format
+            #[arg(short, long, value_enum, default_value_t = Format::Ihex)]
+            format: Format,
+
+            /// Start address of a raw binary dump
+            #[arg(short, long, value_parser = parse_int, default_value = "0")]
+            base: usize,
+
+            /// Decode update pointers in the options page at this address (give twice for
+            /// alternating pages, first page first; defaults to the layout file)
+            #[arg(short, long, value_parser = parse_int)]
+            pointers: Vec<usize>,
+
+            /// Size of the options pages (defaults to the page size)
+            #[arg(long, value_parser = parse_int)]
+            pointers_size: Option<usize>,
+
+            /// Update pointer format (defaults to the layout file, or word)
+            #[arg(long, value_enum)]
+            pointer_format: Option<PointerFormat>,
+
+            /// Check the update at this address instead of the pending one
+            #[arg(short, long, value_parser = parse_int)]
+            update: Option<usize>,
+
+            /// Decode the event log in the page at this address (give once per page; defaults to
+            /// the layout file)
+            #[arg(short, long, value_parser = parse_int)]
+            events: Vec<usize>,
+
+            /// Flash dump
+            input: clio::Input,
+        },
+    }
+
+    /// Firmware area layout
+    #[derive(clap::Args)]
+    pub struct Layout {
+        /// Bootloader layout file (individual values can be overridden)
+        #[arg(short = 'L', long = "layout")]
+        pub file: Option<std::path::PathBuf>,
+
+        /// Start of firmware area
+        #[arg(long, value_parser = parse_int, required_unless_present = "file")]
+        pub fw_start: Option<usize>,
+
+        /// End of firmware area
+        #[arg(long, value_parser = parse_int, required_unless_present = "file")]
+        pub fw_end: Option<usize>,
+
+        /// Offset of the firmware size field [default: 0x30]
+        #[arg(long, value_parser = parse_int)]
+        pub size_off: Option<usize>,
+
+        /// Flash page size
+        #[arg(long, value_parser = parse_int, required_unless_present = "file")]
+        pub page_size: Option<usize>,
+    }
+
+    #[derive(Clone, Copy, clap::ValueEnum)]
+    pub enum Format {
+        /// Intel HEX
+        Ihex,
+        /// Raw binary
+        Bin,
+    }
+
+    fn parse_int(s: &str) -> Result<usize, String> {
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .map_err(|e| format!("{e}"))
+    }
+}
+
+impl args::Layout {
+    /// Firmware area layout, and the layout file it was read from
+    fn resolve(self) -> Result<(Layout, Option<nanolayout::Layout>), String> {
+        let file = self.file.map(nanolayout::Layout::load).transpose()?;
+        let value =
+            |arg: Option<usize>, name: &str, from_file: fn(&nanolayout::Layout) -> usize| {
+                arg.or(file.as_ref().map(from_file))
+                    .ok_or_else(|| format!("Missing --{name}"))
+            };
+
+        let layout = Layout {
+            fw_start: value(self.fw_start, "fw-start", |f| f.firmware.start)?,
+            fw_end: value(self.fw_end, "fw-end", |f| f.firmware.end)?,
+            size_off: value(self.size_off, "size-off", |f| f.firmware.size_offset).unwrap_or(0x30),
+            page_size: value(self.page_size, "page-size", |f| f.flash.page_size)?,
+        };
+        layout.validate()?;
+        Ok((layout, file))
+    }
+}
+
+/// Update pointer format given on the command line, or from the layout file
+fn pointer_format(
+    format: Option<PointerFormat>,
+    file: Option<&nanolayout::Layout>,
+) -> PointerFormat {
+    format
+        .or(file.map(|f| f.bootloader.pointer_format.into()))
+        .unwrap_or(PointerFormat::Word)
+}
+
+fn main() {
+    let args = <args::Args as clap::Parser>::parse();
+
+    if let Err(e) = run(args.command) {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(command: args::Command) -> Result<(), String> {
+    match command {
+        args::Command::Patch {
+            layout,
+            format,
+            input,
+            mut output,
+        } => {
+            let (layout, _) = layout.resolve()?;
+
+            let firmware = package::patch(&load(input, &layout)?, &layout)?;
+
+            let data = match format {
+                args::Format::Ihex => intelhex::create(&[Segment {
+                    address: layout.fw_start,
+                    data: firmware,
+                }])?
+                .into_bytes(),
+                args::Format::Bin => firmware,
+            };
+            output.write_all(&data).map_err(|e| format!("{e}"))
+        }
+
+        args::Command::Update {
+            layout,
+            patched,
+            lz4,
+            stage,
+            pointer,
+            pointer_format,
+            input,
+            mut output,
+        } => {
+            let (layout, file) = layout.resolve()?;
+            let pointer_format = self::pointer_format(pointer_format, file.as_ref());
+
+            let image = load(input, &layout)?;
+            let firmware = if patched {
+                let len = package::verify(&image, &layout)?;
+                image[..len].to_vec()
+            } else {
+                package::patch(&image, &layout)?
+            };
+            let update = package::update(&firmware, lz4);
+
+            let data = match stage {
+                Some(address) => {
+                    package::check_staging(&update, firmware.len(), address, &layout)?;
+
+                    let mut segments = Vec::new();
+                    if let Some(ptr) = pointer {
+                        segments.push(Segment {
+                            address: ptr,
+                            data: pointer_format.encode(address),
+                        });
+                    }
+                    segments.push(Segment {
+                        address,
+                        data: update,
+                    });
+                    intelhex::create(&segments)?.into_bytes()
+                }
+                None => update,
+            };
+            output.write_all(&data).map_err(|e| format!("{e}"))
+        }
+
+        args::Command::Inspect {
+            layout,
+            format,
+            base,
+            pointers,
+            pointers_size,
+            pointer_format,
+            update,
+            events,
+            input,
+        } => {
+            let (layout, file) = layout.resolve()?;
+            let pointer_format = self::pointer_format(pointer_format, file.as_ref());
+            let pointers = match (pointers.is_empty(), &file) {
+                (true, Some(f)) => f.bootloader.options.clone(),
+                _ => pointers,
+            };
+            let events = match (events.is_empty(), &file) {
+                (true, Some(f)) => f.bootloader.event_log.clone(),
+                _ => events,
+            };
+
+            let data = read(input)?;
+            let segments = match format {
+                args::Format::Ihex => intelhex::segments(&data)?,
+                args::Format::Bin => vec![Segment {
+                    address: base,
+                    data,
+                }],
+            };
+
+            let fwarea = image::region(&segments, layout.fw_start, layout.fw_end);
+            let size = pointers_size.unwrap_or(layout.page_size);
+            let pages: Vec<_> = pointers
+                .into_iter()
+                .map(|address| (address, image::region(&segments, address, address + size)))
+                .collect();
+            let pointers = (!pages.is_empty())
+                .then(|| inspect::pointers(pages, pointer_format))
+                .transpose()?;
+
+            print!(
+                "{}",
+                inspect::report(&fwarea, &layout, pointers.as_ref(), update)
+            );
+            if !events.is_empty() {
+                let pages = events
+                    .into_iter()
+                    .map(|address| image::region(&segments, address, address + layout.page_size))
+                    .collect();
+                print!("{}", inspect::events(pages));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn read(mut input: clio::Input) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    input
+        .read_to_end(&mut data)
+        .map_err(|e| format!("{}: {e}", input.path()))?;
+    Ok(data)
+}
+
+/// Load an application image and flatten it into the firmware area
+fn load(input: clio::Input, layout: &Layout) -> Result<Vec<u8>, String> {
+    let segments = image::load(&read(input)?)?;
+    image::flatten(&segments, layout.fw_start, layout.fw_end)
+}
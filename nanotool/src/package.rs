@@ -0,0 +1,240 @@
+//! Patching firmware and building updates
+
+use nanoloader::UpdateInfo;
+use nanoloader::check;
+
+pub const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Firmware area layout, matching the bootloader's `Layout`
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub fw_start: usize,
+    pub fw_end: usize,
+    pub size_off: usize,
+    pub page_size: usize,
+}
+
+impl Layout {
+    pub fn validate(&self) -> Result<(), String> {
+        check::layout(self.fw_start, self.fw_end, self.size_off, self.page_size)
+            .map_err(|fault| format!("Invalid layout: {}", fault.message()))
+    }
+
+    fn fw_area(&self) -> usize {
+        self.fw_end - self.fw_start
+    }
+}
+
+/// Patch a firmware image
+///
+/// The image is padded to a word boundary, its size is stored at the size offset, and the CRC of
+/// the (patched) image is appended.
+pub fn patch(image: &[u8], layout: &Layout) -> Result<Vec<u8>, String> {
+    let mut firmware = image.to_vec();
+    firmware.resize(firmware.len().next_multiple_of(size_of::<u32>()), 0xff);
+
+    let size = firmware.len();
+    if size < layout.size_off + size_of::<u32>() {
+        return Err(format!("Image too small ({size} bytes) to hold size field"));
+    }
+    if size + size_of::<u32>() > layout.fw_area() {
+        return Err(format!("Image too large ({size} bytes) for firmware area"));
+    }
+
+    firmware[layout.size_off..][..size_of::<u32>()].copy_from_slice(&(size as u32).to_le_bytes());
+    let crc = CRC32.checksum(&firmware);
+    firmware.extend_from_slice(&crc.to_le_bytes());
+
+    Ok(firmware)
+}
+
+/// Verify a patched firmware image, returning its length including the CRC
+pub fn verify(firmware: &[u8], layout: &Layout) -> Result<usize, String> {
+    let read = |off: usize| {
+        firmware
+            .get(off..off + size_of::<u32>())
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    };
+
+    let size = read(layout.size_off).ok_or_else(|| String::from("Image too small"))? as usize;
+    if !size.is_multiple_of(size_of::<u32>()) {
+        return Err(format!("Firmware size {size} is not word-aligned"));
+    }
+    let crc_exp = read(size).ok_or_else(|| format!("Invalid firmware size {size}"))?;
+    let crc_act = CRC32.checksum(&firmware[..size]);
+    if crc_exp != crc_act {
+        return Err(format!(
+            "Firmware CRC mismatch: exp=0x{crc_exp:08x}, act=0x{crc_act:08x}"
+        ));
+    }
+    Ok(size + size_of::<u32>())
+}
+
+/// Build an update from a patched firmware image, plain or LZ4-compressed
+pub fn update(firmware: &[u8], lz4: bool) -> Vec<u8> {
+    let (uptype, payload) = if lz4 {
+        (
+            UpdateInfo::TYPE_LZ4,
+            nanoloader::lz4::frame::compress(firmware, nanoloader::lz4::Mode::HighCompression),
+        )
+    } else {
+        (UpdateInfo::TYPE_PLAIN, firmware.to_vec())
+    };
+
+    let mut info = UpdateInfo {
+        checksum: 0,
+        upsize: (size_of::<UpdateInfo>() + payload.len()) as u32,
+        uptype,
+        fwsize: firmware.len() as u32,
+    };
+
+    let mut update = info.to_bytes().to_vec();
+    update.extend_from_slice(&payload);
+
+    info.checksum = CRC32.checksum(&update[size_of::<u32>()..]);
+    update[..size_of::<UpdateInfo>()].copy_from_slice(&info.to_bytes());
+    update
+}
+
+/// Check that an update staged at `address` can be installed by the bootloader
+///
+/// The update must not overlap the pages the new firmware is programmed into.
+pub fn check_staging(
+    update: &[u8],
+    fwsize: usize,
+    address: usize,
+    layout: &Layout,
+) -> Result<(), String> {
+    if !address.is_multiple_of(size_of::<u32>()) {
+        return Err(format!(
+            "Staging address 0x{address:08x} is not word-aligned"
+        ));
+    }
+    let fw_end = layout.fw_start + fwsize.next_multiple_of(layout.page_size);
+    if address < fw_end {
+        return Err(format!(
+            "Staging address 0x{address:08x} overlaps new firmware (ends at 0x{fw_end:08x})"
+        ));
+    }
+    if address + update.len() > layout.fw_end {
+        return Err(format!(
+            "Update at 0x{address:08x} ({} bytes) exceeds firmware area",
+            update.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Format of the update pointer read by the bootloader's `update_address()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PointerFormat {
+    /// Single 32-bit word holding the address (e.g. Test Loader)
+    Word,
+    /// 64-bit address word, with the following 64-bit word left erased (e.g. MSPM0C Loader)
+    Pair,
+}
+
+impl PointerFormat {
+    pub fn encode(&self, address: usize) -> Vec<u8> {
+        match self {
+            PointerFormat::Word => (address as u32).to_le_bytes().to_vec(),
+            PointerFormat::Pair => (address as u64).to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl From<nanolayout::PointerFormat> for PointerFormat {
+    fn from(format: nanolayout::PointerFormat) -> Self {
+        match format {
+            nanolayout::PointerFormat::Word => PointerFormat::Word,
+            nanolayout::PointerFormat::Pair => PointerFormat::Pair,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::image;
+    use crate::intelhex::{self, Segment};
+
+    /// Firmware area of the Test Loader, from the layout file it is built with
+    pub(crate) fn testloader() -> Layout {
+        let file = nanolayout::Layout::parse(include_str!("../../testloader/layout.toml")).unwrap();
+        Layout {
+            fw_start: file.firmware.start,
+            fw_end: file.firmware.end,
+            size_off: file.firmware.size_offset,
+            page_size: file.flash.page_size,
+        }
+    }
+
+    /// Reverse patching, recovering the image as produced by the linker
+    fn unpatch(patched: &[u8]) -> Vec<u8> {
+        let mut image = patched[..patched.len() - 4].to_vec();
+        image[0x30..0x34].fill(0);
+        image
+    }
+
+    fn load(hex: &str, start: usize) -> Vec<u8> {
+        let mut segments = intelhex::segments(hex.as_bytes()).unwrap();
+        segments.retain(|s| s.address >= start);
+        image::flatten(&segments, start, start + 0x10000).unwrap()
+    }
+
+    #[test]
+    fn patched() {
+        let layout = testloader();
+        let hex = include_str!("../../moonbow/test/hello.patched.hex");
+        let expected = load(hex, layout.fw_start);
+
+        assert_eq!(verify(&expected, &layout), Ok(expected.len()));
+        let patched = patch(&unpatch(&expected), &layout).unwrap();
+        assert_eq!(patched, expected);
+
+        let segments = [Segment {
+            address: layout.fw_start,
+            data: patched,
+        }];
+        assert_eq!(intelhex::create(&segments).unwrap(), hex);
+    }
+
+    #[test]
+    fn staged() {
+        let layout = testloader();
+        let hex = include_str!("../../moonbow/test/hello2.up");
+        let expected = load(hex, 0xc000);
+        let firmware = &expected[size_of::<UpdateInfo>()..];
+
+        let update = update(&patch(&unpatch(firmware), &layout).unwrap(), false);
+        assert_eq!(update, expected);
+        assert!(check_staging(&update, firmware.len(), 0xc000, &layout).is_ok());
+
+        let segments = [
+            Segment {
+                address: 0x3c00,
+                data: PointerFormat::Word.encode(0xc000),
+            },
+            Segment {
+                address: 0xc000,
+                data: update,
+            },
+        ];
+        assert_eq!(intelhex::create(&segments).unwrap(), hex);
+    }
+
+    #[test]
+    fn staging() {
+        let layout = testloader();
+        let update = update(&[0; 2000], false);
+        assert!(check_staging(&update, 2000, 0x4000, &layout).is_err());
+        assert!(check_staging(&update, 2000, 0x4802, &layout).is_err());
+        assert!(check_staging(&update, 2000, 0xfc00, &layout).is_err());
+        assert!(check_staging(&update, 2000, 0x4400, &layout).is_err());
+        assert!(check_staging(&update, 2000, 0x4800, &layout).is_ok());
+
+        // The event log at the end of the Flash is not part of the firmware area
+        assert!(check_staging(&update, 2000, 0xf400, &layout).is_err());
+        assert!(check_staging(&update, 2000, 0xf000, &layout).is_ok());
+    }
+}
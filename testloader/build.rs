@@ -1,27 +1,9 @@
-// Boilerplate from cortex-m-quickstart
-
-use std::env;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+// Adapted from the cortex-m-quickstart boilerplate
 
 fn main() {
-    // Put `memory.x` in our output directory and ensure it's
-    // on the linker search path.
-    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-    File::create(out.join("memory.x"))
-        .unwrap()
-        .write_all(include_bytes!("memory.x"))
-        .unwrap();
-    println!("cargo:rustc-link-search={}", out.display());
-
-    // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
-
-    // Specify linker arguments.
+    // Generate `memory.x` and the HAL constants from the layout file, and put them on the
+    // linker search path.
+    nanolayout::build("layout.toml");
 
     // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
     // for example the FLASH and RAM sections in your `memory.x`.
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Run the Test Loader in Moonbow and check that the firmware starts: the plain build on the
+# Cortex-M0+, and the secure build on the Cortex-M33, where the firmware is the non-secure
+# application. The firmware does not exit, so each run is stopped after a few seconds.
+set -e
+cd "$(dirname "$0")"
+
+HELLO="Hello, world! This is the main firmware speaking"
+OUT=target/run
+mkdir -p "$OUT"
+
+cargo build --quiet --manifest-path ../moonbow/Cargo.toml
+
+moonbow() {
+    log="$OUT/$1.log"
+    shift
+    timeout 10 ../moonbow/target/debug/moonbow "$@" > "$log" 2>&1 || true
+    grep -q "$HELLO" "$log" || { echo "firmware did not start, see $log"; exit 1; }
+}
+
+cargo build --quiet --target thumbv6m-none-eabi
+moonbow m0-plus --cpu m0-plus \
+    -e target/thumbv6m-none-eabi/debug/testloader -i firmware/hello.patched.hex
+echo "[m0-plus] firmware started"
+
+# The secure Test Loader keeps the firmware size at offset 0x1c
+cargo build --quiet --release --target thumbv8m.main-none-eabi --features secure
+cargo run --quiet --manifest-path ../nanotool/Cargo.toml -- patch --layout layout.toml \
+    --size-off 0x1c firmware/hello.patched.hex "$OUT/hello.ns.hex"
+moonbow m33 --cpu m33 \
+    -e target/thumbv8m.main-none-eabi/release/testloader -i "$OUT/hello.ns.hex"
+echo "[m33] non-secure firmware started"
@@ -3,41 +3,52 @@
 
 use cortex_m_semihosting::debug;
 use cortex_m_semihosting::hprintln;
-use crc;
-use log::{Log, Level, Metadata, Record};
+use log::LevelFilter;
 use volatile_register::{RO, RW, WO};
 
-use nanoloader::{NanoHal, NanoReason, NanoResult};
-
-struct Logger{}
-impl Log for Logger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
-    }
-
-    fn log(&self, record: &Record) {
-        hprintln!("[NL] LOG - {}", record.args());
-    }
-
-    fn flush(&self) { }
+use core::convert::Infallible;
+
+#[cfg(not(feature = "secure"))]
+use nanoloader::events::{Event, EventLog};
+use nanoloader::measure::{DeviceSecret, Dice, Handover};
+use nanoloader::options::{Banked, OptionsFlash, UpdatePointers, Word};
+use nanoloader::policy::Decision;
+use nanoloader::progress::Phase;
+#[cfg(feature = "forward")]
+use nanoloader::Vectors;
+use nanoloader::{
+    Digest, FlashProgrammer, Layout, Nano, NanoReason, NanoResult, Reporter, UpdateInfo, UpdateStore,
+};
+
+mod layout {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
 }
-static LOGGER: Logger = Logger {};
+
+static LOGGER: nanolog::semihosting::Logger = nanolog::semihosting::Logger::new("[NL] ");
 
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    unsafe {
-        log::set_logger_racy(&LOGGER).unwrap();
-        log::set_max_level_racy(Level::Info.to_level_filter());
-    }
+    // SAFETY: Nothing is logged yet
+    unsafe { LOGGER.init(LevelFilter::Info) };
 
     log::info!("hi there!");
     hprintln!("[NL] Starting");
 
-    let hal = TestHal {
-        ..Default::default()
-    };
-
-    nanoloader::boot::<_>(hal);
+    let nano: Nano<TestLayout, _, _, _, _, _> = Nano::new(
+        Crc32,
+        OptionsStore::new(),
+        TestProgrammer::default(),
+        TestReporter::new(),
+    )
+    .with_measure(Dice {
+        secret: TestSecret,
+        handover: layout::HANDOVER as *mut Handover,
+    });
+
+    #[cfg(not(feature = "secure"))]
+    nano.boot();
+    #[cfg(feature = "secure")]
+    nano.boot_non_secure(&mut TestAttribution);
 }
 
 #[panic_handler]
@@ -45,48 +56,93 @@ fn panic(_panic: &core::panic::PanicInfo<'_>) -> ! {
     loop {}
 }
 
-#[unsafe(link_section = ".bl_opts")]
+/// Update pointer pages, erased in the ELF file
+#[unsafe(link_section = ".bl_opts0")]
+#[used]
+static BL_OPTS0: [u32; 256] = [u32::MAX; 256];
+#[unsafe(link_section = ".bl_opts1")]
 #[used]
-static BL_OPTS: [u32; 256] = [u32::MAX; 256];
+static BL_OPTS1: [u32; 256] = [u32::MAX; 256];
 
-#[derive(Default)]
-struct TestHal {
-    current_prog_addr: u32,
-    current_prog_data: u32,
-}
+/// Options or event log page at the given address, programmed with the Flash controller
+struct FlashPage(usize);
 
-impl TestHal {
-    const FLASH: *const FlashController = 0x4000_0000 as *const FlashController;
-    const WORD_SZ: pow2::Pow2 = pow2::Pow2::align_of::<u32>();
+impl OptionsFlash for FlashPage {
+    type Error = Infallible;
 
-    fn update_find() -> Option<&'static u32> {
-        BL_OPTS
-            .iter()
-            .find(|x| unsafe { core::ptr::read_volatile(*x) } != 0)
-            .filter(|x| unsafe { core::ptr::read_volatile(*x) } != u32::MAX)
+    fn page(&self) -> &[u8] {
+        // SAFETY: Options or event log page from the layout file
+        unsafe { core::slice::from_raw_parts(self.0 as *const u8, layout::FLASH_PAGE_SZ) }
     }
-}
 
-impl NanoHal for TestHal {
-    const FW_START: usize = (16 * 1024);
-    const FW_END: usize = (64 * 1024);
-    const FW_SIZE_OFF: usize = 0x30;
-    const FW_PAGE_SZ: usize = 1024;
+    fn program(&mut self, offset: usize, data: &[u8]) -> Result<(), Infallible> {
+        let base = self.0 + offset;
+        for (i, word) in data.chunks_exact(size_of::<u32>()).enumerate() {
+            unsafe {
+                (*FLASH).addr.write((base + i * size_of::<u32>()) as u32);
+                (*FLASH)
+                    .data
+                    .write(u32::from_le_bytes(word.try_into().unwrap()));
+                (*FLASH).command.write(0x860cd758); // program
+            }
+        }
+        Ok(())
+    }
 
-    fn abort(reason: NanoReason) -> ! {
-        hprintln!("[NL] ABORT - {:?}", reason);
-        debug::exit(debug::EXIT_FAILURE);
-        // not reached
-        loop {}
+    fn erase(&mut self) -> Result<(), Infallible> {
+        hprintln!("[NL] Erasing page at 0x{:08x}", self.0);
+        unsafe {
+            (*FLASH).addr.write(self.0 as u32);
+            (*FLASH).command.write(0x4c6f315f); // erase
+        }
+        Ok(())
     }
+}
+
+const FLASH: *const FlashController = 0x4000_0000 as *const FlashController;
+
+struct TestLayout;
+
+impl Layout for TestLayout {
+    const FW_START: usize = layout::FW_START;
+    const FW_END: usize = layout::FW_END;
+    #[cfg(not(feature = "secure"))]
+    const FW_SIZE_OFF: usize = layout::FW_SIZE_OFF;
+    // Entry 12 (0x30) is the DebugMonitor vector on the Cortex-M33
+    #[cfg(feature = "secure")]
+    const FW_SIZE_OFF: usize = 0x1c;
+    const FW_PAGE_SZ: usize = layout::FLASH_PAGE_SZ;
+    #[cfg(feature = "forward")]
+    const VECTORS: Vectors = Vectors::Forward;
+}
 
-    fn checksum(data: &[u8]) -> u32 {
+nanoloader::export_layout!(TestLayout);
+#[cfg(feature = "forward")]
+nanoloader::forward_exceptions!(TestLayout);
+
+struct Crc32;
+
+impl Digest for Crc32 {
+    fn checksum(&self, data: &[u8]) -> u32 {
         const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
         CRC32.checksum(data)
     }
+}
 
-    fn update_address() -> Option<usize> {
-        let up = TestHal::update_find().map(|x| *x as usize);
+/// Update pointers in the options pages
+struct OptionsStore(Banked<FlashPage, Word>);
+
+impl OptionsStore {
+    fn new() -> Self {
+        let Ok(options) = Banked::new(layout::BL_OPTS.map(FlashPage));
+        OptionsStore(options)
+    }
+}
+
+impl UpdateStore for OptionsStore {
+    fn update_address(&mut self) -> Option<usize> {
+        let Ok(up) = self.0.pending();
+        let up = up.map(|(_, addr)| addr);
 
         if let Some(addr) = up {
             hprintln!("[NL] Update found: 0x{:08x}", addr);
@@ -95,23 +151,154 @@ impl NanoHal for TestHal {
         up
     }
 
-    fn update_clear() {
-        if let Some(up) = TestHal::update_find() {
-            let p = core::ptr::from_ref(up);
+    fn update_queued(&mut self, position: usize) -> Option<usize> {
+        let Ok(up) = self.0.queued(position);
+        up.map(|(_, addr)| addr)
+    }
 
-            unsafe {
-                (*TestHal::FLASH).addr.write(p as u32);
-                (*TestHal::FLASH).data.write(0);
-                (*TestHal::FLASH).command.write(0x860cd758); // program
-            }
+    fn update_clear(&mut self) {
+        if let Ok(Some((index, _))) = self.0.pending() {
+            let Ok(()) = self.0.clear(index);
             hprintln!("[NL] Update cleared");
         }
     }
+}
+
+/// Reports through semihosting, and records events in the event log pages
+struct TestReporter {
+    // The secure build leaves no room for the event log in the bootloader region
+    #[cfg(not(feature = "secure"))]
+    events: EventLog<FlashPage, 2>,
+}
+
+impl TestReporter {
+    const RECOVERY_WORD: *mut u32 = layout::RECOVERY_WORD as *mut u32;
+    const POLICY_WORD: *mut u32 = layout::POLICY_WORD as *mut u32;
+
+    fn new() -> Self {
+        TestReporter {
+            #[cfg(not(feature = "secure"))]
+            events: {
+                let Ok(events) = EventLog::new(layout::EVENT_LOG.map(FlashPage));
+                events
+            },
+        }
+    }
+
+    /// Whether the emulator was started with `arg` on its command line
+    fn host_arg(arg: &[u8]) -> bool {
+        let mut cmdline = [0u8; 64];
+        let block = [cmdline.as_mut_ptr() as usize, cmdline.len()];
+        let res = unsafe { cortex_m_semihosting::syscall!(GET_CMDLINE, block.as_ptr()) };
+
+        res == 0
+            && cmdline
+                .split(|b| *b == 0)
+                .next()
+                .is_some_and(|args| args.split(|b| *b == b' ').any(|a| a == arg))
+    }
+}
+
+impl Reporter for TestReporter {
+    fn abort(&mut self, reason: NanoReason) -> ! {
+        hprintln!("[NL] ABORT - {:?}", reason);
+        debug::exit(debug::EXIT_FAILURE);
+        // not reached
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+
+    fn recovery_requested(&mut self) -> bool {
+        // SAFETY: The word is reserved by the layout file
+        let requested = unsafe { nanoloader::recovery::take_request(Self::RECOVERY_WORD) };
+        requested || Self::host_arg(b"recovery")
+    }
+
+    fn recovery_wait(&mut self) -> bool {
+        // Nothing can deliver an update while the emulator is running
+        hprintln!("[NL] Recovery mode");
+        debug::exit(debug::EXIT_SUCCESS);
+        false
+    }
+
+    fn update_policy(&mut self, _address: usize, _info: &UpdateInfo) -> Decision {
+        if Self::host_arg(b"defer") {
+            Decision::Defer(1)
+        } else {
+            Decision::Install
+        }
+    }
 
+    fn progress(&mut self, phase: Phase, done: usize, total: usize) {
+        hprintln!("[NL] {:?}: {}/{} bytes", phase, done, total);
+    }
+
+    fn record_decision(&mut self, decision: Decision) {
+        hprintln!("[NL] Update policy: {:?}", decision);
+        // SAFETY: The word is reserved by the layout file
+        unsafe { nanoloader::policy::record(Self::POLICY_WORD, decision) };
+    }
+
+    #[cfg(not(feature = "secure"))]
+    fn event(&mut self, event: Event) {
+        let Ok(()) = self.events.append(event);
+    }
+}
+
+/// Makes the firmware area, the RAM and the Flash controller non-secure
+#[cfg(feature = "secure")]
+struct TestAttribution;
+
+#[cfg(feature = "secure")]
+impl nanoloader::secure::Attribution for TestAttribution {
+    fn configure(&mut self) -> NanoResult {
+        use nanoloader::secure::{SauRegion, SauRegionAttribute::NonSecure};
+
+        let region = |start: usize, end: usize| SauRegion {
+            base_address: start as u32,
+            limit_address: (end - 1) as u32 | 0x1f,
+            attribute: NonSecure,
+        };
+        // The bootloader does not run anymore after the handoff, so its RAM can be shared
+        let regions = [
+            region(layout::FW_START, layout::FW_END),
+            region(layout::RAM_START, layout::RAM_END),
+            region(FLASH as usize, FLASH as usize + 0x400),
+        ];
+        nanoloader::secure::sau(&regions).map_err(|_| NanoReason::HalError(1))
+    }
+}
+
+/// Fixed device secret, there is nothing to keep it from the firmware in the emulator
+struct TestSecret;
+
+impl DeviceSecret for TestSecret {
+    fn read(&mut self, uds: &mut [u8; 32]) -> NanoResult {
+        uds.copy_from_slice(b"Nano Loader test device secret!!");
+        nanoloader::OK
+    }
+
+    fn lock(&mut self) {
+        hprintln!("[NL] Device secret locked");
+    }
+}
+
+/// Programs the firmware area with the Flash controller, one word at a time
+#[derive(Default)]
+struct TestProgrammer {
+    current_prog_addr: u32,
+    current_prog_data: u32,
+}
+
+impl TestProgrammer {
+    const WORD_SZ: pow2::Pow2 = pow2::Pow2::align_of::<u32>();
+}
+
+impl FlashProgrammer for TestProgrammer {
     fn program_start(&mut self) -> NanoResult<()> {
         hprintln!("[NL] Programming stated");
 
-        self.current_prog_addr = Self::FW_START as u32;
+        self.current_prog_addr = TestLayout::FW_START as u32;
         self.current_prog_data = 0;
 
         nanoloader::OK
@@ -125,27 +312,38 @@ impl NanoHal for TestHal {
         if Self::WORD_SZ.is_aligned(self.current_prog_addr) {
             let addr = self.current_prog_addr - size_of::<u32>() as u32;
 
-            if pow2::pow2_const!(Self::FW_PAGE_SZ).is_aligned(addr) {
+            if pow2::pow2_const!(TestLayout::FW_PAGE_SZ).is_aligned(addr) {
                 hprintln!("[NL] Erasing flash page at 0x{:08x}", addr);
                 unsafe {
-                    (*TestHal::FLASH).addr.write(addr);
-                    (*TestHal::FLASH).command.write(0x4c6f315f); // erase
+                    (*FLASH).addr.write(addr);
+                    (*FLASH).command.write(0x4c6f315f); // erase
                 }
             }
             unsafe {
-                (*TestHal::FLASH).addr.write(addr);
-                (*TestHal::FLASH)
+                (*FLASH).addr.write(addr);
+                (*FLASH)
                     .data
                     .write(self.current_prog_data.swap_bytes());
-                (*TestHal::FLASH).command.write(0x860cd758); // program
+                (*FLASH).command.write(0x860cd758); // program
             }
             self.current_prog_data = 0;
         }
         nanoloader::OK
     }
 
-    fn program_read(&mut self, _offset: usize) -> NanoResult<u8> {
-        Err(NanoReason::HalError(0))
+    fn program_read(&mut self, offset: usize) -> NanoResult<u8> {
+        let addr = (TestLayout::FW_START + offset) as u32;
+        let word = Self::WORD_SZ.align_down(self.current_prog_addr);
+
+        if addr >= self.current_prog_addr {
+            Err(NanoReason::HalError(0))
+        } else if addr >= word {
+            // Not yet programmed, still in the word buffer
+            let shift = (self.current_prog_addr - addr - 1) * 8;
+            Ok((self.current_prog_data >> shift) as u8)
+        } else {
+            Ok(unsafe { core::ptr::read_volatile(addr as *const u8) })
+        }
     }
 
     fn program_finish(&mut self) -> NanoResult<()> {